@@ -0,0 +1,82 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::backtrace::Backtrace;
+use std::collections::HashMap;
+use std::ptr::NonNull;
+use std::sync::Mutex;
+
+// Wraps an allocator and captures the call stack of every allocation, so a leaked block can be
+// traced back to the code that made it. Capturing is slow, so this is only meant for leak hunting.
+pub struct BacktraceAllocator<A> {
+    inner: A,
+    live: Mutex<HashMap<usize, (usize, Backtrace)>>,
+}
+
+impl<A: Allocator> BacktraceAllocator<A> {
+    pub fn new(inner: A) -> Self {
+        BacktraceAllocator {
+            inner,
+            live: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // (address, size, backtrace) of every block not yet freed, in ascending address order
+    pub fn leak_report(&self) -> Vec<(usize, usize, String)> {
+        let mut report: Vec<(usize, usize, String)> = self
+            .live
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(address, (size, trace))| (*address, *size, trace.to_string()))
+            .collect();
+        report.sort_unstable_by_key(|(address, _, _)| *address);
+        report
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for BacktraceAllocator<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr: NonNull<[u8]> = self.inner.allocate(layout)?;
+        // Captured regardless of RUST_BACKTRACE, a report without stacks would be useless
+        let trace: Backtrace = Backtrace::force_capture();
+        self.live
+            .lock()
+            .unwrap()
+            .insert(ptr.addr().get(), (layout.size(), trace));
+        Ok(ptr)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.live.lock().unwrap().remove(&ptr.addr().get());
+        self.inner.deallocate(ptr, layout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buddy::Buddy;
+    use crate::mutex::Locked;
+
+    #[test]
+    fn test_leak_report() {
+        let allocator: BacktraceAllocator<Locked<Buddy>> =
+            BacktraceAllocator::new(Locked::new(Buddy::new()));
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+
+        let freed: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        let leaked: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        unsafe {
+            allocator.deallocate(freed.as_non_null_ptr(), layout);
+        }
+
+        let report: Vec<(usize, usize, String)> = allocator.leak_report();
+        assert_eq!(report.len(), 1);
+        assert_eq!((report[0].0, report[0].1), (leaked.addr().get(), 32));
+        assert!(report[0].2.contains("test_leak_report"));
+
+        unsafe {
+            allocator.deallocate(leaked.as_non_null_ptr(), layout);
+        }
+        assert!(allocator.leak_report().is_empty());
+    }
+}
@@ -0,0 +1,328 @@
+use std::alloc::{AllocError, Allocator, Layout, System};
+use std::ptr::NonNull;
+use std::sync::MutexGuard;
+
+use crate::mutex::{Lock, Locked};
+use crate::region_source::{Owns, RegionSource, DEFAULT_NODE};
+use crate::stats::{fragmented_share, MemStats};
+
+/*
+    Bitmap Allocator
+    - Regions of 512 bytes (aligned at 16) are split into 512 / BLOCK fixed blocks, one bit per block.
+    - Allocations take the first run of clear bits long enough for the request (and suitably aligned), and set them.
+    - Deallocations clear the run again. Free space needs no list nodes, the bitmap is the whole free state.
+    - When no region has a long enough run, a new region is acquired.
+*/
+
+pub struct BitmapAllocator<const BLOCK: usize, S: RegionSource = System> {
+    source: S,
+    regions: Vec<NonNull<u8>>,
+    // One bit per block, bit i of the region set while block i is in use
+    bitmaps: Vec<[u64; 8]>,
+    total_size: usize,
+    peak_allocated_size: usize,
+    current_allocated_size: usize,
+    // allocate calls that returned a block, and those that returned AllocError
+    alloc_success: u64,
+    alloc_failure: u64,
+    extension_count: u64,
+    // Live allocations by run length, rounded up to a power of 2 like the segregated classes
+    class_live: [u64; 10],
+}
+
+// The only pointers kept are region bases, blocks are found again from bitmap bits. Those regions
+// came from the source and go back to it on drop, so the allocator can move with its source.
+unsafe impl<const BLOCK: usize, S: RegionSource + Send> Send for BitmapAllocator<BLOCK, S> {}
+
+impl<const BLOCK: usize> BitmapAllocator<BLOCK> {
+    pub fn new() -> Self {
+        BitmapAllocator::with_source(System)
+    }
+}
+
+impl<const BLOCK: usize, S: RegionSource> BitmapAllocator<BLOCK, S> {
+    const BLOCKS: usize = 512 / BLOCK;
+
+    pub fn with_source(source: S) -> Self {
+        assert!(
+            BLOCK.is_power_of_two() && BLOCK <= 512,
+            "bitmap block size must be a power of 2 up to 512"
+        );
+        BitmapAllocator {
+            source,
+            regions: Vec::new(),
+            bitmaps: Vec::new(),
+            total_size: 0,
+            peak_allocated_size: 0,
+            current_allocated_size: 0,
+            alloc_success: 0,
+            alloc_failure: 0,
+            extension_count: 0,
+            class_live: [0; 10],
+        }
+    }
+
+    fn run_class(blocks: usize) -> usize {
+        (blocks * BLOCK).next_power_of_two().trailing_zeros() as usize
+    }
+
+    fn is_set(bitmap: &[u64; 8], block: usize) -> bool {
+        bitmap[block / 64] & (1 << (block % 64)) != 0
+    }
+
+    fn set_run(bitmap: &mut [u64; 8], start: usize, blocks: usize, used: bool) {
+        for block in start..start + blocks {
+            if used {
+                bitmap[block / 64] |= 1 << (block % 64);
+            } else {
+                bitmap[block / 64] &= !(1 << (block % 64));
+            }
+        }
+    }
+
+    // First block of a clear, aligned run of the given length in the region, if any
+    fn find_run(&self, region: usize, blocks: usize, align: usize) -> Option<usize> {
+        let base: usize = self.regions[region].addr().get();
+        (0..=Self::BLOCKS - blocks).find(|start| {
+            (base + start * BLOCK).is_multiple_of(align)
+                && (*start..start + blocks).all(|block| !Self::is_set(&self.bitmaps[region], block))
+        })
+    }
+}
+
+impl<const BLOCK: usize, S: RegionSource> Drop for BitmapAllocator<BLOCK, S> {
+    fn drop(&mut self) {
+        for region in &self.regions {
+            unsafe {
+                self.source
+                    .release(*region, Layout::from_size_align_unchecked(512, 16));
+            }
+        }
+    }
+}
+
+impl<const BLOCK: usize, S: RegionSource> Owns for BitmapAllocator<BLOCK, S> {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        let address: usize = ptr.addr().get();
+        self.regions
+            .iter()
+            .any(|region| region.addr().get() <= address && address < region.addr().get() + 512)
+    }
+}
+
+impl<const BLOCK: usize, S: RegionSource> MemStats for BitmapAllocator<BLOCK, S> {
+    fn calculate_allocation_ratio(&self) -> (usize, usize, f64) {
+        (
+            self.peak_allocated_size,
+            self.total_size,
+            self.peak_allocated_size as f64 / self.total_size as f64,
+        )
+    }
+
+    fn current_allocated_size(&self) -> usize {
+        self.current_allocated_size
+    }
+
+    fn extension_count(&self) -> u64 {
+        self.extension_count
+    }
+
+    fn alloc_success(&self) -> u64 {
+        self.alloc_success
+    }
+
+    fn alloc_failure(&self) -> u64 {
+        self.alloc_failure
+    }
+
+    fn size_class_histogram(&self) -> Vec<u64> {
+        self.class_live.to_vec()
+    }
+
+    // Every run of clear bits is a free block, only a fully clear region fits the largest request
+    fn external_fragmentation(&self) -> f64 {
+        let mut runs: Vec<usize> = Vec::new();
+        for bitmap in &self.bitmaps {
+            let mut run: usize = 0;
+            for block in 0..Self::BLOCKS {
+                if Self::is_set(bitmap, block) {
+                    runs.push(run * BLOCK);
+                    run = 0;
+                } else {
+                    run += 1;
+                }
+            }
+            runs.push(run * BLOCK);
+        }
+        fragmented_share(runs, 512)
+    }
+
+    fn reset(&mut self) {
+        self.total_size = 0;
+        self.peak_allocated_size = 0;
+        self.current_allocated_size = 0;
+        self.alloc_success = 0;
+        self.alloc_failure = 0;
+        self.extension_count = 0;
+        self.class_live = [0; 10];
+        for region in &self.regions {
+            unsafe {
+                self.source
+                    .release(*region, Layout::from_size_align_unchecked(512, 16));
+            }
+        }
+        self.regions.clear();
+        self.bitmaps.clear();
+    }
+
+    fn reset_peak(&mut self) {
+        self.peak_allocated_size = self.current_allocated_size;
+    }
+}
+
+unsafe impl<const BLOCK: usize, S: RegionSource> Allocator for Locked<BitmapAllocator<BLOCK, S>> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut alloc: MutexGuard<'_, BitmapAllocator<BLOCK, S>> = self.lock();
+
+        let blocks: usize = usize::max(layout.size().div_ceil(BLOCK), 1);
+        if blocks > BitmapAllocator::<BLOCK, S>::BLOCKS || layout.align() > 512 {
+            alloc.alloc_failure += 1;
+            return Err(AllocError);
+        }
+
+        let mut found: Option<(usize, usize)> = (0..alloc.regions.len()).find_map(|region| {
+            alloc
+                .find_run(region, blocks, layout.align())
+                .map(|start| (region, start))
+        });
+        // Regions are only aligned at 16, so a fresh one can't promise a larger alignment
+        if found.is_none() && layout.align() > 16 {
+            alloc.alloc_failure += 1;
+            return Err(AllocError);
+        }
+        if found.is_none() {
+            // need to expand heap
+            let ptr: NonNull<[u8]> = alloc
+                .source
+                .acquire(
+                    unsafe { Layout::from_size_align_unchecked(512, 16) },
+                    DEFAULT_NODE,
+                )
+                .inspect_err(|_| alloc.alloc_failure += 1)?;
+            alloc.regions.push(ptr.as_non_null_ptr());
+            alloc.bitmaps.push([0; 8]);
+            alloc.total_size += 512;
+            alloc.extension_count += 1;
+
+            let region: usize = alloc.regions.len() - 1;
+            found = alloc
+                .find_run(region, blocks, layout.align())
+                .map(|start| (region, start));
+        }
+        let (region, start): (usize, usize) = found
+            .ok_or(AllocError)
+            .inspect_err(|_| alloc.alloc_failure += 1)?;
+
+        BitmapAllocator::<BLOCK, S>::set_run(&mut alloc.bitmaps[region], start, blocks, true);
+        alloc.current_allocated_size += blocks * BLOCK;
+        alloc.class_live[BitmapAllocator::<BLOCK, S>::run_class(blocks)] += 1;
+        alloc.alloc_success += 1;
+        alloc.peak_allocated_size =
+            usize::max(alloc.current_allocated_size, alloc.peak_allocated_size);
+        debug_assert!(alloc.peak_allocated_size <= alloc.total_size);
+
+        unsafe {
+            let ptr: NonNull<u8> = alloc.regions[region].add(start * BLOCK);
+            Ok(NonNull::slice_from_raw_parts(ptr, blocks * BLOCK))
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let mut alloc: MutexGuard<'_, BitmapAllocator<BLOCK, S>> = self.lock();
+
+        let blocks: usize = usize::max(layout.size().div_ceil(BLOCK), 1);
+        let address: usize = ptr.addr().get();
+        let region: usize = alloc
+            .regions
+            .iter()
+            .position(|base| base.addr().get() <= address && address < base.addr().get() + 512)
+            .expect("pointer was not allocated by this bitmap allocator");
+        let start: usize = (address - alloc.regions[region].addr().get()) / BLOCK;
+
+        BitmapAllocator::<BLOCK, S>::set_run(&mut alloc.bitmaps[region], start, blocks, false);
+        alloc.current_allocated_size -= blocks * BLOCK;
+        alloc.class_live[BitmapAllocator::<BLOCK, S>::run_class(blocks)] -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_block() {
+        let allocator: Locked<BitmapAllocator<32>> = Locked::new(BitmapAllocator::new());
+        let layout: Layout = Layout::from_size_align(20, 8).unwrap();
+
+        let first: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        let second: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(first.len(), 32);
+        assert_eq!(second.addr().get(), first.addr().get() + 32);
+        assert_eq!(allocator.lock().bitmaps[0][0], 0b11);
+
+        unsafe {
+            allocator.deallocate(first.as_non_null_ptr(), layout);
+        }
+        assert_eq!(allocator.lock().bitmaps[0][0], 0b10);
+
+        // The freed block is the first clear bit again
+        let reused: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(reused, first);
+    }
+
+    #[test]
+    fn test_multi_block_run() {
+        let allocator: Locked<BitmapAllocator<32>> = Locked::new(BitmapAllocator::new());
+        let small: Layout = Layout::from_size_align(32, 8).unwrap();
+        let large: Layout = Layout::from_size_align(100, 8).unwrap();
+
+        let a: NonNull<[u8]> = allocator.allocate(small).unwrap();
+        let _b: NonNull<[u8]> = allocator.allocate(small).unwrap();
+        unsafe {
+            allocator.deallocate(a.as_non_null_ptr(), small);
+        }
+
+        // A one-block hole in front doesn't fit 4 blocks, so the run starts after the live block
+        let run: NonNull<[u8]> = allocator.allocate(large).unwrap();
+        assert_eq!(run.len(), 128);
+        assert_eq!(run.addr().get(), a.addr().get() + 64);
+        assert_eq!(allocator.lock().bitmaps[0][0], 0b111110);
+
+        unsafe {
+            allocator.deallocate(run.as_non_null_ptr(), large);
+        }
+        let alloc: MutexGuard<'_, BitmapAllocator<32>> = allocator.lock();
+        assert_eq!(alloc.bitmaps[0][0], 0b10);
+        assert_eq!(alloc.current_allocated_size, 32);
+        assert_eq!(
+            alloc.calculate_allocation_ratio(),
+            (160, 512, 160.0 / 512.0)
+        );
+    }
+
+    #[test]
+    fn test_over_aligned_does_not_extend() {
+        let allocator: Locked<BitmapAllocator<32>> = Locked::new(BitmapAllocator::new());
+
+        assert!(allocator
+            .allocate(Layout::from_size_align(32, 1024).unwrap())
+            .is_err());
+        assert!(allocator
+            .allocate(Layout::from_size_align(32, 64).unwrap())
+            .is_err());
+        let alloc: MutexGuard<'_, BitmapAllocator<32>> = allocator.lock();
+        assert!(alloc.regions.is_empty());
+        assert_eq!(alloc.extension_count, 0);
+        assert_eq!(alloc.alloc_failure, 2);
+    }
+}
@@ -4,246 +4,1366 @@ use std::collections::LinkedList;
 use std::ptr::NonNull;
 use std::sync::MutexGuard;
 
-use crate::mutex::{Lock, Locked};
-use crate::stats::MemStats;
+#[cfg(all(test, feature = "dump"))]
+use crate::dump::encode_dump;
+#[cfg(test)]
+use crate::invariants::list_first_block_twice;
+#[cfg(test)]
+use crate::invariants::{check_free_lists, repair_free_lists, FreeListInvariants};
+use crate::invariants::{validate_free, InvalidFreeHook, ScanBound, ValidationLevel};
+use crate::mutex::{ExclusiveAllocator, InFlight, Lock, Locked};
+#[cfg(any(test, feature = "global_fallback"))]
+use crate::region_source::Owns;
+#[cfg(test)]
+use crate::region_source::PAGE_SIZE;
+use crate::region_source::{prefault, zero_size_block, RegionSource, DEFAULT_NODE};
+#[cfg(test)]
+use crate::snapshot::{AllocatorSnapshot, Snapshot};
+use crate::stats::{fragmented_share, MemStats};
+#[cfg(test)]
+use crate::stats::{free_distribution, list_overhead, region_occupancy};
+
+// One node of a region's split tree, rebuilt from the free lists. A span holding no free block at
+// all is reported as a single allocated block, since the free lists can't tell it apart from a
+// split whose halves are all in use.
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+pub enum BuddyTree {
+    Free,
+    Allocated,
+    Split(Box<BuddyTree>, Box<BuddyTree>),
+}
+
+// Regions are 512 bytes unless a larger request needs a bigger one
+const DEFAULT_REGION_SIZE: usize = 512;
 
-// Holds 10 fixed size lists of sizes 1,2,4,8,16,32,64,128,256,512
-pub struct Buddy {
-    lists: [LinkedList<NonNull<[u8]>>; 10],
+// Holds one list per power of 2 from 1 up to the largest region, 1,2,4,...,512 until a request
+// larger than 512 bytes acquires a bigger region
+pub struct Buddy<S: RegionSource = System> {
+    source: S,
+    lists: Vec<LinkedList<NonNull<[u8]>>>,
     first_byte_ptrs: Vec<NonNull<u8>>,
-    total_size: f64,
-    peak_allocated_size: f64,
-    current_allocated_size: f64,
+    total_size: usize,
+    peak_allocated_size: usize,
+    current_allocated_size: usize,
+    // Live allocations in each class, kept as long as lists
+    class_live: Vec<u64>,
+    // allocate calls that returned a block, and those that returned AllocError
+    alloc_success: u64,
+    alloc_failure: u64,
+    extension_count: u64,
+    split_count: u64,
+    merge_count: u64,
+    // Called with the new peak under the allocator lock, so it must not allocate from this allocator
+    on_peak: Option<Box<dyn Fn(usize) + Send + Sync>>,
+    // Scrub blocks on free so their contents don't linger in reused memory
+    zero_on_free: bool,
+    // Also merge fully free aligned spans on free, even when they aren't made up of buddy pairs
+    aggressive_coalesce: bool,
+    validation: ValidationLevel,
+    on_invalid_free: Option<InvalidFreeHook>,
+    // Alignment requested for region bases, 16 unless regions should start on a page boundary
+    region_align: usize,
+    // Layout each region was acquired with, in the same order as first_byte_ptrs
+    region_layouts: Vec<Layout>,
+    // Hand out slices of exactly the requested length, the full block is still reserved
+    trim_to_request: bool,
+    // Take the lowest-addressed free block of a size instead of the front of its list, so
+    // allocate-free-allocate of one size keeps landing on the same cache lines
+    prefer_low_addresses: bool,
+    // Blocks handed out and not yet freed, checked on drop so regions aren't released under them
+    #[cfg(debug_assertions)]
+    live_allocations: usize,
+    // Called with the live allocation count when dropped while blocks are still lent out, in
+    // place of panicking
+    #[cfg(debug_assertions)]
+    on_live_drop: Option<Box<dyn Fn(usize) + Send + Sync>>,
+    // Leave regions unreleased on drop
+    leak_on_drop: bool,
+    // Touch every page of a region as soon as it is acquired
+    prefault_on_acquire: bool,
 }
 
+// Every free half in the lists comes from a region in first_byte_ptrs, which this allocator
+// acquired and alone releases on drop. Moving it hands that memory to the new thread whole.
+unsafe impl<S: RegionSource + Send> Send for Buddy<S> {}
+// Through &self the lists are only walked to compare addresses and lengths, nothing is split,
+// merged or written, and the hooks are already Send + Sync
+unsafe impl<S: RegionSource + Sync> Sync for Buddy<S> {}
+
 impl Buddy {
     pub fn new() -> Self {
+        Buddy::with_source(System)
+    }
+
+    #[cfg(test)]
+    pub fn with_zero_on_free(zero_on_free: bool) -> Self {
+        let mut allocator: Buddy = Buddy::new();
+        allocator.zero_on_free = zero_on_free;
+        allocator
+    }
+
+    #[cfg(test)]
+    pub fn with_on_peak(on_peak: Box<dyn Fn(usize) + Send + Sync>) -> Self {
+        let mut allocator: Buddy = Buddy::new();
+        allocator.on_peak = Some(on_peak);
+        allocator
+    }
+
+    #[cfg(test)]
+    pub fn with_page_aligned_regions(page_aligned: bool) -> Self {
+        let mut allocator: Buddy = Buddy::new();
+        allocator.region_align = if page_aligned { PAGE_SIZE } else { 16 };
+        allocator
+    }
+}
+
+impl<S: RegionSource> Buddy<S> {
+    pub fn with_source(source: S) -> Self {
         Buddy {
-            lists: [
-                LinkedList::new(),
-                LinkedList::new(),
-                LinkedList::new(),
-                LinkedList::new(),
-                LinkedList::new(),
-                LinkedList::new(),
-                LinkedList::new(),
-                LinkedList::new(),
-                LinkedList::new(),
-                LinkedList::new(),
-            ],
+            source,
+            lists: (0..=DEFAULT_REGION_SIZE.trailing_zeros())
+                .map(|_| LinkedList::new())
+                .collect(),
             first_byte_ptrs: Vec::new(),
-            total_size: 0.0,
-            peak_allocated_size: 0.0,
-            current_allocated_size: 0.0,
+            total_size: 0,
+            peak_allocated_size: 0,
+            current_allocated_size: 0,
+            class_live: vec![0; DEFAULT_REGION_SIZE.trailing_zeros() as usize + 1],
+            alloc_success: 0,
+            alloc_failure: 0,
+            extension_count: 0,
+            split_count: 0,
+            merge_count: 0,
+            on_peak: None,
+            zero_on_free: false,
+            aggressive_coalesce: false,
+            validation: ValidationLevel::DEFAULT,
+            on_invalid_free: None,
+            region_align: 16,
+            region_layouts: Vec::new(),
+            trim_to_request: false,
+            prefer_low_addresses: false,
+            #[cfg(debug_assertions)]
+            live_allocations: 0,
+            #[cfg(debug_assertions)]
+            on_live_drop: None,
+            leak_on_drop: false,
+            prefault_on_acquire: false,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn set_aggressive_coalesce(&mut self, enabled: bool) {
+        self.aggressive_coalesce = enabled;
+    }
+
+    // Live allocations are only counted in debug builds, so only they can report them. Dropping
+    // with blocks still lent out panics unless this hook is installed to handle it instead.
+    #[cfg(all(test, debug_assertions))]
+    pub fn set_on_live_drop(&mut self, on_live_drop: Box<dyn Fn(usize) + Send + Sync>) {
+        self.on_live_drop = Some(on_live_drop);
+    }
+
+    // Skip handing regions back on drop, leaving them for the OS to reclaim at exit. Saves the
+    // release calls when many short lived allocators are created and dropped.
+    #[cfg(test)]
+    pub fn set_leak_on_drop(&mut self, leak_on_drop: bool) {
+        self.leak_on_drop = leak_on_drop;
+    }
+
+    #[cfg(test)]
+    pub fn set_prefault_on_acquire(&mut self, prefault_on_acquire: bool) {
+        self.prefault_on_acquire = prefault_on_acquire;
+    }
+
+    // (start, size) of the region holding the address
+    fn region_containing(&self, address: usize) -> Option<(usize, usize)> {
+        self.first_byte_ptrs
+            .iter()
+            .zip(&self.region_layouts)
+            .map(|(region, layout)| (region.addr().get(), layout.size()))
+            .find(|(start, size)| *start <= address && address < start + size)
+    }
+
+    // Acquires a region and lists it as one free block, returning the list it went into
+    fn acquire_region(&mut self, layout: Layout) -> Result<usize, AllocError> {
+        let region: NonNull<[u8]> = self.source.acquire(layout, DEFAULT_NODE)?;
+        if self.prefault_on_acquire {
+            prefault(region);
+        }
+        let index: usize = layout.size().trailing_zeros() as usize;
+        if index >= self.lists.len() {
+            self.lists.resize_with(index + 1, LinkedList::new);
+            self.class_live.resize(index + 1, 0);
+        }
+        self.lists[index].push_back(region);
+        self.first_byte_ptrs.push(region.as_non_null_ptr());
+        self.region_layouts.push(layout);
+        self.total_size += layout.size();
+        self.extension_count += 1;
+        Ok(index)
+    }
+
+    #[cfg(test)]
+    pub fn set_validation(&mut self, level: ValidationLevel) {
+        self.validation = level;
+    }
+
+    // Replaces aborting when a deallocate fails validation. The block is left alone either way.
+    #[cfg(test)]
+    pub fn set_on_invalid_free(&mut self, on_invalid_free: InvalidFreeHook) {
+        self.on_invalid_free = Some(on_invalid_free);
+    }
+
+    #[cfg(test)]
+    pub fn set_trim_to_request(&mut self, trim_to_request: bool) {
+        self.trim_to_request = trim_to_request;
+    }
+
+    #[cfg(test)]
+    pub fn set_prefer_low_addresses(&mut self, prefer_low_addresses: bool) {
+        self.prefer_low_addresses = prefer_low_addresses;
+    }
+
+    // Removes the next block to serve from a list. Splits push the lower half first, so with
+    // prefer_low_addresses the lower half is always the one handed out or split further.
+    fn take_free_block(&mut self, index: usize) -> Option<NonNull<[u8]>> {
+        if !self.prefer_low_addresses {
+            return self.lists[index].pop_front();
+        }
+        let lowest: usize = self.lists[index]
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, block)| block.addr())?
+            .0;
+        let mut cursor: CursorMut<'_, NonNull<[u8]>> = self.lists[index].cursor_front_mut();
+        for _ in 0..lowest {
+            cursor.move_next();
+        }
+        cursor.remove_current()
+    }
+
+    // Alignment every block served for the layout has. Blocks sit at multiples of their size from
+    // a region base, and region bases are only aligned to region_align. Layouts asking for more
+    // are served from blocks picked at an aligned address instead.
+    pub fn guaranteed_alignment(&self, layout: &Layout) -> usize {
+        usize::max(
+            usize::min(layout.size().next_power_of_two(), self.region_align),
+            layout.align(),
+        )
+    }
+
+    // Takes a block of the class that starts on an align boundary, splitting it off the smallest
+    // aligned free block or off a new region acquired at that alignment. Lower halves keep the
+    // alignment, and upper halves are listed as usual to serve ordinary requests later.
+    fn take_aligned_block(
+        &mut self,
+        index: usize,
+        align: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let found: Option<(usize, usize)> = (index..self.lists.len()).find_map(|list_index| {
+            self.lists[list_index]
+                .iter()
+                .position(|block| block.addr().get().is_multiple_of(align))
+                .map(|position| (list_index, position))
+        });
+        let (mut list_index, position): (usize, usize) = match found {
+            Some(found) => found,
+            None => {
+                let region_size: usize = usize::max(DEFAULT_REGION_SIZE, 1 << index);
+                let layout: Layout =
+                    Layout::from_size_align(region_size, align).map_err(|_| AllocError)?;
+                let list_index: usize = self.acquire_region(layout)?;
+                (list_index, self.lists[list_index].len() - 1)
+            }
+        };
+        let mut cursor: CursorMut<'_, NonNull<[u8]>> = self.lists[list_index].cursor_front_mut();
+        for _ in 0..position {
+            cursor.move_next();
+        }
+        let mut block: NonNull<[u8]> = cursor.remove_current().unwrap();
+        while list_index > index {
+            list_index -= 1;
+            let half: usize = block.len() >> 1;
+            let upper: NonNull<[u8]> =
+                NonNull::slice_from_raw_parts(unsafe { block.as_non_null_ptr().add(half) }, half);
+            self.lists[list_index].push_back(upper);
+            block = NonNull::slice_from_raw_parts(block.as_non_null_ptr(), half);
+            self.split_count += 1;
+        }
+        Ok(block)
+    }
+
+    fn record_allocation(&mut self, rounded_size: usize) {
+        self.current_allocated_size += rounded_size;
+        self.class_live[rounded_size.trailing_zeros() as usize] += 1;
+        self.alloc_success += 1;
+        let previous_peak: usize = self.peak_allocated_size;
+        self.peak_allocated_size =
+            usize::max(self.current_allocated_size, self.peak_allocated_size);
+        if self.peak_allocated_size > previous_peak {
+            if let Some(on_peak) = &self.on_peak {
+                on_peak(self.peak_allocated_size);
+            }
+        }
+        debug_assert!(self.peak_allocated_size <= self.total_size);
+        #[cfg(debug_assertions)]
+        {
+            self.live_allocations += 1;
+        }
+    }
+
+    // The slice allocate returns for a block, cut down to the request when trimming
+    fn returned_slice(&self, block: NonNull<[u8]>, size: usize) -> NonNull<[u8]> {
+        let layout: Layout = Layout::from_size_align(size, 1).unwrap();
+        debug_assert!(block
+            .addr()
+            .get()
+            .is_multiple_of(self.guaranteed_alignment(&layout)));
+        if self.trim_to_request {
+            NonNull::slice_from_raw_parts(block.as_non_null_ptr(), size)
+        } else {
+            block
+        }
+    }
+
+    // Largest request that can be served without acquiring a larger region than the default
+    #[cfg(test)]
+    pub fn max_alloc_size(&self) -> usize {
+        DEFAULT_REGION_SIZE
+    }
+
+    // Bytes reserved for a layout, the request rounded up to the next power of 2
+    #[cfg(test)]
+    pub fn usable_size(&self, layout: &Layout) -> usize {
+        layout.size().next_power_of_two()
+    }
+
+    // Allocated fraction of each region, a region reading 0.0 is entirely free
+    #[cfg(test)]
+    pub fn region_occupancy(&self) -> Vec<f64> {
+        region_occupancy(&self.lists, &self.first_byte_ptrs, &self.region_layouts[..])
+    }
+
+    // Free blocks and bytes currently sitting in each class
+    #[cfg(test)]
+    pub fn free_distribution(&self) -> Vec<(usize, usize, usize)> {
+        free_distribution(&self.lists)
+    }
+
+    // Estimated bytes of bookkeeping, to compare against total_size
+    #[cfg(test)]
+    pub fn overhead_bytes(&self) -> usize {
+        list_overhead(&self.lists, self.first_byte_ptrs.capacity())
+    }
+
+    // Serializes regions, free lists and stats, read back offline with dump::parse_dump
+    #[cfg(all(test, feature = "dump"))]
+    pub fn dump_state(&self) -> Vec<u8> {
+        encode_dump(&self.first_byte_ptrs, &self.snapshot(), self.total_size)
+    }
+
+    // Acquires more regions and lists them as whole free blocks. Live blocks are never moved, so
+    // this is safe to call while allocations are outstanding.
+    #[cfg(test)]
+    pub fn grow_pool(&mut self, additional_regions: usize) -> Result<(), AllocError> {
+        for _ in 0..additional_regions {
+            self.acquire_region(
+                Layout::from_size_align(DEFAULT_REGION_SIZE, self.region_align).unwrap(),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Split tree of every region, in the order the regions were acquired
+    #[cfg(test)]
+    pub fn split_tree(&self) -> Vec<BuddyTree> {
+        self.first_byte_ptrs
+            .iter()
+            .zip(&self.region_layouts)
+            .map(|(region, layout)| {
+                self.span_tree(region.addr().get(), layout.size().trailing_zeros() as usize)
+            })
+            .collect()
+    }
+
+    // Tree for the span of size 2^level starting at start
+    #[cfg(test)]
+    fn span_tree(&self, start: usize, level: usize) -> BuddyTree {
+        let size: usize = 1 << level;
+        if self.lists[level]
+            .iter()
+            .any(|block| block.addr().get() == start)
+        {
+            return BuddyTree::Free;
+        }
+        let holds_free: bool = self.lists[..level]
+            .iter()
+            .flatten()
+            .any(|block| start <= block.addr().get() && block.addr().get() < start + size);
+        if holds_free {
+            BuddyTree::Split(
+                Box::new(self.span_tree(start, level - 1)),
+                Box::new(self.span_tree(start + size / 2, level - 1)),
+            )
+        } else {
+            BuddyTree::Allocated
+        }
+    }
+
+    // Re-merges the free blocks of the region holding addr, leaving every other region untouched.
+    // Cheaper than a full pass when a large block is needed right after a burst of frees.
+    #[cfg(test)]
+    pub fn coalesce_around(&mut self, addr: usize) {
+        let (region_start, region_size): (usize, usize) = match self.region_containing(addr) {
+            Some(region) => region,
+            None => return,
+        };
+
+        let mut blocks: Vec<NonNull<[u8]>> = Vec::new();
+        for list in &mut self.lists {
+            let mut cursor: CursorMut<'_, NonNull<[u8]>> = list.cursor_front_mut();
+            while let Some(free) = cursor.current() {
+                if region_start <= free.addr().get()
+                    && free.addr().get() < region_start + region_size
+                {
+                    blocks.push(cursor.remove_current().unwrap());
+                } else {
+                    cursor.move_next();
+                }
+            }
+        }
+
+        // Putting each block back through promote_free_span merges every span that is fully free
+        blocks.sort_by_key(|block| block.addr());
+        for block in blocks {
+            self.promote_free_span(block, block.len().trailing_zeros() as usize);
+        }
+    }
+
+    // Keeps promoting a freed block to the next class while the aligned span containing it is
+    // entirely free, absorbing whatever free blocks make up the rest of the span
+    fn promote_free_span(&mut self, mut block: NonNull<[u8]>, mut index: usize) {
+        if let Some((region_start, region_size)) = self.region_containing(block.addr().get()) {
+            while (1 << index) < region_size {
+                let span_size: usize = 1 << (index + 1);
+                let span_start: usize =
+                    region_start + ((block.addr().get() - region_start) & !(span_size - 1));
+                let span_end: usize = span_start + span_size;
+                let in_span = |free: &NonNull<[u8]>| {
+                    span_start <= free.addr().get() && free.addr().get() + free.len() <= span_end
+                };
+
+                let free_in_span: usize = self.lists[..=index]
+                    .iter()
+                    .flatten()
+                    .filter(|free| in_span(free))
+                    .map(|free| free.len())
+                    .sum();
+                if free_in_span + block.len() != span_size {
+                    break;
+                }
+
+                for (list_index, list) in self.lists[..=index].iter_mut().enumerate() {
+                    let mut bound: ScanBound = ScanBound::new(list_index, list.len());
+                    let mut cursor: CursorMut<'_, NonNull<[u8]>> = list.cursor_front_mut();
+                    while let Some(free) = cursor.current() {
+                        bound.step();
+                        if in_span(free) {
+                            cursor.remove_current();
+                        } else {
+                            cursor.move_next();
+                        }
+                    }
+                }
+
+                let span_ptr: NonNull<u8> =
+                    NonNull::new(block.as_mut_ptr().with_addr(span_start)).unwrap();
+                block = NonNull::slice_from_raw_parts(span_ptr, span_size);
+                index += 1;
+                self.merge_count += 1;
+            }
+        }
+        self.lists[index].push_back(block);
+    }
+}
+
+impl<S: RegionSource> Buddy<S> {
+    unsafe fn free_block(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        // Zero-size blocks were never taken from a region
+        if layout.size() == 0 {
+            return;
+        }
+        let requested_size: usize = layout.size();
+
+        if !validate_free(
+            self.validation,
+            &self.on_invalid_free,
+            &self.lists,
+            &self.first_byte_ptrs,
+            &self.region_layouts[..],
+            NonNull::slice_from_raw_parts(ptr, layout.size().next_power_of_two()),
+        ) {
+            return;
+        }
+
+        let mut rounded_size: usize = 1;
+        let mut curr_power: usize = requested_size - 1;
+        let mut index = 0;
+
+        while curr_power != 0 {
+            curr_power >>= 1;
+            rounded_size <<= 1;
+            index += 1;
+        }
+        // The whole block is handed out again, including the slack past the request
+        if self.zero_on_free {
+            ptr.as_ptr().write_bytes(0, rounded_size);
+        }
+
+        self.current_allocated_size -= rounded_size;
+        self.class_live[index] -= 1;
+        #[cfg(debug_assertions)]
+        {
+            // Saturates since restore and reset can forget blocks that are still out
+            self.live_allocations = self.live_allocations.saturating_sub(1);
+        }
+        self.insert_free(ptr, rounded_size, index);
+    }
+
+    // Puts a free block back on the lists, merging it with its buddy for as long as the buddy is
+    // free too
+    unsafe fn insert_free(
+        &mut self,
+        mut curr_ptr: NonNull<u8>,
+        mut rounded_size: usize,
+        mut index: usize,
+    ) {
+        // Buddies are paired relative to the base of the region holding the block
+        let (offset, region_size): (usize, usize) = self
+            .region_containing(curr_ptr.addr().get())
+            .expect("freed block is outside every region");
+        loop {
+            if rounded_size == region_size {
+                let slice_ptr: NonNull<[u8]> =
+                    NonNull::slice_from_raw_parts(curr_ptr, rounded_size);
+                self.lists[index].push_back(slice_ptr);
+                return;
+            }
+
+            let current_addr: usize = curr_ptr.addr().get();
+            let normalized_addr: usize = current_addr - offset;
+
+            // get address of buddy (or if we have the smaller of the pair, xor if we have the larger of the pair)
+            let mut normalized_buddy_address: usize = normalized_addr | rounded_size;
+            if normalized_buddy_address == normalized_addr {
+                normalized_buddy_address = normalized_addr ^ rounded_size;
+            }
+
+            let buddy_address: usize = normalized_buddy_address + offset;
+
+            let mut buddy: Option<NonNull<[u8]>> = None;
+            let mut bound: ScanBound = ScanBound::new(index, self.lists[index].len());
+            let mut cursor: CursorMut<'_, NonNull<[u8]>> = self.lists[index].cursor_front_mut();
+            while buddy.is_none() && cursor.current().is_some() {
+                bound.step();
+                let curr = cursor.current().unwrap();
+                if buddy_address == curr.addr().get() {
+                    buddy = cursor.remove_current();
+                }
+                cursor.move_next();
+            }
+
+            if buddy.is_none() {
+                let slice_ptr: NonNull<[u8]> =
+                    NonNull::slice_from_raw_parts(curr_ptr, rounded_size);
+                if self.aggressive_coalesce {
+                    self.promote_free_span(slice_ptr, index);
+                } else {
+                    self.lists[index].push_back(slice_ptr);
+                }
+                return;
+            }
+
+            self.merge_count += 1;
+            rounded_size <<= 1;
+            index += 1;
+            if current_addr > buddy_address {
+                curr_ptr = buddy.unwrap().as_non_null_ptr();
+            }
+        }
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<S: RegionSource> Buddy<S> {
+    // Catches corrupted free lists before a split hands out memory that is already in use
+    fn assert_split_block_valid(&self, block: NonNull<[u8]>) {
+        let start: usize = block.addr().get();
+        let end: usize = start + block.len();
+
+        let in_region: bool = self
+            .region_containing(start)
+            .is_some_and(|(region_start, region_size)| end <= region_start + region_size);
+        assert!(
+            in_region,
+            "split block {:#x}..{:#x} is outside every region",
+            start, end
+        );
+
+        for list in &self.lists {
+            for free_block in list {
+                let free_start: usize = free_block.addr().get();
+                let free_end: usize = free_start + free_block.len();
+                assert!(
+                    end <= free_start || free_end <= start,
+                    "split block {:#x}..{:#x} overlaps free block {:#x}..{:#x}",
+                    start,
+                    end,
+                    free_start,
+                    free_end
+                );
+            }
         }
     }
 }
 
-impl Drop for Buddy {
+impl<S: RegionSource> Drop for Buddy<S> {
     fn drop(&mut self) {
-        let extend_heap_layout: Layout = Layout::from_size_align(512, 16).unwrap();
+        if self.leak_on_drop {
+            return;
+        }
+
+        // Releasing the regions would leave the live blocks dangling
+        #[cfg(debug_assertions)]
+        if self.live_allocations > 0 {
+            match &self.on_live_drop {
+                Some(on_live_drop) => on_live_drop(self.live_allocations),
+                // Don't turn an unwinding panic into an abort
+                None if std::thread::panicking() => {}
+                None => panic!(
+                    "buddy allocator dropped with {} live allocations",
+                    self.live_allocations
+                ),
+            }
+        }
         unsafe {
-            for ptr in &self.first_byte_ptrs {
-                System.deallocate(*ptr, extend_heap_layout);
+            for (ptr, layout) in self.first_byte_ptrs.iter().zip(&self.region_layouts) {
+                self.source.release(*ptr, *layout);
             }
         }
     }
 }
 
-impl MemStats for Buddy {
-    fn calculate_allocation_ratio(&self) -> (f64, f64, f64) {
+#[cfg(any(test, feature = "global_fallback"))]
+impl<S: RegionSource> Owns for Buddy<S> {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.region_containing(ptr.addr().get()).is_some()
+    }
+}
+
+#[cfg(test)]
+impl<S: RegionSource> FreeListInvariants for Buddy<S> {
+    fn check_invariants(&self) -> Result<(), String> {
+        check_free_lists(&self.lists, &self.first_byte_ptrs, &self.region_layouts[..])
+    }
+
+    fn repair(&mut self) -> usize {
+        repair_free_lists(
+            &mut self.lists,
+            &self.first_byte_ptrs,
+            &self.region_layouts[..],
+        )
+    }
+
+    fn inject_overlap(&mut self) {
+        list_first_block_twice(&mut self.lists);
+    }
+}
+
+#[cfg(test)]
+impl<S: RegionSource> Snapshot for Buddy<S> {
+    fn snapshot(&self) -> AllocatorSnapshot {
+        AllocatorSnapshot::capture(
+            &self.lists,
+            self.peak_allocated_size,
+            self.current_allocated_size,
+            &self.class_live,
+        )
+    }
+
+    fn restore(&mut self, snap: &AllocatorSnapshot) {
+        debug_assert!(snap.within_regions(&self.first_byte_ptrs, &self.region_layouts[..]));
+        snap.restore_lists(&mut self.lists, &mut self.class_live);
+        self.peak_allocated_size = snap.peak_allocated_size;
+        self.current_allocated_size = snap.current_allocated_size;
+        // Blocks handed out since the snapshot are back on the free lists
+        #[cfg(debug_assertions)]
+        {
+            self.live_allocations = snap.class_live.iter().sum::<u64>() as usize;
+        }
+    }
+}
+
+impl<S: RegionSource> MemStats for Buddy<S> {
+    fn calculate_allocation_ratio(&self) -> (usize, usize, f64) {
         (
             self.peak_allocated_size,
             self.total_size,
-            self.peak_allocated_size / self.total_size,
+            self.peak_allocated_size as f64 / self.total_size as f64,
+        )
+    }
+
+    fn current_allocated_size(&self) -> usize {
+        self.current_allocated_size
+    }
+
+    fn extension_count(&self) -> u64 {
+        self.extension_count
+    }
+
+    fn split_count(&self) -> u64 {
+        self.split_count
+    }
+
+    fn merge_count(&self) -> u64 {
+        self.merge_count
+    }
+
+    fn alloc_success(&self) -> u64 {
+        self.alloc_success
+    }
+
+    fn alloc_failure(&self) -> u64 {
+        self.alloc_failure
+    }
+
+    fn size_class_histogram(&self) -> Vec<u64> {
+        self.class_live.clone()
+    }
+
+    // Blocks smaller than a default region can't serve the largest request that is guaranteed to
+    // fit without acquiring a bigger region
+    fn external_fragmentation(&self) -> f64 {
+        fragmented_share(
+            self.lists.iter().flatten().map(|block| block.len()),
+            DEFAULT_REGION_SIZE,
         )
     }
 
     fn reset(&mut self) {
-        self.total_size = 0.0;
-        self.peak_allocated_size = 0.0;
-        self.current_allocated_size = 0.0;
-        for byte in &self.first_byte_ptrs {
+        self.total_size = 0;
+        self.peak_allocated_size = 0;
+        self.current_allocated_size = 0;
+        self.class_live.fill(0);
+        self.alloc_success = 0;
+        self.alloc_failure = 0;
+        self.extension_count = 0;
+        self.split_count = 0;
+        self.merge_count = 0;
+        #[cfg(debug_assertions)]
+        {
+            self.live_allocations = 0;
+        }
+        for (byte, layout) in self.first_byte_ptrs.iter().zip(&self.region_layouts) {
             unsafe {
-                System.deallocate(*byte, Layout::from_size_align_unchecked(512, 16));
+                self.source.release(*byte, *layout);
             }
         }
         self.first_byte_ptrs.clear();
+        self.region_layouts.clear();
         for list in &mut self.lists {
             while list.pop_front().is_some() {}
         }
     }
+
+    fn reset_peak(&mut self) {
+        self.peak_allocated_size = self.current_allocated_size;
+    }
 }
 
-unsafe impl Allocator for Locked<Buddy> {
-    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+impl<S: RegionSource> Locked<Buddy<S>> {
+    // Allocates and also reports the class that served the request, the block is 1 << class bytes
+    #[cfg(test)]
+    pub fn allocate_classified(
+        &self,
+        layout: Layout,
+    ) -> Result<(NonNull<[u8]>, usize), AllocError> {
+        let ptr: NonNull<[u8]> = self.allocate(layout)?;
+        Ok((ptr, ptr.len().trailing_zeros() as usize))
+    }
+
+    // Serves a whole 1 << class byte block, splitting or extending the heap as a request of that
+    // size would
+    #[cfg(test)]
+    pub fn allocate_from_class(&self, class: usize) -> Result<NonNull<[u8]>, AllocError> {
+        let size: usize = 1usize.checked_shl(class as u32).ok_or(AllocError)?;
+        self.allocate(Layout::from_size_align(size, 1).map_err(|_| AllocError)?)
+    }
+
+    // Frees every item under a single lock instead of locking once per block
+    #[cfg(test)]
+    pub unsafe fn deallocate_batch(&self, items: &[(NonNull<u8>, Layout)]) {
+        let mut alloc_mutex: MutexGuard<'_, Buddy<S>> = self.lock();
+        for (ptr, layout) in items {
+            alloc_mutex.free_block(*ptr, *layout);
+        }
+    }
+}
+
+impl<S: RegionSource> Buddy<S> {
+    // The whole allocation path, run by whichever lock holds the allocator
+    fn allocate_block(&mut self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
         // round up to the nearest power of 2 for allocation
         let requested_size: usize = layout.size();
         let mut rounded_size: usize = 1;
         let mut index: usize = 0;
 
-        // we will assume 512 is the max request size
-        if requested_size > 512 {
-            return Err(AllocError);
-        } else {
-            let mut curr_power: usize = requested_size - 1;
-            while curr_power != 0 {
-                curr_power >>= 1;
-                rounded_size <<= 1;
-                index += 1;
-            }
+        if let Some(block) = zero_size_block(layout) {
+            return Ok(block);
+        }
+
+        let mut curr_power: usize = requested_size - 1;
+        while curr_power != 0 {
+            curr_power >>= 1;
+            rounded_size <<= 1;
+            index += 1;
         }
 
         // now we check if we already have a block available to allocate
-        let mut alloc_mutex: MutexGuard<'_, Buddy> = self.lock();
+        // A block is only as aligned as its size and its region base, so stricter alignments are
+        // searched for
+        if layout.align() > usize::min(rounded_size, self.region_align) {
+            let block: NonNull<[u8]> = self
+                .take_aligned_block(index, layout.align())
+                .inspect_err(|_| self.alloc_failure += 1)?;
+            self.record_allocation(rounded_size);
+            return Ok(self.returned_slice(block, requested_size));
+        }
         let mut find_index: usize = index;
 
-        while find_index < 10 {
-            if alloc_mutex.lists[find_index].is_empty() {
-                find_index += 1;
-            } else {
-                break;
+        while find_index < self.lists.len() {
+            if self.lists[find_index].is_empty() {
+                find_index += 1;
+            } else {
+                break;
+            }
+        }
+
+        // if no block found, extend the heap, with a region large enough for the request
+        if find_index >= self.lists.len() {
+            // need to extend heap
+            let extend_heap_layout: Layout = Layout::from_size_align(
+                usize::max(DEFAULT_REGION_SIZE, rounded_size),
+                self.region_align,
+            )
+            .map_err(|_| AllocError)
+            .inspect_err(|_| self.alloc_failure += 1)?;
+            self.acquire_region(extend_heap_layout)
+                .inspect_err(|_| self.alloc_failure += 1)?;
+        }
+
+        // recursively split block until we have one that fits the size we want (rounded size)
+        find_index = index + 1;
+        let mut allocated_block: Option<NonNull<[u8]>> = None;
+
+        while allocated_block.is_none() {
+            match self.take_free_block(index) {
+                Some(block) => {
+                    allocated_block = Some(block);
+                }
+                None => match self.take_free_block(find_index) {
+                    None => {
+                        find_index += 1;
+                    }
+                    Some(mut unsplit_block) => unsafe {
+                        find_index -= 1;
+                        let unsplit_block_mut: &mut [u8] = unsplit_block.as_mut();
+                        let split_len: usize = unsplit_block_mut.len() >> 1;
+                        let (block_one, block_two): (&mut [u8], &mut [u8]) =
+                            unsplit_block_mut.split_at_mut(split_len);
+                        let block_one: NonNull<[u8]> = NonNull::slice_from_raw_parts(
+                            NonNull::new(block_one.as_mut_ptr()).unwrap(),
+                            split_len,
+                        );
+                        let block_two: NonNull<[u8]> = NonNull::slice_from_raw_parts(
+                            NonNull::new(block_two.as_mut_ptr()).unwrap(),
+                            split_len,
+                        );
+                        #[cfg(debug_assertions)]
+                        {
+                            self.assert_split_block_valid(block_one);
+                            self.assert_split_block_valid(block_two);
+                        }
+                        self.split_count += 1;
+                        self.lists[find_index].push_back(block_one);
+                        self.lists[find_index].push_back(block_two);
+                    },
+                },
+            }
+        }
+        self.record_allocation(rounded_size);
+
+        // guaranteed to contain a block
+        Ok(self.returned_slice(allocated_block.unwrap(), requested_size))
+    }
+}
+
+impl<S: RegionSource> ExclusiveAllocator for Buddy<S> {
+    fn allocate_exclusive(&mut self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocate_block(layout)
+    }
+
+    unsafe fn deallocate_exclusive(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.free_block(ptr, layout);
+    }
+}
+
+unsafe impl<S: RegionSource> Allocator for Locked<Buddy<S>> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let _in_flight: InFlight<'_> = self.enter_allocate();
+        self.lock().allocate_block(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.lock().free_block(ptr, layout);
+    }
+
+    // Keeps the lower part of the block when the new size falls into a smaller class, handing the
+    // upper halves back to the free lists
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if new_layout.size() == 0 || !ptr.addr().get().is_multiple_of(new_layout.align()) {
+            let new_ptr: NonNull<[u8]> = self.allocate(new_layout)?;
+            std::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_mut_ptr(), new_layout.size());
+            self.deallocate(ptr, old_layout);
+            return Ok(new_ptr);
+        }
+
+        let old_rounded: usize = old_layout.size().next_power_of_two();
+        let new_rounded: usize = new_layout.size().next_power_of_two();
+        let mut alloc_mutex: MutexGuard<'_, Buddy<S>> = self.lock();
+        let mut half: usize = old_rounded >> 1;
+        if alloc_mutex.zero_on_free {
+            ptr.as_ptr()
+                .add(new_rounded)
+                .write_bytes(0, old_rounded - new_rounded);
+        }
+        while half >= new_rounded {
+            alloc_mutex.insert_free(ptr.add(half), half, half.trailing_zeros() as usize);
+            alloc_mutex.split_count += 1;
+            half >>= 1;
+        }
+        alloc_mutex.current_allocated_size -= old_rounded - new_rounded;
+        alloc_mutex.class_live[old_rounded.trailing_zeros() as usize] -= 1;
+        alloc_mutex.class_live[new_rounded.trailing_zeros() as usize] += 1;
+        let block: NonNull<[u8]> = NonNull::slice_from_raw_parts(ptr, new_rounded);
+        Ok(alloc_mutex.returned_slice(block, new_layout.size()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_external_fragmentation() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+        assert_eq!(allocator.lock().external_fragmentation(), 0.0);
+
+        let blocks: Vec<NonNull<[u8]>> = (0..16)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+        // Every other block freed, no two free blocks are buddies so none of them merge
+        for block in blocks.iter().step_by(2) {
+            unsafe {
+                allocator.deallocate(block.as_non_null_ptr(), layout);
+            }
+        }
+        assert_eq!(allocator.lock().external_fragmentation(), 1.0);
+
+        // A whole free region alongside the holes fits the largest request again
+        allocator.lock().grow_pool(1).unwrap();
+        assert_eq!(
+            allocator.lock().external_fragmentation(),
+            256.0 / (256.0 + 512.0)
+        );
+
+        for block in blocks.iter().skip(1).step_by(2) {
+            unsafe {
+                allocator.deallocate(block.as_non_null_ptr(), layout);
+            }
+        }
+        assert_eq!(allocator.lock().external_fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn test_coalesce_in_second_region() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let whole: Layout = Layout::from_size_align(512, 8).unwrap();
+        let half: Layout = Layout::from_size_align(256, 8).unwrap();
+        let first_region: NonNull<[u8]> = allocator.allocate(whole).unwrap();
+        let left: NonNull<[u8]> = allocator.allocate(half).unwrap();
+        let right: NonNull<[u8]> = allocator.allocate(half).unwrap();
+        assert_eq!(allocator.lock().first_byte_ptrs.len(), 2);
+
+        // Buddies pair relative to the second region's base, not the first one's
+        unsafe {
+            allocator.deallocate(right.as_non_null_ptr(), half);
+            allocator.deallocate(left.as_non_null_ptr(), half);
+        }
+        let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+        assert_eq!(alloc.lists[9].len(), 1);
+        assert_eq!(
+            alloc.lists[9].front().unwrap().as_mut_ptr(),
+            alloc.first_byte_ptrs[1].as_ptr()
+        );
+        assert!(alloc.lists[..9].iter().all(|list| list.is_empty()));
+        assert_ne!(first_region.as_mut_ptr(), left.as_mut_ptr());
+        Mutex::unlock(alloc);
+        unsafe {
+            allocator.deallocate(first_region.as_non_null_ptr(), whole);
+        }
+    }
+
+    // Hands out 512-byte regions from the top of one buffer down, so every later region lies below
+    // the first
+    struct DescendingSource {
+        buffer: NonNull<u8>,
+        next_top: std::cell::Cell<usize>,
+    }
+
+    impl RegionSource for DescendingSource {
+        fn acquire(&self, layout: Layout, _node: u8) -> Result<NonNull<[u8]>, AllocError> {
+            if layout.size() != 512 || self.next_top.get() == 0 {
+                return Err(AllocError);
+            }
+            self.next_top.set(self.next_top.get() - 512);
+            let start: NonNull<u8> = unsafe { self.buffer.add(self.next_top.get()) };
+            Ok(NonNull::slice_from_raw_parts(start, 512))
+        }
+
+        unsafe fn release(&self, _ptr: NonNull<u8>, _layout: Layout) {}
+    }
+
+    #[test]
+    fn test_coalesce_in_region_below_first() {
+        let buffer_layout: Layout = Layout::from_size_align(1024, 512).unwrap();
+        let buffer: NonNull<[u8]> = System.allocate(buffer_layout).unwrap();
+        let allocator: Locked<Buddy<DescendingSource>> =
+            Locked::new(Buddy::with_source(DescendingSource {
+                buffer: buffer.as_non_null_ptr(),
+                next_top: std::cell::Cell::new(1024),
+            }));
+        let whole: Layout = Layout::from_size_align(512, 8).unwrap();
+        let half: Layout = Layout::from_size_align(256, 8).unwrap();
+        let first_region: NonNull<[u8]> = allocator.allocate(whole).unwrap();
+        let left: NonNull<[u8]> = allocator.allocate(half).unwrap();
+        let right: NonNull<[u8]> = allocator.allocate(half).unwrap();
+        {
+            let alloc: MutexGuard<'_, Buddy<DescendingSource>> = allocator.lock();
+            assert!(alloc.first_byte_ptrs[1] < alloc.first_byte_ptrs[0]);
+        }
+
+        // Pairing against the first region's base would underflow for these blocks
+        unsafe {
+            allocator.deallocate(right.as_non_null_ptr(), half);
+            allocator.deallocate(left.as_non_null_ptr(), half);
+        }
+        {
+            let alloc: MutexGuard<'_, Buddy<DescendingSource>> = allocator.lock();
+            assert_eq!(alloc.lists[9].len(), 1);
+            assert_eq!(
+                alloc.lists[9].front().unwrap().as_mut_ptr(),
+                buffer.as_mut_ptr()
+            );
+            assert!(alloc.lists[..9].iter().all(|list| list.is_empty()));
+        }
+        unsafe {
+            allocator.deallocate(first_region.as_non_null_ptr(), whole);
+        }
+        drop(allocator);
+        unsafe {
+            System.deallocate(buffer.as_non_null_ptr(), buffer_layout);
+        }
+    }
+
+    #[test]
+    fn test_allocate_larger_than_default_region() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(2048, 8).unwrap();
+        let large: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(large.len(), 2048);
+        {
+            let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+            // A single region sized for the request, with lists up to its class
+            assert_eq!(alloc.first_byte_ptrs.len(), 1);
+            assert_eq!(alloc.calculate_allocation_ratio().1, 2048);
+            assert_eq!(alloc.lists.len(), 12);
+        }
+        unsafe {
+            allocator.deallocate(large.as_non_null_ptr(), layout);
+        }
+        assert_eq!(allocator.lock().lists[11].len(), 1);
+
+        // Smaller requests split the large region instead of acquiring a default one
+        let small: Layout = Layout::from_size_align(600, 8).unwrap();
+        let first: NonNull<[u8]> = allocator.allocate(small).unwrap();
+        let second: NonNull<[u8]> = allocator.allocate(small).unwrap();
+        assert_eq!(first.as_mut_ptr(), large.as_mut_ptr());
+        assert_eq!(second.addr().get(), large.addr().get() + 1024);
+        {
+            let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+            assert_eq!(alloc.extension_count, 1);
+            assert!(alloc.lists.iter().all(|list| list.is_empty()));
+            assert!(alloc.check_invariants().is_ok());
+        }
+        unsafe {
+            allocator.deallocate(first.as_non_null_ptr(), small);
+            allocator.deallocate(second.as_non_null_ptr(), small);
+        }
+        let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+        assert_eq!(alloc.lists[11].len(), 1);
+        assert_eq!(alloc.split_tree(), vec![BuddyTree::Free]);
+    }
+
+    #[test]
+    fn test_over_aligned_layout() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(32, 64).unwrap();
+        let blocks: Vec<NonNull<[u8]>> = (0..10)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+        for block in &blocks {
+            assert!(block.addr().get().is_multiple_of(64));
+            assert_eq!(block.len(), 32);
+        }
+
+        // Alignments beyond the region base come from regions acquired at that alignment
+        let page: Layout = Layout::from_size_align(8, 256).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(page).unwrap();
+        assert!(ptr.addr().get().is_multiple_of(256));
+        assert_eq!(allocator.lock().guaranteed_alignment(&page), 256);
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), page);
+            for block in blocks {
+                allocator.deallocate(block.as_non_null_ptr(), layout);
             }
         }
+        let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+        assert!(alloc.check_invariants().is_ok());
+        assert!(alloc
+            .region_occupancy()
+            .iter()
+            .all(|occupancy| *occupancy == 0.0));
+    }
 
-        // if no block found, extend the heap
-        if find_index >= 10 {
-            // need to extend heap
-            let extend_heap_layout: Layout = Layout::from_size_align(512, 16).unwrap();
-            let ptr: NonNull<[u8]> = System.allocate(extend_heap_layout).unwrap();
-            // ln!("{}", ptr.addr());
-            let first_byte_ptr: NonNull<u8> = ptr.as_non_null_ptr();
-            alloc_mutex.lists[9].push_back(ptr);
-            alloc_mutex.first_byte_ptrs.push(first_byte_ptr);
-            // println!("{:#?}", alloc_mutex.first_byte_ptrs)
-            alloc_mutex.total_size += 512.0;
+    #[test]
+    fn test_free_distribution() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let small: Layout = Layout::from_size_align(32, 8).unwrap();
+        let medium: Layout = Layout::from_size_align(64, 8).unwrap();
+        let first: NonNull<[u8]> = allocator.allocate(small).unwrap();
+        let second: NonNull<[u8]> = allocator.allocate(medium).unwrap();
+        unsafe {
+            allocator.deallocate(first.as_non_null_ptr(), small);
         }
 
-        // recursively split block until we have one that fits the size we want (rounded size)
-        find_index = index + 1;
-        let mut allocated_block: Option<NonNull<[u8]>> = None;
-
-        while allocated_block.is_none() {
-            match alloc_mutex.lists[index].pop_front() {
-                Some(block) => {
-                    allocated_block = Some(block);
-                }
-                None => match alloc_mutex.lists[find_index].pop_front() {
-                    None => {
-                        find_index += 1;
-                    }
-                    Some(mut unsplit_block) => unsafe {
-                        find_index -= 1;
-                        let unsplit_block_mut: &mut [u8] = unsplit_block.as_mut();
-                        let split_len: usize = unsplit_block_mut.len() >> 1;
-                        let (block_one, block_two): (&mut [u8], &mut [u8]) =
-                            unsplit_block_mut.split_at_mut(split_len);
-                        alloc_mutex.lists[find_index].push_back(NonNull::slice_from_raw_parts(
-                            NonNull::new(block_one.as_mut_ptr()).unwrap(),
-                            split_len,
-                        ));
-                        alloc_mutex.lists[find_index].push_back(NonNull::slice_from_raw_parts(
-                            NonNull::new(block_two.as_mut_ptr()).unwrap(),
-                            split_len,
-                        ));
-                    },
-                },
-            }
+        // The freed 32 merges with its buddy into a 64, whose own buddy is still allocated
+        let mut expected: Vec<(usize, usize, usize)> = (0..10).map(|index| (index, 0, 0)).collect();
+        expected[6] = (6, 1, 64);
+        expected[7] = (7, 1, 128);
+        expected[8] = (8, 1, 256);
+        assert_eq!(allocator.lock().free_distribution(), expected);
+        unsafe {
+            allocator.deallocate(second.as_non_null_ptr(), medium);
         }
-        alloc_mutex.current_allocated_size += rounded_size as f64;
-        alloc_mutex.peak_allocated_size = f64::max(
-            alloc_mutex.current_allocated_size,
-            alloc_mutex.peak_allocated_size,
-        );
-
-        // guaranteed to contain a block
-        Ok(allocated_block.unwrap())
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        let requested_size: usize = layout.size();
-        let mut curr_ptr = ptr;
+    #[test]
+    fn test_shrink_to_smaller_class() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let old_layout: Layout = Layout::from_size_align(256, 8).unwrap();
+        let new_layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(old_layout).unwrap();
+        let base: usize = ptr.addr().get();
 
-        let mut alloc_mutex = self.lock();
-        let offset: usize = alloc_mutex.first_byte_ptrs[0].addr().get();
+        let shrunk: NonNull<[u8]> =
+            unsafe { allocator.shrink(ptr.as_non_null_ptr(), old_layout, new_layout) }.unwrap();
+        assert_eq!((shrunk.addr().get(), shrunk.len()), (base, 64));
+        {
+            let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+            let front = |index: usize| alloc.lists[index].front().map(|block| block.addr().get());
+            assert_eq!(front(6), Some(base + 64));
+            assert_eq!(front(7), Some(base + 128));
+            assert!(alloc.stats_consistent());
+        }
 
-        let mut rounded_size: usize = 1;
-        let mut curr_power: usize = requested_size - 1;
-        let mut index = 0;
+        // With the 64 block freed, the released halves merge all the way back into the region
+        unsafe {
+            allocator.deallocate(shrunk.as_non_null_ptr(), new_layout);
+        }
+        {
+            let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+            assert_eq!(alloc.lists[9].len(), 1);
+            assert!(alloc.lists[..9].iter().all(|list| list.is_empty()));
+        }
 
-        while curr_power != 0 {
-            curr_power >>= 1;
-            rounded_size <<= 1;
-            index += 1;
+        // Blocks from a larger region hand back halves above the default region size too
+        let large: Layout = Layout::from_size_align(2048, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(large).unwrap();
+        let shrunk: NonNull<[u8]> =
+            unsafe { allocator.shrink(ptr.as_non_null_ptr(), large, new_layout) }.unwrap();
+        assert_eq!(shrunk.as_mut_ptr(), ptr.as_mut_ptr());
+        let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+        assert_eq!(
+            alloc.lists[10].front().unwrap().addr().get(),
+            ptr.addr().get() + 1024
+        );
+        assert_eq!(alloc.lists[9].len(), 2);
+        assert!(alloc.check_invariants().is_ok());
+        Mutex::unlock(alloc);
+        unsafe {
+            allocator.deallocate(shrunk.as_non_null_ptr(), new_layout);
         }
+    }
 
-        alloc_mutex.current_allocated_size -= rounded_size as f64;
-        loop {
-            if rounded_size == 512 {
-                let slice_ptr: NonNull<[u8]> =
-                    NonNull::slice_from_raw_parts(curr_ptr, rounded_size);
-                alloc_mutex.lists[9].push_back(slice_ptr);
-                return;
+    #[test]
+    fn test_allocate_from_class() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        for (class, size) in [(3, 8), (9, 512), (10, 1024)] {
+            let block: NonNull<[u8]> = allocator.allocate_from_class(class).unwrap();
+            assert_eq!(block.len(), size);
+            unsafe {
+                allocator.deallocate(
+                    block.as_non_null_ptr(),
+                    Layout::from_size_align(size, 8).unwrap(),
+                );
             }
+        }
+        assert!(allocator.allocate_from_class(usize::BITS as usize).is_err());
+    }
 
-            let current_addr: usize = curr_ptr.addr().get();
-            let normalized_addr: usize = current_addr - offset; // should always be positive since offset is first address
+    #[test]
+    fn test_prefer_low_addresses() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        allocator.lock().set_prefer_low_addresses(true);
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+        let blocks: Vec<NonNull<[u8]>> = (0..4)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
 
-            // get address of buddy (or if we have the smaller of the pair, xor if we have the larger of the pair)
-            let mut normalized_buddy_address: usize = normalized_addr | rounded_size;
-            if normalized_buddy_address == normalized_addr {
-                normalized_buddy_address = normalized_addr ^ rounded_size;
+        // Leaves the higher block at the front of the 32 list
+        unsafe {
+            allocator.deallocate(blocks[2].as_non_null_ptr(), layout);
+            allocator.deallocate(blocks[0].as_non_null_ptr(), layout);
+        }
+        for _ in 0..3 {
+            let block: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+            assert_eq!(block.addr(), blocks[0].addr());
+            unsafe {
+                allocator.deallocate(block.as_non_null_ptr(), layout);
             }
+        }
+        unsafe {
+            allocator.deallocate(blocks[1].as_non_null_ptr(), layout);
+            allocator.deallocate(blocks[3].as_non_null_ptr(), layout);
+        }
+    }
 
-            let buddy_address: usize = normalized_buddy_address + offset;
-
-            let mut buddy: Option<NonNull<[u8]>> = None;
-            let mut cursor: CursorMut<'_, NonNull<[u8]>> =
-                alloc_mutex.lists[index].cursor_front_mut();
-            while buddy.is_none() && cursor.current().is_some() {
-                let curr = cursor.current().unwrap();
-                if buddy_address == curr.addr().get() {
-                    buddy = cursor.remove_current();
-                }
-                cursor.move_next();
+    #[test]
+    fn test_guaranteed_alignment() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        for size in [1, 3, 8, 12, 16, 24, 100, 7, 64, 200, 2, 512] {
+            let layout: Layout = Layout::from_size_align(size, 1).unwrap();
+            let guaranteed: usize = allocator.lock().guaranteed_alignment(&layout);
+            assert_eq!(guaranteed, usize::min(size.next_power_of_two(), 16));
+            let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+            assert!(ptr.as_mut_ptr().addr().is_multiple_of(guaranteed));
+            unsafe {
+                allocator.deallocate(ptr.as_non_null_ptr(), layout);
             }
+        }
+    }
 
-            if buddy.is_none() {
-                let slice_ptr: NonNull<[u8]> =
-                    NonNull::slice_from_raw_parts(curr_ptr, rounded_size);
-                alloc_mutex.lists[index].push_back(slice_ptr);
-                return;
-            }
+    #[test]
+    fn test_trim_to_request() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        allocator.lock().set_trim_to_request(true);
+        let layout: Layout = Layout::from_size_align(100, 8).unwrap();
 
-            rounded_size <<= 1;
-            index += 1;
-            if current_addr > buddy_address {
-                curr_ptr = buddy.unwrap().as_non_null_ptr();
-            }
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(ptr.len(), 100);
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
         }
+        // The whole 128 byte block went back
+        let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+        assert_eq!(alloc.current_allocated_size, 0);
+        assert_eq!(alloc.lists[9].len(), 1);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::Mutex;
+    #[test]
+    fn test_split_tree() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+
+        // 512 down to 32 splits four times, each time the upper half is left free
+        let split = |left: BuddyTree| BuddyTree::Split(Box::new(left), Box::new(BuddyTree::Free));
+        let expected: BuddyTree = split(split(split(split(BuddyTree::Allocated))));
+        assert_eq!(allocator.lock().split_tree(), vec![expected]);
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+    }
+
+    #[test]
+    fn test_grow_pool() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let live: NonNull<[u8]> = allocator.allocate_filled(layout, 0x5A).unwrap();
+        let total_before: usize = allocator.lock().calculate_allocation_ratio().1;
+
+        allocator.lock().grow_pool(2).unwrap();
+        assert_eq!(
+            allocator.lock().calculate_allocation_ratio().1,
+            total_before + 1024
+        );
+        unsafe {
+            assert!(live.as_ref().iter().all(|byte| *byte == 0x5A));
+        }
+        assert_eq!(allocator.lock().lists[9].len(), 2);
+        unsafe {
+            allocator.deallocate(live.as_non_null_ptr(), layout);
+        }
+    }
 
     #[test]
     fn test_allocate_fail() {
         let allocator: Locked<Buddy> = Locked::new(Buddy::new());
-        let invalid_layout: Layout = Layout::from_size_align(1024, 16).unwrap();
+        // No source can hand out a region this large
+        let invalid_layout: Layout = Layout::from_size_align(1 << 62, 16).unwrap();
         assert_eq!(allocator.allocate(invalid_layout), Err(AllocError));
+        assert_eq!(allocator.lock().alloc_failure, 1);
     }
 
     #[test]
     fn test_allocate_success() {
         let allocator: Locked<Buddy> = Locked::new(Buddy::new());
-        let layout: Layout = Layout::from_size_align(120, 8).unwrap();
-        let ptr: Result<NonNull<[u8]>, AllocError> = allocator.allocate(layout);
+        let first_layout: Layout = Layout::from_size_align(120, 8).unwrap();
+        let first: Result<NonNull<[u8]>, AllocError> = allocator.allocate(first_layout);
 
-        assert!(ptr.is_ok());
-        assert_eq!(ptr.unwrap().len(), 128);
+        assert!(first.is_ok());
+        assert_eq!(first.unwrap().len(), 128);
 
         // verify blocks are split correctly
         // should have one 256 block and one 128 block (index 7 and 8)
@@ -266,6 +1386,11 @@ mod tests {
         assert_eq!(alloc_mutex.lists[7].len(), 0);
         assert_eq!(alloc_mutex.lists[8].len(), 1);
         Mutex::unlock(alloc_mutex);
+
+        unsafe {
+            allocator.deallocate(ptr.unwrap().as_non_null_ptr(), layout);
+            allocator.deallocate(first.unwrap().as_non_null_ptr(), first_layout);
+        }
     }
 
     #[test]
@@ -273,7 +1398,7 @@ mod tests {
         // TODO: Need to change recursion to a loop to avoid stack overflows + increase performance gains
         let allocator: Locked<Buddy> = Locked::new(Buddy::new());
         let layout: Layout = Layout::from_size_align(120, 8).unwrap();
-        let ptr: NonNull<[u8]> = allocator.allocate(layout.clone()).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
 
         unsafe {
             let first_byte_ptr: NonNull<u8> = ptr.as_non_null_ptr();
@@ -292,7 +1417,7 @@ mod tests {
         assert_eq!(alloc_mutex.lists[9].len(), 1);
         Mutex::unlock(alloc_mutex);
 
-        let ptr = allocator.allocate(layout.clone()).unwrap();
+        let ptr = allocator.allocate(layout).unwrap();
         let alloc_mutex: MutexGuard<'_, Buddy> = allocator.lock();
         // println!("{:#?}", alloc_mutex.lists);
         assert_eq!(alloc_mutex.lists[0].len(), 0);
@@ -308,7 +1433,7 @@ mod tests {
         Mutex::unlock(alloc_mutex);
 
         let smaller_layout: Layout = Layout::from_size_align(3, 8).unwrap();
-        let ptr2: NonNull<[u8]> = allocator.allocate(smaller_layout.clone()).unwrap();
+        let ptr2: NonNull<[u8]> = allocator.allocate(smaller_layout).unwrap();
 
         let alloc_mutex: MutexGuard<'_, Buddy> = allocator.lock();
         // println!("{:#?}", alloc_mutex.lists);
@@ -361,11 +1486,165 @@ mod tests {
         Mutex::unlock(alloc_mutex);
     }
 
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "overlaps free block")]
+    fn test_split_corrupt_list() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let _ = allocator
+            .allocate(Layout::from_size_align(256, 8).unwrap())
+            .unwrap();
+
+        // Duplicate the remaining free 256 block so splitting it overlaps a listed block
+        let mut alloc_mutex: MutexGuard<'_, Buddy> = allocator.lock();
+        let free_block: NonNull<[u8]> = *alloc_mutex.lists[8].front().unwrap();
+        alloc_mutex.lists[8].push_back(free_block);
+        Mutex::unlock(alloc_mutex);
+
+        let _ = allocator.allocate(Layout::from_size_align(128, 8).unwrap());
+    }
+
+    #[test]
+    fn test_page_aligned_regions() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::with_page_aligned_regions(true));
+        let layout: Layout = Layout::from_size_align(512, 16).unwrap();
+        let blocks: Vec<NonNull<[u8]>> = (0..3)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+
+        let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+        assert_eq!(alloc.first_byte_ptrs.len(), 3);
+        assert!(alloc
+            .first_byte_ptrs
+            .iter()
+            .all(|region| region.addr().get() % PAGE_SIZE == 0));
+        Mutex::unlock(alloc);
+        for block in blocks {
+            unsafe {
+                allocator.deallocate(block.as_non_null_ptr(), layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_aggressive_coalesce() {
+        for aggressive in [false, true] {
+            let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+            allocator.lock().set_aggressive_coalesce(aggressive);
+            let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+            let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+
+            // Leave the upper half free as two 128 blocks instead of its 256 buddy
+            let mut alloc_mutex: MutexGuard<'_, Buddy> = allocator.lock();
+            let upper_half: NonNull<[u8]> = alloc_mutex.lists[8].pop_front().unwrap();
+            let middle: NonNull<u8> = unsafe { upper_half.as_non_null_ptr().add(128) };
+            alloc_mutex.lists[7].push_back(NonNull::slice_from_raw_parts(
+                upper_half.as_non_null_ptr(),
+                128,
+            ));
+            alloc_mutex.lists[7].push_back(NonNull::slice_from_raw_parts(middle, 128));
+            Mutex::unlock(alloc_mutex);
+
+            unsafe {
+                allocator.deallocate(ptr.as_non_null_ptr(), layout);
+            }
+
+            let alloc_mutex: MutexGuard<'_, Buddy> = allocator.lock();
+            if aggressive {
+                assert_eq!(alloc_mutex.lists[9].len(), 1);
+                assert!(alloc_mutex.lists[..9].iter().all(|list| list.is_empty()));
+            } else {
+                // Strict coalescing stops at 256 since its buddy isn't listed as a 256 block
+                assert_eq!(alloc_mutex.lists[9].len(), 0);
+                assert_eq!(alloc_mutex.lists[8].len(), 1);
+                assert_eq!(alloc_mutex.lists[7].len(), 2);
+            }
+        }
+    }
+
+    #[test]
+    fn test_usable_size() {
+        let allocator: Buddy = Buddy::new();
+        assert_eq!(
+            allocator.usable_size(&Layout::from_size_align(100, 8).unwrap()),
+            128
+        );
+        assert_eq!(
+            allocator.usable_size(&Layout::from_size_align(1000, 8).unwrap()),
+            1024
+        );
+    }
+
+    #[test]
+    fn test_deallocate_same_class_sizes() {
+        // (allocate size, deallocate size) pairs that round to the same class
+        let pairs: [(usize, usize); 6] = [
+            (120, 128),
+            (120, 100),
+            (120, 70),
+            (65, 128),
+            (3, 4),
+            (300, 257),
+        ];
+        for (allocate_size, deallocate_size) in pairs {
+            let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+            for _ in 0..3 {
+                let ptr: NonNull<[u8]> = allocator
+                    .allocate(Layout::from_size_align(allocate_size, 8).unwrap())
+                    .unwrap();
+                unsafe {
+                    allocator.deallocate(
+                        ptr.as_non_null_ptr(),
+                        Layout::from_size_align(deallocate_size, 8).unwrap(),
+                    );
+                }
+
+                let alloc_mutex: MutexGuard<'_, Buddy> = allocator.lock();
+                assert_eq!(alloc_mutex.current_allocated_size, 0);
+                assert_eq!(alloc_mutex.lists[9].len(), 1);
+                assert!(alloc_mutex.lists[..9].iter().all(|list| list.is_empty()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_zero_on_free() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::with_zero_on_free(true));
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate_filled(layout, 0x5A).unwrap();
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+
+        let reused: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(reused.as_mut_ptr(), ptr.as_mut_ptr());
+        unsafe {
+            assert!(reused.as_ref()[..64].iter().all(|byte| *byte == 0));
+        }
+
+        // A 100 byte request is served a 128 byte block, the slack past the request is scrubbed too
+        let odd: Layout = Layout::from_size_align(100, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate_filled(odd, 0x5A).unwrap();
+        assert_eq!(ptr.len(), 128);
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), odd);
+        }
+        let reused_odd: NonNull<[u8]> = allocator
+            .allocate(Layout::from_size_align(128, 8).unwrap())
+            .unwrap();
+        assert_eq!(reused_odd.as_mut_ptr(), ptr.as_mut_ptr());
+        unsafe {
+            assert!(reused_odd.as_ref().iter().all(|byte| *byte == 0));
+            allocator.deallocate(reused_odd.as_non_null_ptr(), odd);
+            allocator.deallocate(reused.as_non_null_ptr(), layout);
+        }
+    }
+
     #[test]
     fn test_allocation_stats() {
         let allocator: Locked<Buddy> = Locked::new(Buddy::new());
-        let layout: Layout = Layout::from_size_align(256, 8).unwrap();
-        let _ = allocator.allocate(layout).unwrap();
+        let large: Layout = Layout::from_size_align(256, 8).unwrap();
+        let large_ptr: NonNull<[u8]> = allocator.allocate(large).unwrap();
 
         let layout: Layout = Layout::from_size_align(128, 8).unwrap();
         let ptr = allocator.allocate(layout).unwrap();
@@ -376,11 +1655,183 @@ mod tests {
         }
 
         let layout: Layout = Layout::from_size_align(32, 8).unwrap();
-        let _ = allocator.allocate(layout).unwrap();
+        let small_ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+
+        let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+        assert_eq!(alloc.total_size, 512);
+        assert_eq!(alloc.peak_allocated_size, 384);
+        assert_eq!(alloc.current_allocated_size, 288);
+        Mutex::unlock(alloc);
+
+        unsafe {
+            allocator.deallocate(small_ptr.as_non_null_ptr(), layout);
+            allocator.deallocate(large_ptr.as_non_null_ptr(), large);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "dropped with 1 live allocations")]
+    fn test_drop_with_live_allocation() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let boxed: Box<u64, &Locked<Buddy>> = Box::new_in(7, &allocator);
+
+        // Leak the box so the allocator is dropped while its block is still lent out
+        std::mem::forget(boxed);
+        drop(allocator);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_on_live_drop_hook() {
+        let (sender, receiver): (Sender<usize>, Receiver<usize>) = mpsc::channel();
+        let mut buddy: Buddy = Buddy::new();
+        buddy.set_on_live_drop(Box::new(move |live| sender.send(live).unwrap()));
+        let allocator: Locked<Buddy> = Locked::new(buddy);
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+        let _lent: [NonNull<[u8]>; 2] = [(); 2].map(|_| allocator.allocate(layout).unwrap());
+
+        // The hook replaces the panic, and still sees the blocks that were never freed
+        drop(allocator);
+        assert_eq!(receiver.iter().collect::<Vec<usize>>(), vec![2]);
+    }
+
+    #[test]
+    fn test_split_merge_counts() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(8, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+
+        // 512 down to 8 is six levels
+        assert_eq!(allocator.lock().split_count(), 6);
+
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+        let mut alloc: MutexGuard<'_, Buddy> = allocator.lock();
+        assert_eq!(alloc.merge_count(), 6);
+        alloc.reset();
+        assert_eq!((alloc.split_count(), alloc.merge_count()), (0, 0));
+    }
+
+    #[test]
+    fn test_coalesce_around() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(128, 8).unwrap();
+        let blocks: Vec<NonNull<[u8]>> = (0..8)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+
+        // List the blocks as free without merging them, as frees that missed their buddy would
+        let mut alloc: MutexGuard<'_, Buddy> = allocator.lock();
+        alloc.lists[7].extend(blocks);
+        let first_region: usize = alloc.first_byte_ptrs[0].addr().get();
+        alloc.coalesce_around(first_region + 200);
+
+        assert_eq!(alloc.lists[9].len(), 1);
+        assert_eq!(alloc.lists[9].front().unwrap().addr().get(), first_region);
+        // The second region was never scanned
+        assert_eq!(alloc.lists[7].len(), 4);
+        assert_eq!(alloc.merge_count(), 3);
+        // The blocks were freed by hand, so the live count has to be dropped by hand too
+        #[cfg(debug_assertions)]
+        {
+            alloc.live_allocations = 0;
+        }
+    }
+
+    #[test]
+    fn test_owns() {
+        let a: Locked<Buddy> = Locked::new(Buddy::new());
+        let b: Locked<Buddy> = Locked::new(Buddy::new());
+        let small: Layout = Layout::from_size_align(8, 8).unwrap();
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let other: NonNull<[u8]> = b.allocate(small).unwrap();
+        let ptr: NonNull<[u8]> = a.allocate(layout).unwrap();
 
+        assert!(a.lock().owns(ptr.as_non_null_ptr()));
+        assert!(!b.lock().owns(ptr.as_non_null_ptr()));
+        unsafe {
+            a.deallocate(ptr.as_non_null_ptr(), layout);
+            b.deallocate(other.as_non_null_ptr(), small);
+        }
+    }
+
+    #[test]
+    fn test_max_alloc_size() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let max: usize = allocator.lock().max_alloc_size();
+        assert_eq!(max, 512);
+        let fits: Layout = Layout::from_size_align(max, 8).unwrap();
+        let fitted: NonNull<[u8]> = allocator.allocate(fits).unwrap();
+        assert_eq!(allocator.lock().calculate_allocation_ratio().1, max);
+        // One past it needs a region of its own
+        let past: Layout = Layout::from_size_align(max + 1, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(past).unwrap();
+        assert_eq!(allocator.lock().calculate_allocation_ratio().1, 3 * max);
+        unsafe {
+            allocator.deallocate(fitted.as_non_null_ptr(), fits);
+            allocator.deallocate(ptr.as_non_null_ptr(), past);
+        }
+    }
+
+    #[test]
+    fn test_allocate_classified() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(100, 8).unwrap();
+        let (ptr, class_index): (NonNull<[u8]>, usize) =
+            allocator.allocate_classified(layout).unwrap();
+        assert_eq!(class_index, 7);
+        assert_eq!(ptr.len(), 128);
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+    }
+
+    #[test]
+    fn test_stats_exact_near_usize_max() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        allocator.lock().total_size = usize::MAX - 512;
+
+        let layout: Layout = Layout::from_size_align(8, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
         let alloc: MutexGuard<'_, Buddy> = allocator.lock();
-        assert_eq!(alloc.total_size, 512 as f64);
-        assert_eq!(alloc.peak_allocated_size, 384 as f64);
-        assert_eq!(alloc.current_allocated_size, 288 as f64);
+        assert_eq!(alloc.total_size, usize::MAX);
+        // The same sum in f64 can't tell the extension happened
+        assert_eq!((usize::MAX - 512) as f64 + 512.0, (usize::MAX - 512) as f64);
+        Mutex::unlock(alloc);
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+    }
+
+    #[test]
+    fn test_on_peak_callback() {
+        let (sender, receiver): (Sender<usize>, Receiver<usize>) = mpsc::channel();
+        let allocator: Locked<Buddy> = Locked::new(Buddy::with_on_peak(Box::new(move |peak| {
+            sender.send(peak).unwrap();
+        })));
+
+        let small: Layout = Layout::from_size_align(8, 8).unwrap();
+        let medium: Layout = Layout::from_size_align(16, 8).unwrap();
+        let first: NonNull<[u8]> = allocator.allocate(small).unwrap();
+        let second: NonNull<[u8]> = allocator.allocate(medium).unwrap();
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+
+        // Staying below the previous peak should not fire the callback
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+        let third: NonNull<[u8]> = allocator.allocate(medium).unwrap();
+
+        unsafe {
+            allocator.deallocate(first.as_non_null_ptr(), small);
+            allocator.deallocate(second.as_non_null_ptr(), medium);
+            allocator.deallocate(third.as_non_null_ptr(), medium);
+        }
+        drop(allocator);
+        let peaks: Vec<usize> = receiver.iter().collect();
+        assert_eq!(peaks, vec![8, 24, 56]);
     }
 }
@@ -0,0 +1,61 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::ptr::NonNull;
+
+const CACHE_LINE: usize = 64;
+
+// Wraps an allocator and pads every allocation out to whole cache lines, so separately allocated
+// objects never share a line and can't false share
+pub struct CacheLinePadded<A> {
+    inner: A,
+}
+
+impl<A> CacheLinePadded<A> {
+    pub fn new(inner: A) -> Self {
+        CacheLinePadded { inner }
+    }
+}
+
+// Padding only depends on the original layout, so deallocate can rebuild what was allocated
+fn padded(layout: Layout) -> Result<Layout, AllocError> {
+    Layout::from_size_align(
+        layout.size().max(1).next_multiple_of(CACHE_LINE),
+        layout.align().max(CACHE_LINE),
+    )
+    .map_err(|_| AllocError)
+}
+
+unsafe impl<A: Allocator> Allocator for CacheLinePadded<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.inner.allocate(padded(layout)?)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.inner.deallocate(ptr, padded(layout).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+
+    #[test]
+    fn test_padded_to_cache_line() {
+        let allocator: CacheLinePadded<System> = CacheLinePadded::new(System);
+        let layout: Layout = Layout::from_size_align(10, 1).unwrap();
+
+        let first: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        let second: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(first.addr().get() % CACHE_LINE, 0);
+        assert!(first.len() >= CACHE_LINE);
+        assert_ne!(
+            first.addr().get() / CACHE_LINE,
+            second.addr().get() / CACHE_LINE
+        );
+
+        unsafe {
+            allocator.deallocate(first.as_non_null_ptr(), layout);
+            allocator.deallocate(second.as_non_null_ptr(), layout);
+        }
+    }
+}
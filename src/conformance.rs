@@ -0,0 +1,379 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::ptr::NonNull;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::buddy::Buddy;
+use crate::invariants::{FreeListInvariants, InvalidFreeHook, ValidationLevel};
+use crate::mutex::{Lock, Locked};
+use crate::region_source::PAGE_SIZE;
+use crate::segregated_free_list::SegregatedFreeList;
+use crate::simple_segregated_storage::SimpleSegregatedStorage;
+use crate::snapshot::{AllocatorSnapshot, Snapshot};
+use crate::stats::MemStats;
+
+/*
+    Checks every free list allocator has to pass, written once and run against each of them.
+    Allocator specific behavior stays in the allocator's own tests.
+*/
+
+fn check_vec_grow<A: Allocator>(allocator: A) {
+    let mut vec: Vec<u64, &A> = Vec::with_capacity_in(2, &allocator);
+
+    // Pushing past the initial capacity forces several reallocations
+    for i in 0..40 {
+        vec.push(i * 3);
+    }
+    assert!(vec.capacity() >= 40);
+    assert!(vec
+        .iter()
+        .enumerate()
+        .all(|(i, value)| *value == i as u64 * 3));
+
+    vec.truncate(10);
+    vec.shrink_to_fit();
+    assert!(vec
+        .iter()
+        .enumerate()
+        .all(|(i, value)| *value == i as u64 * 3));
+}
+
+#[test]
+fn test_vec_grow() {
+    check_vec_grow(Locked::new(Buddy::new()));
+    check_vec_grow(Locked::new(SegregatedFreeList::new()));
+    check_vec_grow(Locked::new(SimpleSegregatedStorage::new()));
+}
+
+fn check_snapshot_restore<A: Snapshot>(allocator: Locked<A>)
+where
+    Locked<A>: Allocator,
+{
+    let layouts: [Layout; 2] = [64, 100].map(|size| Layout::from_size_align(size, 8).unwrap());
+    let kept: [NonNull<[u8]>; 2] = layouts.map(|layout| allocator.allocate(layout).unwrap());
+    let snap: AllocatorSnapshot = allocator.lock().snapshot();
+
+    for size in [200, 400] {
+        let _ = allocator
+            .allocate(Layout::from_size_align(size, 8).unwrap())
+            .unwrap();
+    }
+
+    allocator.lock().restore(&snap);
+    let restored: AllocatorSnapshot = allocator.lock().snapshot();
+    assert_eq!(restored.lists, snap.lists);
+    assert_eq!(restored.class_live, snap.class_live);
+    assert_eq!(restored.current_allocated_size, snap.current_allocated_size);
+    assert_eq!(restored.peak_allocated_size, snap.peak_allocated_size);
+
+    // Blocks handed out before the snapshot are still live after restoring it
+    for (block, layout) in kept.iter().zip(layouts) {
+        unsafe {
+            allocator.deallocate(block.as_non_null_ptr(), layout);
+        }
+    }
+}
+
+#[test]
+fn test_snapshot_restore() {
+    check_snapshot_restore(Locked::new(Buddy::new()));
+    check_snapshot_restore(Locked::new(SegregatedFreeList::new()));
+    check_snapshot_restore(Locked::new(SimpleSegregatedStorage::new()));
+}
+
+fn check_repair_overlap<A: FreeListInvariants>(allocator: Locked<A>)
+where
+    Locked<A>: Allocator,
+{
+    let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+
+    let mut alloc: MutexGuard<'_, A> = allocator.lock();
+    assert!(alloc.check_invariants().is_ok());
+    alloc.inject_overlap();
+    assert!(alloc.check_invariants().is_err());
+    assert_eq!(alloc.repair(), 1);
+    assert!(alloc.check_invariants().is_ok());
+    Mutex::unlock(alloc);
+    unsafe {
+        allocator.deallocate(ptr.as_non_null_ptr(), layout);
+    }
+}
+
+#[test]
+fn test_repair_overlap() {
+    check_repair_overlap(Locked::new(Buddy::new()));
+    check_repair_overlap(Locked::new(SegregatedFreeList::new()));
+    check_repair_overlap(Locked::new(SimpleSegregatedStorage::new()));
+}
+
+// The setters are passed in since each allocator has its own, with the same signature
+fn check_double_free_reported<A>(
+    mut alloc: A,
+    set_validation: fn(&mut A, ValidationLevel),
+    set_on_invalid_free: fn(&mut A, InvalidFreeHook),
+) where
+    Locked<A>: Allocator,
+{
+    let reasons: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    let reported: Arc<Mutex<Vec<String>>> = Arc::clone(&reasons);
+    set_validation(&mut alloc, ValidationLevel::Full);
+    set_on_invalid_free(
+        &mut alloc,
+        Box::new(move |reason: &str| reported.lock().unwrap().push(reason.to_string())),
+    );
+    let allocator: Locked<A> = Locked::new(alloc);
+    let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+    let held: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+    unsafe {
+        allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        allocator.deallocate(held.as_non_null_ptr(), layout);
+    }
+
+    let reasons: MutexGuard<'_, Vec<String>> = reasons.lock().unwrap();
+    assert_eq!(reasons.len(), 1);
+    assert!(reasons[0].starts_with("double free"));
+}
+
+#[test]
+fn test_double_free_reported() {
+    check_double_free_reported(
+        Buddy::new(),
+        Buddy::set_validation,
+        Buddy::set_on_invalid_free,
+    );
+    check_double_free_reported(
+        SegregatedFreeList::new(),
+        SegregatedFreeList::set_validation,
+        SegregatedFreeList::set_on_invalid_free,
+    );
+    check_double_free_reported(
+        SimpleSegregatedStorage::new(),
+        SimpleSegregatedStorage::set_validation,
+        SimpleSegregatedStorage::set_on_invalid_free,
+    );
+}
+
+fn check_on_peak<A>(with_on_peak: fn(Box<dyn Fn(usize) + Send + Sync>) -> A)
+where
+    Locked<A>: Allocator,
+{
+    let (sender, receiver): (Sender<usize>, Receiver<usize>) = mpsc::channel();
+    let allocator: Locked<A> = Locked::new(with_on_peak(Box::new(move |peak| {
+        sender.send(peak).unwrap();
+    })));
+
+    let layouts: [Layout; 3] = [8, 16, 32].map(|size| Layout::from_size_align(size, 8).unwrap());
+    let blocks: [NonNull<[u8]>; 3] = layouts.map(|layout| allocator.allocate(layout).unwrap());
+    // Staying below the previous peak should not fire the callback
+    unsafe {
+        allocator.deallocate(blocks[2].as_non_null_ptr(), layouts[2]);
+    }
+    let again: NonNull<[u8]> = allocator.allocate(layouts[1]).unwrap();
+    unsafe {
+        allocator.deallocate(again.as_non_null_ptr(), layouts[1]);
+        for (block, layout) in blocks[..2].iter().zip(layouts) {
+            allocator.deallocate(block.as_non_null_ptr(), layout);
+        }
+    }
+
+    drop(allocator);
+    let peaks: Vec<usize> = receiver.iter().collect();
+    assert_eq!(peaks, vec![8, 24, 56]);
+}
+
+#[test]
+fn test_on_peak() {
+    check_on_peak(Buddy::with_on_peak);
+    check_on_peak(SegregatedFreeList::with_on_peak);
+    check_on_peak(SimpleSegregatedStorage::with_on_peak);
+}
+
+// Accessors are passed in the same way, none of them are on a shared trait
+fn check_layout_reports<A: MemStats>(
+    allocator: Locked<A>,
+    region_occupancy: fn(&A) -> Vec<f64>,
+    free_distribution: fn(&A) -> Vec<(usize, usize, usize)>,
+    overhead_bytes: fn(&A) -> usize,
+) where
+    Locked<A>: Allocator,
+{
+    assert_eq!(overhead_bytes(&allocator.lock()), 0);
+    let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+    let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+    {
+        let alloc: MutexGuard<'_, A> = allocator.lock();
+        assert!(region_occupancy(&alloc)[0] > 0.0);
+        assert!(overhead_bytes(&alloc) > 0);
+    }
+    unsafe {
+        allocator.deallocate(ptr.as_non_null_ptr(), layout);
+    }
+
+    // With nothing live, every byte of every region is in some free list
+    let alloc: MutexGuard<'_, A> = allocator.lock();
+    assert!(region_occupancy(&alloc)
+        .iter()
+        .all(|occupancy| *occupancy == 0.0));
+    let free_bytes: usize = free_distribution(&alloc)
+        .iter()
+        .map(|(_, _, bytes)| bytes)
+        .sum();
+    assert_eq!(free_bytes, alloc.calculate_allocation_ratio().1);
+}
+
+#[test]
+fn test_layout_reports() {
+    check_layout_reports(
+        Locked::new(Buddy::new()),
+        Buddy::region_occupancy,
+        Buddy::free_distribution,
+        Buddy::overhead_bytes,
+    );
+    check_layout_reports(
+        Locked::new(SegregatedFreeList::new()),
+        SegregatedFreeList::region_occupancy,
+        SegregatedFreeList::free_distribution,
+        SegregatedFreeList::overhead_bytes,
+    );
+    check_layout_reports(
+        Locked::new(SimpleSegregatedStorage::new()),
+        SimpleSegregatedStorage::region_occupancy,
+        SimpleSegregatedStorage::free_distribution,
+        SimpleSegregatedStorage::overhead_bytes,
+    );
+}
+
+type DeallocateBatch<A> = unsafe fn(&Locked<A>, &[(NonNull<u8>, Layout)]);
+
+// Freeing in one batch has to leave the allocator as freeing one block at a time does
+fn check_deallocate_batch<A: MemStats>(
+    single: Locked<A>,
+    batched: Locked<A>,
+    deallocate_batch: DeallocateBatch<A>,
+) where
+    Locked<A>: Allocator,
+{
+    let layouts: Vec<Layout> = [24, 64, 24, 200, 8]
+        .iter()
+        .map(|size| Layout::from_size_align(*size, 8).unwrap())
+        .collect();
+    let single_items: Vec<(NonNull<u8>, Layout)> = layouts
+        .iter()
+        .map(|layout| (single.allocate(*layout).unwrap().as_non_null_ptr(), *layout))
+        .collect();
+    let batched_items: Vec<(NonNull<u8>, Layout)> = layouts
+        .iter()
+        .map(|layout| {
+            (
+                batched.allocate(*layout).unwrap().as_non_null_ptr(),
+                *layout,
+            )
+        })
+        .collect();
+
+    unsafe {
+        for (ptr, layout) in &single_items[1..] {
+            single.deallocate(*ptr, *layout);
+        }
+        deallocate_batch(&batched, &batched_items[1..]);
+    }
+    {
+        let single: MutexGuard<'_, A> = single.lock();
+        let batched: MutexGuard<'_, A> = batched.lock();
+        assert_eq!(
+            batched.calculate_allocation_ratio(),
+            single.calculate_allocation_ratio()
+        );
+        assert_eq!(
+            batched.size_class_histogram(),
+            single.size_class_histogram()
+        );
+        assert_eq!(batched.merge_count(), single.merge_count());
+    }
+    unsafe {
+        single.deallocate(single_items[0].0, single_items[0].1);
+        batched.deallocate(batched_items[0].0, batched_items[0].1);
+    }
+}
+
+#[test]
+fn test_deallocate_batch() {
+    check_deallocate_batch(
+        Locked::new(Buddy::new()),
+        Locked::new(Buddy::new()),
+        Locked::<Buddy>::deallocate_batch,
+    );
+    check_deallocate_batch(
+        Locked::new(SegregatedFreeList::new()),
+        Locked::new(SegregatedFreeList::new()),
+        Locked::<SegregatedFreeList>::deallocate_batch,
+    );
+    check_deallocate_batch(
+        Locked::new(SimpleSegregatedStorage::new()),
+        Locked::new(SimpleSegregatedStorage::new()),
+        Locked::<SimpleSegregatedStorage>::deallocate_batch,
+    );
+}
+
+fn check_grow_pool<A: MemStats>(
+    allocator: Locked<A>,
+    grow_pool: fn(&mut A, usize) -> Result<(), AllocError>,
+) where
+    Locked<A>: Allocator,
+{
+    let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+    let live: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+    let total_before: usize = allocator.lock().calculate_allocation_ratio().1;
+
+    grow_pool(&mut allocator.lock(), 2).unwrap();
+    assert_eq!(
+        allocator.lock().calculate_allocation_ratio().1,
+        total_before + 1024
+    );
+    unsafe {
+        allocator.deallocate(live.as_non_null_ptr(), layout);
+    }
+}
+
+#[test]
+fn test_grow_pool() {
+    check_grow_pool(Locked::new(Buddy::new()), Buddy::grow_pool);
+    check_grow_pool(
+        Locked::new(SegregatedFreeList::new()),
+        SegregatedFreeList::grow_pool,
+    );
+    check_grow_pool(
+        Locked::new(SimpleSegregatedStorage::new()),
+        SimpleSegregatedStorage::grow_pool,
+    );
+}
+
+fn check_page_aligned_regions<A>(with_page_aligned_regions: fn(bool) -> A)
+where
+    Locked<A>: Allocator,
+{
+    let allocator: Locked<A> = Locked::new(with_page_aligned_regions(true));
+    // A 512 byte block takes a whole region, so each one starts where its region does
+    let layout: Layout = Layout::from_size_align(512, 16).unwrap();
+    let blocks: Vec<NonNull<[u8]>> = (0..3)
+        .map(|_| allocator.allocate(layout).unwrap())
+        .collect();
+    assert!(blocks
+        .iter()
+        .all(|block| block.addr().get().is_multiple_of(PAGE_SIZE)));
+    for block in blocks {
+        unsafe {
+            allocator.deallocate(block.as_non_null_ptr(), layout);
+        }
+    }
+}
+
+#[test]
+fn test_page_aligned_regions() {
+    check_page_aligned_regions(Buddy::with_page_aligned_regions);
+    check_page_aligned_regions(SegregatedFreeList::with_page_aligned_regions);
+    check_page_aligned_regions(SimpleSegregatedStorage::with_page_aligned_regions);
+}
@@ -0,0 +1,27 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Global allocator that counts calls into System, used to measure hidden heap traffic such as
+// the node allocations behind LinkedList free lists
+pub struct CountingAllocator;
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+impl CountingAllocator {
+    // Number of alloc and dealloc calls made through the global allocator so far
+    pub fn calls() -> usize {
+        CALLS.load(Ordering::Relaxed)
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
@@ -0,0 +1,254 @@
+use std::ptr::NonNull;
+
+use crate::snapshot::AllocatorSnapshot;
+
+/*
+    Binary dump of an allocator's state for post-mortem analysis.
+    - Every field is written as a little endian u64 after a 4 byte magic.
+    - Layout: region count, region bases, class count, then per class a block count followed by
+      (address, length) pairs, and finally total, peak and current sizes.
+*/
+
+const MAGIC: &[u8; 4] = b"DMMD";
+
+// Read-only view of a dump, addresses are plain numbers and never dereferenced
+#[derive(Debug, PartialEq)]
+pub struct ParsedDump {
+    pub regions: Vec<usize>,
+    pub lists: Vec<Vec<(usize, usize)>>,
+    pub total_size: usize,
+    pub peak_allocated_size: usize,
+    pub current_allocated_size: usize,
+}
+
+// Why a byte string could not be read back as a dump
+#[derive(Debug, PartialEq)]
+pub enum DumpError {
+    // Doesn't start with the dump magic
+    BadMagic,
+    // Ends before every field announced so far was read
+    Truncated,
+    // A count promises more entries than the remaining bytes hold, or a value doesn't fit a usize
+    Corrupt,
+}
+
+// Reads the little endian words after the magic, checking every length against what is left
+struct WordReader<'a> {
+    words: std::slice::ChunksExact<'a, u8>,
+}
+
+impl WordReader<'_> {
+    fn next(&mut self) -> Result<usize, DumpError> {
+        let word: &[u8] = self.words.next().ok_or(DumpError::Truncated)?;
+        usize::try_from(u64::from_le_bytes(word.try_into().unwrap()))
+            .map_err(|_| DumpError::Corrupt)
+    }
+
+    // A count of entries that each take words_per_entry words, refused before anything is sized by
+    // it when the data can't hold that many
+    fn count(&mut self, words_per_entry: usize) -> Result<usize, DumpError> {
+        let count: usize = self.next()?;
+        if count > self.words.len() / words_per_entry {
+            return Err(DumpError::Corrupt);
+        }
+        Ok(count)
+    }
+}
+
+pub fn encode_dump(
+    regions: &[NonNull<u8>],
+    snap: &AllocatorSnapshot,
+    total_size: usize,
+) -> Vec<u8> {
+    let mut data: Vec<u8> = MAGIC.to_vec();
+    let mut push = |value: usize| data.extend_from_slice(&(value as u64).to_le_bytes());
+
+    push(regions.len());
+    for region in regions {
+        push(region.addr().get());
+    }
+    push(snap.lists.len());
+    for list in &snap.lists {
+        push(list.len());
+        for block in list {
+            push(block.addr().get());
+            push(block.len());
+        }
+    }
+    push(total_size);
+    push(snap.peak_allocated_size);
+    push(snap.current_allocated_size);
+    data
+}
+
+// The data may come from anywhere, so nothing is indexed or allocated before its length is checked
+pub fn parse_dump(data: &[u8]) -> Result<ParsedDump, DumpError> {
+    let body: &[u8] = data.strip_prefix(MAGIC).ok_or(DumpError::BadMagic)?;
+    let mut reader: WordReader<'_> = WordReader {
+        words: body.chunks_exact(8),
+    };
+
+    let region_count: usize = reader.count(1)?;
+    let regions: Vec<usize> = (0..region_count)
+        .map(|_| reader.next())
+        .collect::<Result<_, _>>()?;
+    // Every class takes at least its block count word
+    let class_count: usize = reader.count(1)?;
+    let mut lists: Vec<Vec<(usize, usize)>> = Vec::with_capacity(class_count);
+    for _ in 0..class_count {
+        let block_count: usize = reader.count(2)?;
+        let blocks: Vec<(usize, usize)> = (0..block_count)
+            .map(|_| Ok((reader.next()?, reader.next()?)))
+            .collect::<Result<_, _>>()?;
+        lists.push(blocks);
+    }
+
+    Ok(ParsedDump {
+        regions,
+        lists,
+        total_size: reader.next()?,
+        peak_allocated_size: reader.next()?,
+        current_allocated_size: reader.next()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buddy::Buddy;
+    use crate::mutex::{Lock, Locked};
+    use crate::segregated_free_list::SegregatedFreeList;
+    use crate::simple_segregated_storage::SimpleSegregatedStorage;
+    use crate::snapshot::Snapshot;
+    use crate::stats::MemStats;
+    use std::alloc::{Allocator, Layout};
+    use std::sync::MutexGuard;
+
+    // Checks a dump against a snapshot taken under the same lock, returning it for checks that
+    // depend on the allocator
+    fn dump_matches_snapshot<A: Snapshot + MemStats>(
+        allocator: &Locked<A>,
+        dump_state: fn(&A) -> Vec<u8>,
+    ) -> ParsedDump {
+        let alloc: MutexGuard<'_, A> = allocator.lock();
+        let parsed: ParsedDump = parse_dump(&dump_state(&alloc)).unwrap();
+        let snap: AllocatorSnapshot = alloc.snapshot();
+        let (peak, total, _): (usize, usize, f64) = alloc.calculate_allocation_ratio();
+
+        let expected: Vec<Vec<(usize, usize)>> = snap
+            .lists
+            .iter()
+            .map(|list| list.iter().map(|b| (b.addr().get(), b.len())).collect())
+            .collect();
+        assert_eq!(parsed.lists, expected);
+        assert_eq!(parsed.total_size, total);
+        assert_eq!(parsed.peak_allocated_size, peak);
+        assert_eq!(
+            parsed.current_allocated_size,
+            alloc.current_allocated_size()
+        );
+        parsed
+    }
+
+    #[test]
+    fn test_dump_round_trip() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let small: Layout = Layout::from_size_align(16, 8).unwrap();
+        let large: Layout = Layout::from_size_align(128, 8).unwrap();
+        let first: NonNull<[u8]> = allocator.allocate(small).unwrap();
+        allocator.allocate(small).unwrap();
+        allocator.allocate(large).unwrap();
+        unsafe {
+            allocator.deallocate(first.as_non_null_ptr(), small);
+        }
+
+        let parsed: ParsedDump =
+            dump_matches_snapshot(&allocator, SimpleSegregatedStorage::dump_state);
+        assert_eq!(parsed.regions.len(), 2);
+        assert!(parsed.lists[4].contains(&(first.addr().get(), 16)));
+        assert_eq!(parsed.current_allocated_size, 16 + 128);
+    }
+
+    #[test]
+    fn test_dump_coalescing_allocators() {
+        let layouts: [Layout; 2] = [24, 100].map(|size| Layout::from_size_align(size, 8).unwrap());
+
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let blocks: [NonNull<[u8]>; 2] = layouts.map(|layout| allocator.allocate(layout).unwrap());
+        assert_eq!(
+            dump_matches_snapshot(&allocator, Buddy::dump_state)
+                .regions
+                .len(),
+            1
+        );
+        for (block, layout) in blocks.iter().zip(layouts) {
+            unsafe {
+                allocator.deallocate(block.as_non_null_ptr(), layout);
+            }
+        }
+
+        let allocator: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
+        let blocks: [NonNull<[u8]>; 2] = layouts.map(|layout| allocator.allocate(layout).unwrap());
+        assert_eq!(
+            dump_matches_snapshot(&allocator, SegregatedFreeList::dump_state)
+                .regions
+                .len(),
+            1
+        );
+        for (block, layout) in blocks.iter().zip(layouts) {
+            unsafe {
+                allocator.deallocate(block.as_non_null_ptr(), layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_truncated_dump() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        allocator
+            .allocate(Layout::from_size_align(64, 8).unwrap())
+            .unwrap();
+        let data: Vec<u8> = allocator.lock().dump_state();
+        assert!(parse_dump(&data).is_ok());
+
+        // Every cut short of the full dump is refused, whichever field it lands in
+        for len in 0..data.len() {
+            let expected: DumpError = if len < MAGIC.len() {
+                DumpError::BadMagic
+            } else {
+                DumpError::Truncated
+            };
+            let result: Result<ParsedDump, DumpError> = parse_dump(&data[..len]);
+            assert!(
+                result == Err(expected) || result == Err(DumpError::Corrupt),
+                "cut at {} parsed as {:?}",
+                len,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_corrupt_dump() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        allocator
+            .allocate(Layout::from_size_align(64, 8).unwrap())
+            .unwrap();
+        let data: Vec<u8> = allocator.lock().dump_state();
+
+        assert_eq!(parse_dump(b"NOPE"), Err(DumpError::BadMagic));
+
+        // A huge region count is refused up front instead of sizing a Vec by it
+        let mut huge_regions: Vec<u8> = data.clone();
+        huge_regions[4..12].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(parse_dump(&huge_regions), Err(DumpError::Corrupt));
+
+        // The class count follows the single region base
+        let mut huge_classes: Vec<u8> = data.clone();
+        huge_classes[20..28].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert_eq!(parse_dump(&huge_classes), Err(DumpError::Corrupt));
+    }
+}
@@ -0,0 +1,114 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::ptr::NonNull;
+use std::sync::Mutex;
+
+// Wraps an allocator and holds on to freed blocks until the epoch is advanced, so a block freed
+// in the current epoch is never handed out again before the next one starts
+pub struct EpochAllocator<A: Allocator> {
+    inner: A,
+    pending: Mutex<Vec<(NonNull<u8>, Layout)>>,
+}
+
+// The pending pointers are blocks of the inner allocator that callers already gave up, only ever
+// handed back to it, so the wrapper can go wherever the inner allocator can
+unsafe impl<A: Allocator + Send> Send for EpochAllocator<A> {}
+// Every &self method reaches the pending list through its lock, the rest is the inner allocator's
+unsafe impl<A: Allocator + Sync> Sync for EpochAllocator<A> {}
+
+impl<A: Allocator> EpochAllocator<A> {
+    pub fn new(inner: A) -> Self {
+        EpochAllocator {
+            inner,
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Returns every block freed during the current epoch to the inner allocator
+    pub fn advance_epoch(&self) {
+        let pending: Vec<(NonNull<u8>, Layout)> =
+            std::mem::take(&mut *self.pending.lock().unwrap());
+        for (ptr, layout) in pending {
+            unsafe {
+                self.inner.deallocate(ptr, layout);
+            }
+        }
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for EpochAllocator<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.inner.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.pending.lock().unwrap().push((ptr, layout));
+    }
+}
+
+impl<A: Allocator> Drop for EpochAllocator<A> {
+    fn drop(&mut self) {
+        self.advance_epoch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mutex::Locked;
+    use crate::simple_segregated_storage::SimpleSegregatedStorage;
+    use std::thread;
+
+    #[test]
+    fn test_free_deferred_until_epoch_advance() {
+        let allocator: EpochAllocator<Locked<SimpleSegregatedStorage>> =
+            EpochAllocator::new(Locked::new(SimpleSegregatedStorage::new()));
+        // A whole-region block, so the only free block after the epoch is the one freed here
+        let layout: Layout = Layout::from_size_align(512, 16).unwrap();
+
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+
+        // Same epoch, the freed block must not be reused
+        let other: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_ne!(other.as_mut_ptr(), ptr.as_mut_ptr());
+
+        allocator.advance_epoch();
+        let reused: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(reused.as_mut_ptr(), ptr.as_mut_ptr());
+    }
+
+    #[test]
+    fn test_free_and_advance_on_different_threads() {
+        let allocator: EpochAllocator<Locked<SimpleSegregatedStorage>> =
+            EpochAllocator::new(Locked::new(SimpleSegregatedStorage::new()));
+        let layout: Layout = Layout::from_size_align(512, 16).unwrap();
+
+        // Addresses cross the threads as integers, NonNull isn't Send
+        let freed: usize = thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+                    unsafe {
+                        allocator.deallocate(ptr.as_non_null_ptr(), layout);
+                    }
+                    ptr.addr().get()
+                })
+                .join()
+                .unwrap()
+        });
+        let other: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_ne!(other.addr().get(), freed);
+
+        thread::scope(|scope| {
+            scope.spawn(|| allocator.advance_epoch());
+        });
+        let reused: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(reused.addr().get(), freed);
+        unsafe {
+            allocator.deallocate(reused.as_non_null_ptr(), layout);
+            allocator.deallocate(other.as_non_null_ptr(), layout);
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use std::alloc::{AllocError, Allocator, Layout, System};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[cfg(debug_assertions)]
+use crate::invariants::find_cycle;
+use crate::mutex::Locked;
+use crate::region_source::RegionSource;
+use crate::simple_segregated_storage::{SimpleSegregatedStorage, CLASS_TABLE};
+
+// Blocks need room for the intrusive next pointer, so classes below 8 bytes always take the lock
+const MIN_FAST_CLASS: usize = 3;
+// Regions are at least 16 aligned, so a class block is aligned to its size up to 16
+const MAX_FAST_ALIGN: usize = 16;
+
+// Treiber stack threaded through the free blocks themselves. Any thread may push, but only one
+// pops at a time. A popper reads the next word of the head block before its CAS, and with a
+// single popper nobody else can have taken that block and started writing to it. It also rules
+// out ABA, since the head can't be popped and pushed back behind the popper's back.
+struct FreeStack {
+    head: AtomicUsize,
+    popping: AtomicBool,
+}
+
+impl FreeStack {
+    const fn new() -> Self {
+        FreeStack {
+            head: AtomicUsize::new(0),
+            popping: AtomicBool::new(false),
+        }
+    }
+
+    // block must be at least 8 bytes, 8 aligned, and not in use
+    unsafe fn push(&self, block: NonNull<u8>) {
+        let next: &AtomicUsize = &*(block.as_ptr() as *const AtomicUsize);
+        let mut head: usize = self.head.load(Ordering::Acquire);
+        // Pushing the current head again would point it at itself
+        debug_assert_ne!(
+            head,
+            block.addr().get(),
+            "double free of {:#x}",
+            block.addr().get()
+        );
+        loop {
+            next.store(head, Ordering::Relaxed);
+            match self.head.compare_exchange_weak(
+                head,
+                block.addr().get(),
+                Ordering::Release,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    // Holds off pops while walking, since a popped block could be written to mid-walk. Pushes only
+    // add blocks in front of the head read here, so they don't disturb the walk.
+    #[cfg(debug_assertions)]
+    fn find_cycle(&self) -> Option<usize> {
+        while self
+            .popping
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+        let head: usize = self.head.load(Ordering::Acquire);
+        let cycle: Option<usize> = find_cycle(head, |block| unsafe {
+            (*(block as *const AtomicUsize)).load(Ordering::Relaxed)
+        });
+        self.popping.store(false, Ordering::Release);
+        cycle
+    }
+
+    // None when the stack is empty or another thread is popping, either way the caller takes
+    // the slow path instead of waiting
+    fn pop(&self) -> Option<NonNull<u8>> {
+        if self
+            .popping
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+        let mut head: usize = self.head.load(Ordering::Acquire);
+        let popped: Option<NonNull<u8>> = loop {
+            let Some(block) = NonNull::new(head as *mut u8) else {
+                break None;
+            };
+            // Pushes only add blocks in front of this one, so it stays free until the CAS below
+            let next: usize =
+                unsafe { (*(block.as_ptr() as *const AtomicUsize)).load(Ordering::Relaxed) };
+            match self
+                .head
+                .compare_exchange_weak(head, next, Ordering::Acquire, Ordering::Acquire)
+            {
+                Ok(_) => break Some(block),
+                Err(current) => head = current,
+            }
+        };
+        self.popping.store(false, Ordering::Release);
+        popped
+    }
+}
+
+// Simple segregated storage with a lock-free cache of freed blocks per class in front of it.
+// Blocks only go through the lock when their class cache is empty, so the inner allocator counts
+// cached blocks as still allocated.
+pub struct FastPathStorage<S: RegionSource = System> {
+    stacks: [FreeStack; 10],
+    slow: Locked<SimpleSegregatedStorage<S>>,
+}
+
+impl FastPathStorage {
+    pub fn new() -> Self {
+        FastPathStorage::with_inner(SimpleSegregatedStorage::new())
+    }
+}
+
+impl<S: RegionSource> FastPathStorage<S> {
+    pub fn with_inner(inner: SimpleSegregatedStorage<S>) -> Self {
+        FastPathStorage {
+            stacks: [const { FreeStack::new() }; 10],
+            slow: Locked::new(inner),
+        }
+    }
+
+    pub fn slow(&self) -> &Locked<SimpleSegregatedStorage<S>> {
+        &self.slow
+    }
+
+    // Over-aligned layouts go through the lock, since a cached block is only as aligned as its
+    // class guarantees
+    fn is_fast(index: usize, layout: Layout) -> bool {
+        index >= MIN_FAST_CLASS && layout.align() <= usize::min(1 << index, MAX_FAST_ALIGN)
+    }
+
+    // A block freed twice can link a stack back onto itself, after which pops hand the same
+    // blocks out forever. Checked after every push in debug builds.
+    #[cfg(debug_assertions)]
+    fn assert_acyclic(&self, index: usize) {
+        if let Some(block) = self.stacks[index].find_cycle() {
+            panic!(
+                "free stack for the {} byte class loops back through {:#x}",
+                1 << index,
+                block
+            );
+        }
+    }
+}
+
+unsafe impl<S: RegionSource> Allocator for FastPathStorage<S> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() > 512 {
+            return Err(AllocError);
+        }
+        let index: usize = CLASS_TABLE[layout.size()] as usize;
+
+        if FastPathStorage::<S>::is_fast(index, layout) {
+            if let Some(block) = self.stacks[index].pop() {
+                return Ok(NonNull::slice_from_raw_parts(block, 1 << index));
+            }
+        }
+        self.slow.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() > 512 {
+            return;
+        }
+        let index: usize = CLASS_TABLE[layout.size()] as usize;
+
+        if FastPathStorage::<S>::is_fast(index, layout) {
+            self.stacks[index].push(ptr);
+            #[cfg(debug_assertions)]
+            self.assert_acyclic(index);
+        } else {
+            self.slow.deallocate(ptr, layout);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mutex::Lock;
+    use crate::stats::MemStats;
+    use std::thread;
+
+    #[test]
+    fn test_fast_path_reuses_freed_block() {
+        let allocator: FastPathStorage = FastPathStorage::new();
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+        let reused: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(reused, ptr);
+
+        // Only the first allocation went through the lock
+        let (peak, _, _): (usize, usize, f64) =
+            allocator.slow().lock().calculate_allocation_ratio();
+        assert_eq!(peak, 64);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "free stack for the 64 byte class loops back")]
+    fn test_cycle_detected() {
+        let allocator: FastPathStorage = FastPathStorage::new();
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let a: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        let b: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+
+        // Freeing a again while b is on top links a -> b -> a, which the free itself catches
+        unsafe {
+            allocator.deallocate(a.as_non_null_ptr(), layout);
+            allocator.deallocate(b.as_non_null_ptr(), layout);
+            allocator.deallocate(a.as_non_null_ptr(), layout);
+        }
+    }
+
+    #[test]
+    fn test_over_aligned_takes_slow_path() {
+        // Page aligned regions let the slow path serve blocks aligned to their full size
+        let allocator: FastPathStorage =
+            FastPathStorage::with_inner(SimpleSegregatedStorage::with_page_aligned_regions(true));
+        let aligned: Layout = Layout::from_size_align(64, 8).unwrap();
+        let over_aligned: Layout = Layout::from_size_align(64, 64).unwrap();
+
+        let ptr: NonNull<[u8]> = allocator.allocate(aligned).unwrap();
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), aligned);
+        }
+        // The cached block stays in the stack instead of being handed out for the stricter layout
+        let slow: NonNull<[u8]> = allocator.allocate(over_aligned).unwrap();
+        assert_ne!(slow.addr(), ptr.addr());
+        assert!(slow.addr().get().is_multiple_of(64));
+        assert_eq!(allocator.allocate(aligned).unwrap().addr(), ptr.addr());
+        assert_eq!(allocator.slow().lock().alloc_success(), 2);
+    }
+
+    #[test]
+    fn test_no_block_shared_under_contention() {
+        let allocator: FastPathStorage = FastPathStorage::new();
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+
+        thread::scope(|scope| {
+            for id in 0..8_u8 {
+                let allocator: &FastPathStorage = &allocator;
+                scope.spawn(move || {
+                    for _ in 0..2000 {
+                        let ptrs: Vec<NonNull<[u8]>> = (0..4)
+                            .map(|_| allocator.allocate(layout).unwrap())
+                            .collect();
+                        for ptr in &ptrs {
+                            unsafe { ptr.as_mut_ptr().write_bytes(id, ptr.len()) };
+                        }
+                        thread::yield_now();
+                        // Another thread holding the same block would have overwritten the pattern
+                        for ptr in ptrs {
+                            unsafe {
+                                assert!(ptr.as_ref().iter().all(|byte| *byte == id));
+                                allocator.deallocate(ptr.as_non_null_ptr(), layout);
+                            }
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
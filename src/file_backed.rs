@@ -0,0 +1,125 @@
+use std::alloc::{AllocError, Layout};
+use std::fs::File;
+use std::ptr::NonNull;
+use std::sync::Mutex;
+
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::region_source::RegionSource;
+
+/*
+    Region source that maps chunks of a file instead of taking anonymous memory from System.
+    - Each region is mapped from the next free offset of the file, with offsets aligned to 512.
+    - The file is grown with set_len as regions are acquired, so it must be opened for reading and writing.
+    - Releasing a region unmaps it, but the file is never shrunk and offsets are never reused.
+*/
+
+pub struct FileBackedStorage {
+    file: File,
+    mappings: Mutex<FileMappings>,
+}
+
+struct FileMappings {
+    next_offset: u64,
+    maps: Vec<MmapMut>,
+}
+
+impl FileBackedStorage {
+    pub fn new(file: File) -> Self {
+        FileBackedStorage {
+            file,
+            mappings: Mutex::new(FileMappings {
+                next_offset: 0,
+                maps: Vec::new(),
+            }),
+        }
+    }
+}
+
+impl RegionSource for FileBackedStorage {
+    fn acquire(&self, layout: Layout, _node: u8) -> Result<NonNull<[u8]>, AllocError> {
+        let mut mappings = self.mappings.lock().unwrap();
+        let chunk_align: u64 = usize::max(layout.align(), 512) as u64;
+        let offset: u64 = mappings.next_offset.next_multiple_of(chunk_align);
+        let len: usize = layout.size();
+
+        self.file
+            .set_len(offset + len as u64)
+            .map_err(|_| AllocError)?;
+        let mut map: MmapMut = unsafe {
+            MmapOptions::new()
+                .offset(offset)
+                .len(len)
+                .map_mut(&self.file)
+                .map_err(|_| AllocError)?
+        };
+
+        // Mappings start on a page boundary plus the offset within the page
+        let ptr: *mut u8 = map.as_mut_ptr();
+        if ptr.align_offset(layout.align()) != 0 {
+            return Err(AllocError);
+        }
+
+        mappings.next_offset = offset + len as u64;
+        mappings.maps.push(map);
+        Ok(NonNull::slice_from_raw_parts(
+            NonNull::new(ptr).ok_or(AllocError)?,
+            len,
+        ))
+    }
+
+    unsafe fn release(&self, ptr: NonNull<u8>, _layout: Layout) {
+        let mut mappings = self.mappings.lock().unwrap();
+        if let Some(position) = mappings
+            .maps
+            .iter()
+            .position(|map| std::ptr::eq(map.as_ptr(), ptr.as_ptr()))
+        {
+            // Dropping the mapping unmaps it
+            mappings.maps.swap_remove(position);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::mutex::Locked;
+    use crate::segregated_free_list::SegregatedFreeList;
+    use std::alloc::Allocator;
+    use std::fs::{self, OpenOptions};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_file_backed_segregated_free_list() {
+        let path: PathBuf =
+            std::env::temp_dir().join(format!("allocators-{}.mmap", std::process::id()));
+        let file: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+
+        let allocator: Locked<SegregatedFreeList<FileBackedStorage>> = Locked::new(
+            SegregatedFreeList::with_source(FileBackedStorage::new(file)),
+        );
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+
+        unsafe {
+            for (i, byte) in (*ptr.as_ptr()).iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+            assert!((*ptr.as_ptr())
+                .iter()
+                .enumerate()
+                .all(|(i, byte)| *byte == i as u8));
+        }
+        assert!(fs::metadata(&path).unwrap().len() >= 512);
+
+        drop(allocator);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+use std::alloc::{Allocator, Layout};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr::NonNull;
+
+use crate::buddy::Buddy;
+use crate::invariants::FreeListInvariants;
+use crate::mutex::{Lock, Locked};
+use crate::segregated_free_list::SegregatedFreeList;
+use crate::stats::MemStats;
+
+/*
+    Randomized allocate/free interleavings for the coalescing paths.
+    - Free lists are checked against their invariants after every operation.
+    - A failing sequence is shrunk by dropping operations while it still fails, then reported.
+    - Requests go up to 256 bytes, so a run keeps several regions live and frees merge across them.
+*/
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Allocate(usize),
+    // Frees the live block at this index, wrapped to the number of live blocks
+    Free(usize),
+}
+
+// xorshift64, enough to vary sizes and free order without pulling in a dependency
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn generate(seed: u64, len: usize) -> Vec<Op> {
+    let mut rng: Rng = Rng(seed);
+    (0..len)
+        .map(|_| {
+            if rng.next().is_multiple_of(3) {
+                Op::Free(rng.next() as usize)
+            } else {
+                Op::Allocate(1 + rng.next() as usize % 256)
+            }
+        })
+        .collect()
+}
+
+fn run<A: Allocator>(
+    allocator: &A,
+    check: &dyn Fn(&A) -> Result<(), String>,
+    ops: &[Op],
+) -> Result<(), String> {
+    let mut live: Vec<(NonNull<[u8]>, Layout)> = Vec::new();
+    for (step, op) in ops.iter().enumerate() {
+        match *op {
+            Op::Allocate(size) => {
+                let layout: Layout = Layout::from_size_align(size, 1).unwrap();
+                if let Ok(ptr) = allocator.allocate(layout) {
+                    live.push((ptr, layout));
+                }
+            }
+            Op::Free(index) => {
+                if live.is_empty() {
+                    continue;
+                }
+                let (ptr, layout) = live.swap_remove(index % live.len());
+                unsafe {
+                    allocator.deallocate(ptr.as_non_null_ptr(), layout);
+                }
+            }
+        }
+        check(allocator).map_err(|err| format!("after step {}: {}", step, err))?;
+    }
+
+    for (ptr, layout) in live {
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+    }
+    check(allocator).map_err(|err| format!("after freeing everything: {}", err))
+}
+
+// Drops operations one at a time, keeping each removal that still fails
+fn shrink(mut ops: Vec<Op>, fails: impl Fn(&[Op]) -> bool) -> Vec<Op> {
+    let mut index: usize = 0;
+    while index < ops.len() {
+        let mut candidate: Vec<Op> = ops.clone();
+        candidate.remove(index);
+        if fails(&candidate) {
+            ops = candidate;
+        } else {
+            index += 1;
+        }
+    }
+    ops
+}
+
+fn fuzz<A: MemStats>(
+    new: impl Fn() -> Locked<A>,
+    check: impl Fn(&Locked<A>) -> Result<(), String>,
+    seeds: &[u64],
+) where
+    Locked<A>: Allocator,
+{
+    let attempt = |ops: &[Op]| -> (Result<(), String>, u64) {
+        let allocator: Locked<A> = new();
+        match catch_unwind(AssertUnwindSafe(|| run(&allocator, &check, ops))) {
+            Ok(result) => (result, allocator.lock().extension_count()),
+            // A step that panicked may have left the allocator mid-operation, so don't inspect it
+            Err(_) => (Err(String::from("by panicking")), 0),
+        }
+    };
+
+    for seed in seeds {
+        let ops: Vec<Op> = generate(*seed, 200);
+        let (result, regions) = attempt(&ops);
+        if let Err(err) = result {
+            let minimal: Vec<Op> = shrink(ops, |ops| attempt(ops).0.is_err());
+            panic!(
+                "seed {} failed {}, minimal sequence: {:?}",
+                seed, err, minimal
+            );
+        }
+        // Coalescing across regions is only exercised once a second region exists
+        assert!(regions >= 2, "seed {} stayed within one region", seed);
+    }
+}
+
+const SEEDS: [u64; 8] = [1, 2, 3, 5, 8, 13, 21, 34];
+
+#[test]
+fn fuzz_buddy_coalescing() {
+    fuzz(
+        || Locked::new(Buddy::new()),
+        |allocator| allocator.lock().check_invariants(),
+        &SEEDS,
+    );
+}
+
+#[test]
+fn fuzz_segregated_free_list_coalescing() {
+    fuzz(
+        || Locked::new(SegregatedFreeList::new()),
+        |allocator| allocator.lock().check_invariants(),
+        &SEEDS,
+    );
+}
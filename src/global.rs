@@ -0,0 +1,164 @@
+use std::alloc::{Allocator, GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::ptr::{self, NonNull};
+
+use crate::region_source::Owns;
+
+/*
+    Exposes one of the crate's allocators as a GlobalAlloc, with System behind it.
+    - Requests up to 512 bytes go to the inner allocator, larger ones (or ones it fails) to System.
+    - dealloc routes by address, a pointer inside one of the inner allocator's regions goes back to it.
+    - The free lists keep their nodes in LinkedLists, which allocate through the global allocator.
+      Those nested calls would take the inner lock twice, so they are sent straight to System.
+*/
+
+thread_local! {
+    // Set while this thread is inside the inner allocator
+    static IN_INNER: Cell<bool> = const { Cell::new(false) };
+}
+
+// Clears IN_INNER when dropped, so a panic inside the inner allocator doesn't leave this thread
+// routed to System for good
+struct InnerGuard;
+
+impl Drop for InnerGuard {
+    fn drop(&mut self) {
+        IN_INNER.set(false);
+    }
+}
+
+pub struct GlobalWithFallback<A> {
+    inner: A,
+}
+
+impl<A> GlobalWithFallback<A> {
+    pub const fn new(inner: A) -> Self {
+        GlobalWithFallback { inner }
+    }
+}
+
+impl<A: Allocator + Owns> GlobalWithFallback<A> {
+    // Runs f against the inner allocator, or returns None if this thread is already inside it
+    fn with_inner<T>(&self, f: impl FnOnce(&A) -> T) -> Option<T> {
+        if IN_INNER.get() {
+            return None;
+        }
+        IN_INNER.set(true);
+        let _guard: InnerGuard = InnerGuard;
+        Some(f(&self.inner))
+    }
+}
+
+unsafe impl<A: Allocator + Owns> GlobalAlloc for GlobalWithFallback<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() <= 512 {
+            let block = self.with_inner(|inner| inner.allocate(layout));
+            if let Some(Ok(ptr)) = block {
+                return ptr.as_mut_ptr();
+            }
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr: NonNull<u8> = match NonNull::new(ptr) {
+            Some(ptr) => ptr,
+            None => return,
+        };
+        let freed: Option<bool> = self.with_inner(|inner| {
+            if inner.owns(ptr) {
+                inner.deallocate(ptr, layout);
+                true
+            } else {
+                false
+            }
+        });
+        if freed != Some(true) {
+            System.dealloc(ptr.as_ptr(), layout);
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout: Layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr: *mut u8 = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, usize::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buddy::Buddy;
+    use crate::mutex::Locked;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    #[test]
+    fn test_inner_flag_cleared_on_unwind() {
+        let global: GlobalWithFallback<Locked<Buddy>> =
+            GlobalWithFallback::new(Locked::new(Buddy::new()));
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            global.with_inner(|_| panic!("inner allocator failed"))
+        }));
+        assert!(result.is_err());
+        assert!(!IN_INNER.get());
+        assert_eq!(global.with_inner(|_| 1), Some(1));
+    }
+
+    // Only meaningful with the crate built with global_fallback, where GLOBAL is a
+    // GlobalWithFallback and every allocation in the test harness goes through it
+    #[cfg(feature = "global_fallback")]
+    #[test]
+    fn test_installed_as_global() {
+        use std::thread;
+
+        let owned = |ptr: *const u8| {
+            crate::GLOBAL
+                .inner
+                .owns(NonNull::new(ptr as *mut u8).unwrap())
+        };
+
+        // Growing the inner allocator's own region table and free lists allocates while its
+        // lock is held, those nested calls must go to System rather than deadlock
+        let handles: Vec<thread::JoinHandle<Vec<Box<[u8; 48]>>>> = (0..4)
+            .map(|_| thread::spawn(|| (0..200).map(|_| Box::new([0x5A; 48])).collect()))
+            .collect();
+        for handle in handles {
+            let boxes: Vec<Box<[u8; 48]>> = handle.join().unwrap();
+            assert!(boxes
+                .iter()
+                .all(|b| owned(b.as_ptr()) && b.iter().all(|byte| *byte == 0x5A)));
+        }
+
+        let large: Vec<u8> = vec![0; 4096];
+        assert!(!owned(large.as_ptr()));
+        assert!(!IN_INNER.get());
+    }
+
+    #[test]
+    fn test_small_and_large_routing() {
+        let global: GlobalWithFallback<Locked<Buddy>> =
+            GlobalWithFallback::new(Locked::new(Buddy::new()));
+        let small: Layout = Layout::from_size_align(64, 8).unwrap();
+        let large: Layout = Layout::from_size_align(4096, 8).unwrap();
+
+        unsafe {
+            let small_ptr: *mut u8 = global.alloc(small);
+            let large_ptr: *mut u8 = global.alloc(large);
+            assert!(global.inner.owns(NonNull::new(small_ptr).unwrap()));
+            assert!(!global.inner.owns(NonNull::new(large_ptr).unwrap()));
+
+            // Growing past 512 moves the block out to System with its contents
+            small_ptr.write_bytes(0x5A, 64);
+            let grown: *mut u8 = global.realloc(small_ptr, small, 1024);
+            assert!(!global.inner.owns(NonNull::new(grown).unwrap()));
+            assert!((0..64).all(|i| *grown.add(i) == 0x5A));
+
+            global.dealloc(grown, Layout::from_size_align(1024, 8).unwrap());
+            global.dealloc(large_ptr, large);
+        }
+    }
+}
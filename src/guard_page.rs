@@ -0,0 +1,101 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::ptr::NonNull;
+
+use crate::region_source::zero_size_block;
+
+/*
+    Debugging allocator that gives every allocation its own pages, bypassing the free lists.
+    - Each block sits at the end of its data pages, right before a PROT_NONE guard page, so
+      writing past the end faults immediately.
+    - Freed pages are made inaccessible rather than unmapped, so their addresses are never handed
+      out again and any use after free faults too. That address space is never given back.
+    - Faults are real segfaults, they crash the process and can't be caught in a test.
+*/
+
+pub struct GuardPageAllocator {
+    page_size: usize,
+}
+
+impl GuardPageAllocator {
+    pub fn new() -> Self {
+        let page_size: usize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        GuardPageAllocator { page_size }
+    }
+
+    // Bytes of readable pages needed to hold the request
+    fn data_len(&self, layout: Layout) -> usize {
+        layout.size().next_multiple_of(self.page_size)
+    }
+
+    // Offset of the block inside its data pages, as close to the guard page as alignment allows
+    fn block_offset(&self, layout: Layout) -> usize {
+        (self.data_len(layout) - layout.size()) & !(layout.align() - 1)
+    }
+}
+
+unsafe impl Allocator for GuardPageAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(block) = zero_size_block(layout) {
+            return Ok(block);
+        }
+        // Mappings are only page aligned
+        if layout.align() > self.page_size {
+            return Err(AllocError);
+        }
+
+        let data_len: usize = self.data_len(layout);
+        unsafe {
+            let base: *mut libc::c_void = libc::mmap(
+                std::ptr::null_mut(),
+                data_len + self.page_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if base == libc::MAP_FAILED {
+                return Err(AllocError);
+            }
+            let guard: *mut libc::c_void = base.cast::<u8>().add(data_len).cast();
+            if libc::mprotect(guard, self.page_size, libc::PROT_NONE) != 0 {
+                libc::munmap(base, data_len + self.page_size);
+                return Err(AllocError);
+            }
+
+            let block: *mut u8 = base.cast::<u8>().add(self.block_offset(layout));
+            Ok(NonNull::slice_from_raw_parts(
+                NonNull::new_unchecked(block),
+                layout.size(),
+            ))
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+        let base: *mut u8 = ptr.as_ptr().sub(self.block_offset(layout));
+        libc::mprotect(base.cast(), self.data_len(layout), libc::PROT_NONE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_write_free() {
+        let allocator: GuardPageAllocator = GuardPageAllocator::new();
+        for size in [1, 100, allocator.page_size, allocator.page_size + 1] {
+            let layout: Layout = Layout::from_size_align(size, 8).unwrap();
+            let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+            assert_eq!(ptr.len(), size);
+            assert!(ptr.as_mut_ptr().addr().is_multiple_of(8));
+            unsafe {
+                ptr.as_mut_ptr().write_bytes(0x5A, size);
+                assert!(ptr.as_ref().iter().all(|byte| *byte == 0x5A));
+                allocator.deallocate(ptr.as_non_null_ptr(), layout);
+            }
+        }
+    }
+}
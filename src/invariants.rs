@@ -0,0 +1,267 @@
+use std::collections::LinkedList;
+use std::ptr::NonNull;
+
+use crate::region_source::RegionSizes;
+
+/*
+    Free list consistency checks shared by the allocators:
+    - Every free block must lie entirely inside one of the owned regions.
+    - No two free blocks may overlap (which includes the same block being listed twice).
+    Blocks are visited in address order, and on an overlap the block appearing later is the one reported/removed.
+    Intrusive lists, where each free block stores the address of the next, can also be checked for cycles.
+*/
+
+#[cfg(test)]
+pub fn check_free_lists(
+    lists: &[LinkedList<NonNull<[u8]>>],
+    regions: &[NonNull<u8>],
+    region_sizes: impl RegionSizes,
+) -> Result<(), String> {
+    match find_invalid_blocks(lists, regions, region_sizes).first() {
+        None => Ok(()),
+        Some((list_index, position)) => {
+            let block: &NonNull<[u8]> = lists[*list_index].iter().nth(*position).unwrap();
+            Err(format!(
+                "free block {:#x}..{:#x} in list {} is outside every region or overlaps another free block",
+                block.addr().get(),
+                block.addr().get() + block.len(),
+                list_index
+            ))
+        }
+    }
+}
+
+// Walks an intrusive list ending in 0 with Floyd's algorithm, returning an address on the cycle if
+// the list never ends
+#[cfg(test)]
+pub fn find_cycle(head: usize, next: impl Fn(usize) -> usize) -> Option<usize> {
+    let mut slow: usize = head;
+    let mut fast: usize = head;
+    loop {
+        if fast == 0 || next(fast) == 0 {
+            return None;
+        }
+        slow = next(slow);
+        fast = next(next(fast));
+        if slow == fast {
+            return Some(slow);
+        }
+    }
+}
+
+// Counts the blocks a cursor scan visits. Unlike iterators, which stop after len blocks, a cursor
+// follows the links, so a list whose links loop back would be scanned forever. In debug builds a
+// scan that goes past len panics instead.
+pub struct ScanBound {
+    list: usize,
+    len: usize,
+    steps: usize,
+}
+
+impl ScanBound {
+    pub fn new(list: usize, len: usize) -> Self {
+        ScanBound {
+            list,
+            len,
+            steps: 0,
+        }
+    }
+
+    // Called once per block visited
+    pub fn step(&mut self) {
+        if cfg!(debug_assertions) {
+            self.steps += 1;
+            assert!(
+                self.steps <= self.len,
+                "free list {} loops back on itself, scanned past its {} blocks",
+                self.list,
+                self.len
+            );
+        }
+    }
+}
+
+// Free list allocators checked against the shared invariants above, each pointing the shared
+// checks at its own lists and region table
+#[cfg(test)]
+pub trait FreeListInvariants {
+    fn check_invariants(&self) -> Result<(), String>;
+    // Removes free blocks that are outside every region or overlap another free block, returning
+    // how many were removed
+    fn repair(&mut self) -> usize;
+    // Puts the allocator in a state check_invariants rejects, to test recovery
+    fn inject_overlap(&mut self);
+}
+
+// Lists the first free block a second time so the free lists overlap
+#[cfg(test)]
+pub fn list_first_block_twice(lists: &mut [LinkedList<NonNull<[u8]>>]) {
+    let block: NonNull<[u8]> = *lists
+        .iter()
+        .find_map(|list| list.front())
+        .expect("need a free block to overlap");
+    lists[0].push_back(block);
+}
+
+// How much a deallocate checks the block it is given before trusting it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValidationLevel {
+    // Trust the caller
+    None,
+    // The pointer must lie in one of the allocator's regions
+    Bounds,
+    // The whole block must fit in its region and must not already be free (double free)
+    Full,
+}
+
+impl ValidationLevel {
+    // Bounds checks are cheap next to a use-after-free going unnoticed, so every build makes them
+    // unless asked otherwise. A const, since allocators are built in const fns for statics.
+    pub const DEFAULT: ValidationLevel = ValidationLevel::Bounds;
+}
+
+// Called with the reason a deallocate failed validation. It runs under the allocator lock.
+pub type InvalidFreeHook = Box<dyn Fn(&str) + Send + Sync>;
+
+// Checks deallocate runs on the blocks it is given. Returns whether the block may be freed. A
+// failure is reported without unwinding, since the allocator's lock is held and its lists may be
+// mid-update: the hook gets the reason if one is set, otherwise the process aborts.
+pub fn validate_free(
+    level: ValidationLevel,
+    on_invalid_free: &Option<InvalidFreeHook>,
+    lists: &[LinkedList<NonNull<[u8]>>],
+    regions: &[NonNull<u8>],
+    region_sizes: impl RegionSizes,
+    block: NonNull<[u8]>,
+) -> bool {
+    match check_free(level, lists, regions, region_sizes, block) {
+        Ok(()) => true,
+        Err(reason) => {
+            report_invalid_free(on_invalid_free, &reason);
+            false
+        }
+    }
+}
+
+pub fn report_invalid_free(on_invalid_free: &Option<InvalidFreeHook>, reason: &str) {
+    match on_invalid_free {
+        Some(hook) => hook(reason),
+        None => {
+            eprintln!("{}", reason);
+            std::process::abort();
+        }
+    }
+}
+
+fn check_free(
+    level: ValidationLevel,
+    lists: &[LinkedList<NonNull<[u8]>>],
+    regions: &[NonNull<u8>],
+    region_sizes: impl RegionSizes,
+    block: NonNull<[u8]>,
+) -> Result<(), String> {
+    if level == ValidationLevel::None {
+        return Ok(());
+    }
+
+    let start: usize = block.addr().get();
+    let end: usize = start + block.len();
+    let (region_start, region_size): (usize, usize) = regions
+        .iter()
+        .enumerate()
+        .map(|(index, region)| (region.addr().get(), region_sizes.region_size(index)))
+        .find(|(region_start, region_size)| {
+            *region_start <= start && start < region_start + region_size
+        })
+        .ok_or_else(|| format!("deallocated pointer {:#x} is not in any region", start))?;
+    if level == ValidationLevel::Bounds {
+        return Ok(());
+    }
+
+    if end > region_start + region_size {
+        return Err(format!(
+            "deallocated block {:#x}..{:#x} runs past the end of its region",
+            start, end
+        ));
+    }
+    if lists.iter().flatten().any(|free| {
+        let free_start: usize = free.addr().get();
+        free_start < end && start < free_start + free.len()
+    }) {
+        return Err(format!("double free of {:#x}", start));
+    }
+    Ok(())
+}
+
+// Drops every block check_free_lists would complain about, returning how many were removed
+#[cfg(test)]
+pub fn repair_free_lists(
+    lists: &mut [LinkedList<NonNull<[u8]>>],
+    regions: &[NonNull<u8>],
+    region_sizes: impl RegionSizes,
+) -> usize {
+    let invalid: Vec<(usize, usize)> = find_invalid_blocks(lists, regions, region_sizes);
+    for (list_index, list) in lists.iter_mut().enumerate() {
+        let blocks: LinkedList<NonNull<[u8]>> = std::mem::take(list);
+        *list = blocks
+            .into_iter()
+            .enumerate()
+            .filter(|(position, _)| !invalid.contains(&(list_index, *position)))
+            .map(|(_, block)| block)
+            .collect();
+    }
+    invalid.len()
+}
+
+// (list index, position in list) of each block breaking an invariant
+#[cfg(test)]
+fn find_invalid_blocks(
+    lists: &[LinkedList<NonNull<[u8]>>],
+    regions: &[NonNull<u8>],
+    region_sizes: impl RegionSizes,
+) -> Vec<(usize, usize)> {
+    let mut blocks: Vec<(usize, usize, NonNull<[u8]>)> = lists
+        .iter()
+        .enumerate()
+        .flat_map(|(list_index, list)| {
+            list.iter()
+                .enumerate()
+                .map(move |(position, block)| (list_index, position, *block))
+        })
+        .collect();
+    blocks.sort_by_key(|(_, _, block)| block.addr().get());
+
+    let mut invalid: Vec<(usize, usize)> = Vec::new();
+    let mut covered_until: usize = 0;
+    for (list_index, position, block) in blocks {
+        let start: usize = block.addr().get();
+        let end: usize = start + block.len();
+        let in_region: bool = regions.iter().enumerate().any(|(index, region)| {
+            let region_start: usize = region.addr().get();
+            region_start <= start && end <= region_start + region_sizes.region_size(index)
+        });
+
+        if !in_region || start < covered_until {
+            invalid.push((list_index, position));
+        } else {
+            covered_until = end;
+        }
+    }
+    invalid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "free list 2 loops back on itself")]
+    fn test_scan_bound() {
+        // A std list can't be made to loop, so step one block past its length by hand
+        let mut bound: ScanBound = ScanBound::new(2, 3);
+        for _ in 0..4 {
+            bound.step();
+        }
+    }
+}
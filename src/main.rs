@@ -4,21 +4,99 @@
 #![feature(slice_ptr_get)]
 #![feature(strict_provenance)]
 
-use std::sync::{Mutex, MutexGuard};
-
+#[cfg(all(test, feature = "backtrace"))]
+mod backtrace;
+#[cfg(test)]
+mod bitmap;
 mod buddy;
+#[cfg(test)]
+mod cache_line;
+#[cfg(test)]
+mod conformance;
+#[cfg(all(feature = "count_allocations", not(feature = "global_fallback")))]
+mod counting;
+#[cfg(all(test, feature = "dump"))]
+mod dump;
+#[cfg(test)]
+mod epoch;
+#[cfg(test)]
+mod fast_path;
+#[cfg(all(test, feature = "mmap"))]
+mod file_backed;
+#[cfg(test)]
+mod fuzz;
+#[cfg(any(test, feature = "global_fallback"))]
+mod global;
+#[cfg(all(test, unix, feature = "guard_pages"))]
+mod guard_page;
+mod invariants;
+#[cfg(test)]
+mod min_align;
 mod mutex;
+#[cfg(test)]
+mod owned_block;
+mod region_source;
+#[cfg(test)]
+mod round_robin;
 mod segregated_free_list;
 mod simple_segregated_storage;
+#[cfg(test)]
+mod snapshot;
+#[cfg(test)]
+mod static_pool;
 mod stats;
+mod striped;
+#[cfg(all(test, feature = "track_allocations"))]
+mod tagged;
+#[cfg(test)]
+mod timing;
+#[cfg(test)]
+mod trace;
+#[cfg(all(test, feature = "tracing"))]
+mod tracing_log;
+mod vec_segregated_storage;
 
 use crate::buddy::Buddy;
-use crate::mutex::{Lock, Locked};
+#[cfg(all(feature = "count_allocations", not(feature = "global_fallback")))]
+use crate::counting::CountingAllocator;
+use crate::mutex::{Lock, Locked, ReadLock, RwLocked, SpinLocked, WriteLock};
 use crate::segregated_free_list::SegregatedFreeList;
 use crate::simple_segregated_storage::SimpleSegregatedStorage;
 use crate::stats::MemStats;
+use crate::striped::StripedLocked;
+use crate::vec_segregated_storage::VecSegregatedStorage;
+
+#[cfg(feature = "global_fallback")]
+use crate::global::GlobalWithFallback;
+
+// Counting every call slows down the whole program, so it is only installed on request
+#[cfg(all(feature = "count_allocations", not(feature = "global_fallback")))]
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[cfg(feature = "global_fallback")]
+#[global_allocator]
+static GLOBAL: GlobalWithFallback<Locked<SimpleSegregatedStorage>> =
+    GlobalWithFallback::new(Locked::new(SimpleSegregatedStorage::new()));
 
 fn main() {
+    if std::env::args().any(|arg| arg == "--compare-free-lists") {
+        compare_free_list_backends();
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--compare-striping") {
+        compare_lock_striping();
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--compare-spinning") {
+        compare_lock_spinning();
+        return;
+    }
+    if std::env::args().any(|arg| arg == "--compare-max-class-frees") {
+        compare_max_class_frees();
+        return;
+    }
+
     println!("\nTesting Simple Segregated Storage Allocator");
     let allocator = Locked::new(SimpleSegregatedStorage::new());
     test_throughput(&allocator);
@@ -33,11 +111,21 @@ fn main() {
     let allocator = Locked::new(Buddy::new());
     test_throughput(&allocator);
     test_peak_memory_usage(&allocator);
+
+    println!("\nTesting Buddy Allocator behind a read-write lock");
+    let allocator = RwLocked::new(Buddy::new());
+    test_peak_memory_usage(&allocator);
 }
 
-fn test_throughput<T: std::alloc::Allocator>(allocator: &T) {
+fn test_throughput<A: MemStats>(allocator: &Locked<A>)
+where
+    Locked<A>: std::alloc::Allocator,
+{
     use std::time::{Duration, Instant};
     const TOTAL: f64 = 5.0;
+    allocator.warmup();
+    // The warmup blocks are all freed, so the reported peak only covers the timed allocations
+    allocator.lock().reset_peak();
     let start: Instant = Instant::now();
 
     let _b = Box::new_in(1_u8, allocator);
@@ -64,19 +152,23 @@ fn test_throughput<T: std::alloc::Allocator>(allocator: &T) {
 
     let end: Instant = Instant::now();
     let delta: Duration = end - start;
+    let extension_count: u64 = allocator.lock().extension_count();
     println!(
-        "num_allocations: {}\ntime_taken: {} seconds\nthroughput: {} allocations per seconds",
+        "num_allocations: {}\ntime_taken: {} seconds\nthroughput: {} allocations per seconds\nheap_extensions: {}",
         TOTAL as usize,
         delta.as_secs_f64(),
-        TOTAL / delta.as_secs_f64()
+        TOTAL / delta.as_secs_f64(),
+        extension_count
     );
+    print_allocation_ratio(&*allocator.lock());
 }
 
-fn test_peak_memory_usage<A: MemStats, T: std::alloc::Allocator + Lock<A>>(allocator: &T) {
+// Resetting needs the exclusive lock, the final stats are only read so they go through ReadLock
+fn test_peak_memory_usage<A: MemStats, T: std::alloc::Allocator + WriteLock<A> + ReadLock<A>>(
+    allocator: &T,
+) {
     // reset stats
-    let mut alloc: MutexGuard<'_, A> = allocator.lock();
-    alloc.reset();
-    Mutex::unlock(alloc);
+    allocator.write().reset();
 
     let _b = Box::new_in(1_u8, allocator);
     {
@@ -100,11 +192,189 @@ fn test_peak_memory_usage<A: MemStats, T: std::alloc::Allocator + Lock<A>>(alloc
     let _o = Box::new_in(100_u64, allocator);
     let _p = Box::new_in(100_u64, allocator);
 
-    let alloc: MutexGuard<'_, A> = allocator.lock();
-    let (allocated_size, total_size, peak_mem_usage_ratio): (f64, f64, f64) =
-        (*alloc).calculate_allocation_ratio();
+    print_stats(&*allocator.read());
+}
+
+// The peak ratio plus the counters kept alongside it
+fn print_stats<A: MemStats>(stats: &A) {
+    debug_assert!(stats.stats_consistent());
+    print_allocation_ratio(stats);
+    println!(
+        "current_allocated: {} bytes\nalloc_success: {}\nalloc_failure: {}\nsplits: {}\nmerges: {}\nalignment_waste: {} bytes\nexternal_fragmentation: {}\nlive_per_class: {:?}",
+        stats.current_allocated_size(),
+        stats.alloc_success(),
+        stats.alloc_failure(),
+        stats.split_count(),
+        stats.merge_count(),
+        stats.alignment_waste(),
+        stats.external_fragmentation(),
+        stats.size_class_histogram()
+    );
+}
+
+fn print_allocation_ratio<A: MemStats>(stats: &A) {
+    let (allocated_size, total_size, peak_mem_usage_ratio): (usize, usize, f64) =
+        stats.calculate_allocation_ratio();
     println!(
         "allocated_memory: {} bytes\ntotal_memory: {} bytes\npeak_memory_usage_ratio {} ",
         allocated_size, total_size, peak_mem_usage_ratio
     );
 }
+
+// Global allocator calls, the LinkedList node traffic, are only reported with count_allocations
+fn compare_free_list_backends() {
+    println!("\nTesting LinkedList free lists");
+    let allocator = Locked::new(SimpleSegregatedStorage::new());
+    test_free_list_backend(&allocator);
+
+    println!("\nTesting Vec free lists");
+    let allocator = Locked::new(VecSegregatedStorage::new());
+    test_free_list_backend(&allocator);
+}
+
+fn compare_lock_striping() {
+    println!("\nTesting one lock for all classes");
+    let allocator = Locked::new(SimpleSegregatedStorage::new());
+    test_contended_classes(&allocator);
+    print_allocation_ratio(&*allocator.lock());
+
+    println!("\nTesting one lock per class");
+    let allocator = StripedLocked::new();
+    test_contended_classes(&allocator);
+    print_allocation_ratio(&allocator);
+}
+
+fn compare_lock_spinning() {
+    println!("\nTesting blocking lock");
+    let allocator = Locked::new(SimpleSegregatedStorage::new());
+    test_contended_classes(&allocator);
+
+    println!("\nTesting spin then block lock");
+    let allocator = SpinLocked::new(SimpleSegregatedStorage::new(), 100);
+    test_contended_classes(&allocator);
+}
+
+// A full region block skips the neighbor search on free, a half region block scans every list for
+// its neighbors, so both are timed with the same long free list in place
+fn compare_max_class_frees() {
+    println!("\nTesting frees of full region blocks");
+    test_frees_past_free_list(&Locked::new(SegregatedFreeList::new()), 512);
+
+    println!("\nTesting frees of half region blocks");
+    test_frees_past_free_list(&Locked::new(SegregatedFreeList::new()), 256);
+}
+
+fn test_frees_past_free_list(allocator: &Locked<SegregatedFreeList>, size: usize) {
+    use std::alloc::{Allocator, Layout};
+    use std::ptr::NonNull;
+    use std::time::{Duration, Instant};
+    const ROUNDS: usize = 10_000;
+    const SCATTERED: usize = 1_000;
+
+    // Freeing every other small block leaves them scattered, none of them can merge back
+    let small: Layout = Layout::from_size_align(32, 8).unwrap();
+    let scattered: Vec<NonNull<[u8]>> = (0..2 * SCATTERED)
+        .map(|_| allocator.allocate(small).unwrap())
+        .collect();
+    for block in scattered.iter().step_by(2) {
+        unsafe {
+            allocator.deallocate(block.as_non_null_ptr(), small);
+        }
+    }
+
+    let layout: Layout = Layout::from_size_align(size, 8).unwrap();
+    let mut delta: Duration = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        let block: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        let start: Instant = Instant::now();
+        unsafe {
+            allocator.deallocate(block.as_non_null_ptr(), layout);
+        }
+        delta += start.elapsed();
+    }
+    println!(
+        "free_list_length: {}\ntime_taken: {} seconds\nthroughput: {} frees per seconds",
+        SCATTERED,
+        delta.as_secs_f64(),
+        ROUNDS as f64 / delta.as_secs_f64()
+    );
+}
+
+fn test_contended_classes<T: std::alloc::Allocator + Sync>(allocator: &T) {
+    use std::alloc::Layout;
+    use std::time::{Duration, Instant};
+    const ROUNDS: usize = 100_000;
+    const THREADS: usize = 4;
+
+    // Each thread sticks to its own size class, so only a shared lock makes them wait
+    let start: Instant = Instant::now();
+    std::thread::scope(|scope| {
+        for thread_index in 0..THREADS {
+            scope.spawn(move || {
+                let layout: Layout = Layout::from_size_align(8 << thread_index, 8).unwrap();
+                for _ in 0..ROUNDS {
+                    let block = allocator.allocate(layout).unwrap();
+                    unsafe {
+                        allocator.deallocate(block.as_non_null_ptr(), layout);
+                    }
+                }
+            });
+        }
+    });
+    let delta: Duration = start.elapsed();
+    println!(
+        "time_taken: {} seconds\nthroughput: {} allocations per seconds",
+        delta.as_secs_f64(),
+        (ROUNDS * THREADS) as f64 / delta.as_secs_f64()
+    );
+}
+
+fn test_free_list_backend<T: std::alloc::Allocator>(allocator: &T) {
+    use std::alloc::Layout;
+    use std::ptr::NonNull;
+    use std::time::{Duration, Instant};
+    const ROUNDS: usize = 100_000;
+    let layouts: [Layout; 5] =
+        [8, 24, 64, 100, 200].map(|size| Layout::from_size_align(size, 8).unwrap());
+
+    // Blocks are kept in a fixed array so the loop itself never calls the global allocator
+    #[cfg(all(feature = "count_allocations", not(feature = "global_fallback")))]
+    let calls_before: usize = CountingAllocator::calls();
+    let start: Instant = Instant::now();
+    for _ in 0..ROUNDS {
+        let blocks: [NonNull<[u8]>; 5] = layouts.map(|layout| allocator.allocate(layout).unwrap());
+        for (block, layout) in blocks.iter().zip(layouts) {
+            unsafe {
+                allocator.deallocate(block.as_non_null_ptr(), layout);
+            }
+        }
+    }
+    let delta: Duration = start.elapsed();
+
+    let total: f64 = (ROUNDS * layouts.len()) as f64;
+    println!(
+        "num_allocations: {}\ntime_taken: {} seconds\nthroughput: {} allocations per seconds",
+        total as usize,
+        delta.as_secs_f64(),
+        total / delta.as_secs_f64()
+    );
+    #[cfg(all(feature = "count_allocations", not(feature = "global_fallback")))]
+    println!(
+        "global_allocator_calls: {}",
+        CountingAllocator::calls() - calls_before
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peak_memory_usage_demo() {
+        test_peak_memory_usage(&Locked::new(SimpleSegregatedStorage::new()));
+        test_peak_memory_usage(&Locked::new(SegregatedFreeList::new()));
+        test_peak_memory_usage(&Locked::new(Buddy::new()));
+        test_peak_memory_usage(&RwLocked::new(SimpleSegregatedStorage::new()));
+        test_peak_memory_usage(&RwLocked::new(Buddy::new()));
+    }
+}
@@ -0,0 +1,49 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::ptr::NonNull;
+
+// Wraps an allocator and raises every request to at least N byte alignment, e.g. for FFI that
+// expects 16 byte aligned buffers whatever the Rust type
+pub struct MinAlign<A, const N: usize> {
+    inner: A,
+}
+
+impl<A, const N: usize> MinAlign<A, N> {
+    pub fn new(inner: A) -> Self {
+        MinAlign { inner }
+    }
+}
+
+// Only depends on the original layout, so deallocate can rebuild the layout that was allocated
+fn raised<const N: usize>(layout: Layout) -> Result<Layout, AllocError> {
+    layout.align_to(N).map_err(|_| AllocError)
+}
+
+unsafe impl<A: Allocator, const N: usize> Allocator for MinAlign<A, N> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.inner.allocate(raised::<N>(layout)?)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.inner.deallocate(ptr, raised::<N>(layout).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mutex::Locked;
+    use crate::segregated_free_list::SegregatedFreeList;
+
+    #[test]
+    fn test_min_align() {
+        let allocator: MinAlign<Locked<SegregatedFreeList>, 16> =
+            MinAlign::new(Locked::new(SegregatedFreeList::new()));
+
+        // Without the wrapper the second byte would be placed right after the first
+        let first: Box<u8, _> = Box::new_in(1_u8, &allocator);
+        let second: Box<u8, _> = Box::new_in(2_u8, &allocator);
+        assert_eq!((&*first as *const u8).addr() % 16, 0);
+        assert_eq!((&*second as *const u8).addr() % 16, 0);
+        assert_eq!((*first, *second), (1, 2));
+    }
+}
@@ -1,23 +1,614 @@
-use std::sync::{Mutex, MutexGuard};
+use std::alloc::{AllocError, Allocator, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(test)]
+use std::sync::{Arc, Weak};
+use std::sync::{
+    Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+};
+
+#[cfg(test)]
+use crate::owned_block::OwnedBlock;
+#[cfg(test)]
+use crate::stats::MemStats;
 
 pub trait Lock<A> {
-    fn lock(&self) -> MutexGuard<A>;
+    fn lock(&self) -> MutexGuard<'_, A>;
+    // None instead of blocking when another thread holds the lock, for paths that would rather
+    // fail than wait
+    fn try_lock(&self) -> Option<MutexGuard<'_, A>>;
+}
+
+// Access for code that only reads the allocator, such as sampling stats. A lock that can share
+// reads hands out a shared guard, Locked can only hand out its exclusive one.
+pub trait ReadLock<A> {
+    type ReadGuard<'a>: Deref<Target = A>
+    where
+        Self: 'a;
+
+    fn read(&self) -> Self::ReadGuard<'_>;
+}
+
+// Exclusive access for code that changes the allocator outside allocate and deallocate, such as
+// resetting its stats
+pub trait WriteLock<A> {
+    type WriteGuard<'a>: DerefMut<Target = A>
+    where
+        Self: 'a;
+
+    fn write(&self) -> Self::WriteGuard<'_>;
 }
 
 pub struct Locked<A> {
     inner: Mutex<A>,
+    // allocate calls currently executing, including those still waiting for the lock
+    in_flight: AtomicUsize,
+}
+
+// Counts one allocate call as in flight until dropped, so early error returns are covered too
+pub struct InFlight<'a> {
+    counter: &'a AtomicUsize,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
             inner: Mutex::new(inner),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    // Number of allocate calls executing right now. Unlike the live block count this measures
+    // concurrency, so a value that stays high means callers are queuing on the lock.
+    #[cfg(test)]
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn enter_allocate(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight {
+            counter: &self.in_flight,
         }
     }
 }
 
+impl<A> Locked<A>
+where
+    Locked<A>: Allocator,
+{
+    // Allocates as usual, then fills the whole block with a recognizable pattern
+    #[cfg(test)]
+    pub fn allocate_filled(&self, layout: Layout, byte: u8) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr: NonNull<[u8]> = self.allocate(layout)?;
+        unsafe {
+            ptr.as_mut_ptr().write_bytes(byte, ptr.len());
+        }
+        Ok(ptr)
+    }
+
+    // Allocates a block that panics if it is dropped without being released
+    #[cfg(test)]
+    pub fn allocate_owned(&self, layout: Layout) -> Result<OwnedBlock<'_, Self>, AllocError> {
+        let block: NonNull<[u8]> = self.allocate(layout)?;
+        Ok(OwnedBlock::new(self, block, layout))
+    }
+
+    // Allocates and frees one block of every power of 2 up to 512, so each size class has its
+    // region and free list in place before anything is timed
+    pub fn warmup(&self) {
+        for class_index in 0..10 {
+            let layout: Layout = Layout::from_size_align(1 << class_index, 1).unwrap();
+            if let Ok(ptr) = self.allocate(layout) {
+                unsafe {
+                    self.deallocate(ptr.as_non_null_ptr(), layout);
+                }
+            }
+        }
+    }
+}
+
+// A thread panicking mid-operation poisons the mutex, but the allocator only holds lists of
+// pointers, so later callers take the guard anyway rather than failing every allocation after it
 impl<A> Lock<A> for Locked<A> {
-    fn lock(&self) -> MutexGuard<A> {
-        self.inner.lock().unwrap()
+    fn lock(&self) -> MutexGuard<'_, A> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn try_lock(&self) -> Option<MutexGuard<'_, A>> {
+        match self.inner.try_lock() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+}
+
+impl<A> ReadLock<A> for Locked<A> {
+    type ReadGuard<'a>
+        = MutexGuard<'a, A>
+    where
+        Self: 'a;
+
+    fn read(&self) -> MutexGuard<'_, A> {
+        self.lock()
+    }
+}
+
+impl<A> WriteLock<A> for Locked<A> {
+    type WriteGuard<'a>
+        = MutexGuard<'a, A>
+    where
+        Self: 'a;
+
+    fn write(&self) -> MutexGuard<'_, A> {
+        self.lock()
+    }
+}
+
+// Allocation entry points for a caller that already has exclusive access to the allocator, so a
+// lock other than Locked can serve allocate and deallocate
+pub trait ExclusiveAllocator {
+    fn allocate_exclusive(&mut self, layout: Layout) -> Result<NonNull<[u8]>, AllocError>;
+    // ptr must have come from this allocator with the same layout, as for Allocator::deallocate
+    unsafe fn deallocate_exclusive(&mut self, ptr: NonNull<u8>, layout: Layout);
+}
+
+// Lets any number of readers look at the allocator at once, for sampling stats from many threads
+// without queuing behind each other. allocate and deallocate take the write lock.
+pub struct RwLocked<A> {
+    inner: RwLock<A>,
+}
+
+impl<A> RwLocked<A> {
+    pub fn new(inner: A) -> Self {
+        RwLocked {
+            inner: RwLock::new(inner),
+        }
+    }
+}
+
+// Poisoning is recovered from the same way Locked does
+impl<A> ReadLock<A> for RwLocked<A> {
+    type ReadGuard<'a>
+        = RwLockReadGuard<'a, A>
+    where
+        Self: 'a;
+
+    fn read(&self) -> RwLockReadGuard<'_, A> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<A> WriteLock<A> for RwLocked<A> {
+    type WriteGuard<'a>
+        = RwLockWriteGuard<'a, A>
+    where
+        Self: 'a;
+
+    fn write(&self) -> RwLockWriteGuard<'_, A> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+unsafe impl<A: ExclusiveAllocator> Allocator for RwLocked<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.write().allocate_exclusive(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.write().deallocate_exclusive(ptr, layout)
+    }
+}
+
+// Spins on an AtomicBool up to a limit before blocking, avoiding a context switch when the holder
+// is about to release it. Callers that win the flag go on to an uncontended mutex, callers that run
+// out of spins fall back to blocking on the Locked mutex, which is what keeps access exclusive.
+pub struct SpinLocked<A> {
+    inner: Locked<A>,
+    busy: AtomicBool,
+    spin_limit: usize,
+}
+
+// Clears the busy flag once the operation that set it is done
+struct BusyFlag<'a> {
+    busy: &'a AtomicBool,
+}
+
+impl Drop for BusyFlag<'_> {
+    fn drop(&mut self) {
+        self.busy.store(false, Ordering::Release);
+    }
+}
+
+impl<A> SpinLocked<A> {
+    pub const fn new(inner: A, spin_limit: usize) -> Self {
+        SpinLocked {
+            inner: Locked::new(inner),
+            busy: AtomicBool::new(false),
+            spin_limit,
+        }
+    }
+
+    // None once the spin limit runs out, and the caller blocks on the mutex instead
+    fn spin(&self) -> Option<BusyFlag<'_>> {
+        for _ in 0..self.spin_limit {
+            if self
+                .busy
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return Some(BusyFlag { busy: &self.busy });
+            }
+            std::hint::spin_loop();
+        }
+        None
+    }
+}
+
+impl<A> Lock<A> for SpinLocked<A> {
+    // The guard can't carry the flag with it, so this spins on the mutex itself
+    fn lock(&self) -> MutexGuard<'_, A> {
+        for _ in 0..self.spin_limit {
+            if let Some(guard) = self.inner.try_lock() {
+                return guard;
+            }
+            std::hint::spin_loop();
+        }
+        self.inner.lock()
+    }
+
+    fn try_lock(&self) -> Option<MutexGuard<'_, A>> {
+        self.inner.try_lock()
+    }
+}
+
+unsafe impl<A> Allocator for SpinLocked<A>
+where
+    Locked<A>: Allocator,
+{
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let _flag: Option<BusyFlag<'_>> = self.spin();
+        self.inner.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let _flag: Option<BusyFlag<'_>> = self.spin();
+        self.inner.deallocate(ptr, layout)
+    }
+}
+
+// Reference counted handle so allocations can keep the allocator alive. Orphan rules prevent
+// implementing Allocator on Arc<Locked<A>> directly, so it is wrapped in a local type instead.
+#[cfg(test)]
+pub struct Shared<A> {
+    inner: Arc<Locked<A>>,
+}
+
+#[cfg(test)]
+impl<A> Shared<A> {
+    pub fn new(inner: A) -> Self {
+        Shared {
+            inner: Arc::new(Locked::new(inner)),
+        }
+    }
+}
+
+#[cfg(test)]
+impl<A> Shared<A> {
+    pub fn downgrade_stats(&self) -> WeakStatsHandle<A> {
+        WeakStatsHandle::new(&self.inner)
+    }
+}
+
+// Observes an allocator's stats without keeping it alive
+#[cfg(test)]
+pub struct WeakStatsHandle<A> {
+    inner: Weak<Locked<A>>,
+}
+
+#[cfg(test)]
+impl<A> WeakStatsHandle<A> {
+    pub fn new(allocator: &Arc<Locked<A>>) -> Self {
+        WeakStatsHandle {
+            inner: Arc::downgrade(allocator),
+        }
+    }
+}
+
+#[cfg(test)]
+impl<A: MemStats> WeakStatsHandle<A> {
+    // Same as calculate_allocation_ratio, or None once the allocator has been dropped
+    pub fn stats(&self) -> Option<(usize, usize, f64)> {
+        self.inner
+            .upgrade()
+            .map(|allocator| allocator.lock().calculate_allocation_ratio())
+    }
+}
+
+#[cfg(test)]
+impl<A> Clone for Shared<A> {
+    fn clone(&self) -> Self {
+        Shared {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+#[cfg(test)]
+impl<A> Lock<A> for Shared<A> {
+    fn lock(&self) -> MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+
+    fn try_lock(&self) -> Option<MutexGuard<'_, A>> {
+        self.inner.try_lock()
+    }
+}
+
+#[cfg(test)]
+unsafe impl<A> Allocator for Shared<A>
+where
+    Locked<A>: Allocator,
+{
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.inner.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.inner.deallocate(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buddy::Buddy;
+    use crate::segregated_free_list::SegregatedFreeList;
+    use crate::simple_segregated_storage::SimpleSegregatedStorage;
+    use std::thread;
+
+    fn assert_filled<A>(allocator: &Locked<A>)
+    where
+        Locked<A>: Allocator,
+    {
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate_filled(layout, 0xDD).unwrap();
+        unsafe {
+            assert!(ptr.as_ref().iter().all(|byte| *byte == 0xDD));
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+    }
+
+    #[test]
+    fn test_rw_locked_readers_with_writer() {
+        let allocator: RwLocked<Buddy> = RwLocked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+        let done: AtomicBool = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            let readers: Vec<thread::ScopedJoinHandle<'_, usize>> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        // Samples at least once even if the writer finishes before this starts
+                        let mut samples: usize = 0;
+                        loop {
+                            let stats: RwLockReadGuard<'_, Buddy> = allocator.read();
+                            assert!(stats.stats_consistent());
+                            samples += 1;
+                            if done.load(Ordering::Relaxed) {
+                                return samples;
+                            }
+                            drop(stats);
+                            // Leave the writer room to run when threads share a core
+                            thread::yield_now();
+                        }
+                    })
+                })
+                .collect();
+
+            for _ in 0..1000 {
+                let block: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+                unsafe {
+                    allocator.deallocate(block.as_non_null_ptr(), layout);
+                }
+            }
+            done.store(true, Ordering::Relaxed);
+            for reader in readers {
+                assert!(reader.join().unwrap() > 0);
+            }
+        });
+
+        // Readers share the lock, the writer waits for both to go
+        let first: RwLockReadGuard<'_, Buddy> = allocator.read();
+        let second: RwLockReadGuard<'_, Buddy> = allocator.read();
+        assert_eq!(
+            first.calculate_allocation_ratio(),
+            second.calculate_allocation_ratio()
+        );
+        assert!(allocator.inner.try_write().is_err());
+        drop((first, second));
+        allocator.write().reset();
+        assert_eq!(allocator.read().calculate_allocation_ratio().1, 0);
+    }
+
+    #[test]
+    fn test_recover_from_poison() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+        thread::scope(|scope| {
+            let panicked = scope.spawn(|| {
+                let _held: MutexGuard<'_, Buddy> = allocator.lock();
+                panic!("poisoning the allocator lock");
+            });
+            assert!(panicked.join().is_err());
+        });
+        assert!(allocator.inner.is_poisoned());
+
+        thread::scope(|scope| {
+            let served = scope.spawn(|| match allocator.allocate(layout) {
+                Ok(ptr) => unsafe {
+                    allocator.deallocate(ptr.as_non_null_ptr(), layout);
+                    true
+                },
+                Err(_) => false,
+            });
+            assert!(served.join().unwrap());
+        });
+        assert!(allocator.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_try_lock() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let held: MutexGuard<'_, Buddy> = allocator.lock();
+        assert!(allocator.try_lock().is_none());
+        // Contention is seen from other threads as well
+        thread::scope(|scope| {
+            assert!(scope
+                .spawn(|| allocator.try_lock().is_none())
+                .join()
+                .unwrap());
+        });
+        drop(held);
+        assert!(allocator.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_spin_locked_threads() {
+        let allocator: SpinLocked<Buddy> = SpinLocked::new(Buddy::new(), 100);
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+
+        // Each thread stamps its blocks, so two threads handed the same block would see a mismatch
+        thread::scope(|scope| {
+            for thread_index in 0..4_u8 {
+                let allocator: &SpinLocked<Buddy> = &allocator;
+                scope.spawn(move || {
+                    for _ in 0..1000 {
+                        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+                        unsafe {
+                            ptr.as_mut_ptr().write_bytes(thread_index, layout.size());
+                            thread::yield_now();
+                            assert!(ptr.as_ref().iter().all(|byte| *byte == thread_index));
+                            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+                        }
+                    }
+                });
+            }
+        });
+        // Every caller that won the flag handed it back
+        assert!(!allocator.busy.load(Ordering::Relaxed));
+        let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+        assert!(alloc.stats_consistent());
+        assert!(alloc
+            .region_occupancy()
+            .iter()
+            .all(|occupancy| *occupancy == 0.0));
+    }
+
+    #[test]
+    fn test_spin_locked_falls_back_to_mutex() {
+        let allocator: SpinLocked<Buddy> = SpinLocked::new(Buddy::new(), 10);
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+
+        // With the flag stuck, every call runs out of spins and has to go through the mutex
+        allocator.busy.store(true, Ordering::Relaxed);
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+        assert!(allocator.busy.load(Ordering::Relaxed));
+        allocator.busy.store(false, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+
+        let held: MutexGuard<'_, Buddy> = allocator.lock();
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+                unsafe {
+                    allocator.deallocate(ptr.as_non_null_ptr(), layout);
+                }
+            });
+            // The waiting call counts as in flight even though it can't take the lock yet
+            let start: std::time::Instant = std::time::Instant::now();
+            while allocator.in_flight() != 1 {
+                assert!(start.elapsed().as_secs() < 5, "allocate never started");
+                thread::yield_now();
+            }
+            drop(held);
+            waiter.join().unwrap();
+        });
+        assert_eq!(allocator.in_flight(), 0);
+
+        // Failed calls leave the gauge too
+        let too_large: Layout = Layout::from_size_align(1 << 62, 8).unwrap();
+        assert!(allocator.allocate(too_large).is_err());
+        assert_eq!(allocator.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_allocate_filled() {
+        assert_filled(&Locked::new(Buddy::new()));
+        assert_filled(&Locked::new(SegregatedFreeList::new()));
+        assert_filled(&Locked::new(SimpleSegregatedStorage::new()));
+    }
+
+    #[test]
+    fn test_shared_across_threads() {
+        let allocator: Shared<Buddy> = Shared::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+
+        let handles: Vec<thread::JoinHandle<()>> = (0..2)
+            .map(|_| {
+                let allocator: Shared<Buddy> = allocator.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+                        assert_eq!(ptr.len(), 64);
+                        unsafe {
+                            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Both threads should have shared a single region
+        let alloc: MutexGuard<'_, Buddy> = allocator.lock();
+        let (_, total_size, _): (usize, usize, f64) = alloc.calculate_allocation_ratio();
+        assert_eq!(total_size, 512);
+    }
+
+    #[test]
+    fn test_weak_stats_handle() {
+        let allocator: Shared<Buddy> = Shared::new(Buddy::new());
+        let handle: WeakStatsHandle<Buddy> = allocator.downgrade_stats();
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+
+        assert_eq!(handle.stats(), Some((64, 512, 0.125)));
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+
+        // The handle must not keep the allocator alive
+        drop(allocator);
+        assert_eq!(handle.stats(), None);
     }
 }
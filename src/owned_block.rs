@@ -0,0 +1,72 @@
+use std::alloc::{Allocator, Layout};
+use std::ptr::NonNull;
+
+// A block that must be handed back with release. Dropping it unreleased panics instead of freeing
+// silently, so tests catch the forgotten free at the point the block goes out of scope.
+pub struct OwnedBlock<'a, A: Allocator> {
+    allocator: &'a A,
+    block: NonNull<[u8]>,
+    layout: Layout,
+    released: bool,
+}
+
+impl<'a, A: Allocator> OwnedBlock<'a, A> {
+    pub fn new(allocator: &'a A, block: NonNull<[u8]>, layout: Layout) -> Self {
+        OwnedBlock {
+            allocator,
+            block,
+            layout,
+            released: false,
+        }
+    }
+
+    pub fn as_ptr(&self) -> NonNull<[u8]> {
+        self.block
+    }
+
+    // Deallocates the block, defusing the drop bomb
+    pub fn release(mut self) {
+        unsafe {
+            self.allocator
+                .deallocate(self.block.as_non_null_ptr(), self.layout);
+        }
+        self.released = true;
+    }
+}
+
+impl<A: Allocator> Drop for OwnedBlock<'_, A> {
+    fn drop(&mut self) {
+        // Don't turn an unrelated panic into an abort while unwinding
+        if !self.released && !std::thread::panicking() {
+            panic!(
+                "block {:#x} of {} bytes dropped without release",
+                self.block.addr().get(),
+                self.layout.size()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buddy::Buddy;
+    use crate::mutex::Locked;
+
+    #[test]
+    fn test_release() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+        let block: OwnedBlock<'_, Locked<Buddy>> = allocator.allocate_owned(layout).unwrap();
+        assert_eq!(block.as_ptr().len(), 32);
+        block.release();
+    }
+
+    #[test]
+    #[should_panic(expected = "dropped without release")]
+    fn test_drop_without_release() {
+        let allocator: Locked<Buddy> = Locked::new(Buddy::new());
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+        let _block: OwnedBlock<'_, Locked<Buddy>> = allocator.allocate_owned(layout).unwrap();
+    }
+}
@@ -0,0 +1,347 @@
+use std::alloc::{AllocError, Allocator, Layout, System};
+use std::num::NonZeroUsize;
+use std::ptr::NonNull;
+#[cfg(test)]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+#[cfg(any(test, feature = "global_fallback"))]
+use crate::mutex::{Lock, Locked};
+
+// Region base alignment used when an allocator is asked for page aligned regions
+pub const PAGE_SIZE: usize = 4096;
+
+// Node regions are acquired on unless an allocation asks for a specific one
+pub const DEFAULT_NODE: u8 = 0;
+
+// Where an allocator gets the regions it carves blocks from, System unless configured otherwise.
+// The node names the simulated NUMA node a region belongs to, sources without nodes ignore it.
+pub trait RegionSource {
+    fn acquire(&self, layout: Layout, node: u8) -> Result<NonNull<[u8]>, AllocError>;
+    unsafe fn release(&self, ptr: NonNull<u8>, layout: Layout);
+}
+
+// Size of each region in an allocator's region table, for checks that walk the table. Allocators
+// with one fixed region size pass it as is, those with varying sizes pass each region's layout.
+pub trait RegionSizes: Copy {
+    fn region_size(self, index: usize) -> usize;
+}
+
+impl RegionSizes for usize {
+    fn region_size(self, _index: usize) -> usize {
+        self
+    }
+}
+
+impl RegionSizes for &[Layout] {
+    fn region_size(self, index: usize) -> usize {
+        self[index].size()
+    }
+}
+
+// Whether the address lies in one of this allocator's regions, used to route deallocations
+#[cfg(any(test, feature = "global_fallback"))]
+pub trait Owns {
+    fn owns(&self, ptr: NonNull<u8>) -> bool;
+}
+
+#[cfg(any(test, feature = "global_fallback"))]
+impl<A: Owns> Owns for Locked<A> {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.lock().owns(ptr)
+    }
+}
+
+// Zero-size requests get no memory, only a dangling pointer aligned as requested. None for any
+// other size, which the allocator serves itself.
+pub fn zero_size_block(layout: Layout) -> Option<NonNull<[u8]>> {
+    if layout.size() != 0 {
+        return None;
+    }
+    let dangling: NonNull<u8> =
+        NonNull::without_provenance(NonZeroUsize::new(layout.align()).unwrap());
+    Some(NonNull::slice_from_raw_parts(dangling, 0))
+}
+
+// Writes a zero to the first byte of the region and of every page starting inside it, so the OS
+// commits its pages now instead of faulting them in on first use. Only for regions with no live
+// blocks, since the bytes are overwritten.
+pub fn prefault(region: NonNull<[u8]>) {
+    let start: usize = region.addr().get();
+    let mut offset: usize = 0;
+    while offset < region.len() {
+        unsafe {
+            region.as_mut_ptr().add(offset).write_volatile(0);
+        }
+        offset = (start + offset + 1).next_multiple_of(PAGE_SIZE) - start;
+    }
+}
+
+impl RegionSource for System {
+    fn acquire(&self, layout: Layout, _node: u8) -> Result<NonNull<[u8]>, AllocError> {
+        self.allocate(layout)
+    }
+
+    unsafe fn release(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.deallocate(ptr, layout)
+    }
+}
+
+// Shared sources let the caller keep a handle to a source an allocator owns
+impl<S: RegionSource> RegionSource for Arc<S> {
+    fn acquire(&self, layout: Layout, node: u8) -> Result<NonNull<[u8]>, AllocError> {
+        (**self).acquire(layout, node)
+    }
+
+    unsafe fn release(&self, ptr: NonNull<u8>, layout: Layout) {
+        (**self).release(ptr, layout)
+    }
+}
+
+// Source that refuses new regions while frozen, so a test can assert its code stays within the
+// regions already reserved. Allocators keep serving from free blocks they hold. Share it through
+// an Arc to freeze it while an allocator owns it.
+#[cfg(test)]
+pub struct Freezable<S: RegionSource = System> {
+    inner: S,
+    frozen: AtomicBool,
+}
+
+#[cfg(test)]
+impl<S: RegionSource> Freezable<S> {
+    pub fn new(inner: S) -> Self {
+        Freezable {
+            inner,
+            frozen: AtomicBool::new(false),
+        }
+    }
+
+    pub fn freeze(&self) {
+        self.frozen.store(true, Ordering::SeqCst);
+    }
+
+    pub fn unfreeze(&self) {
+        self.frozen.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl<S: RegionSource> RegionSource for Freezable<S> {
+    fn acquire(&self, layout: Layout, node: u8) -> Result<NonNull<[u8]>, AllocError> {
+        if self.frozen.load(Ordering::SeqCst) {
+            return Err(AllocError);
+        }
+        self.inner.acquire(layout, node)
+    }
+
+    unsafe fn release(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.inner.release(ptr, layout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buddy::Buddy;
+    use crate::mutex::{Lock, Locked};
+    use crate::segregated_free_list::SegregatedFreeList;
+    use crate::simple_segregated_storage::SimpleSegregatedStorage;
+    use crate::stats::MemStats;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::MutexGuard;
+
+    struct CountingSource {
+        acquired: Arc<AtomicUsize>,
+        released: Arc<AtomicUsize>,
+    }
+
+    impl RegionSource for CountingSource {
+        fn acquire(&self, layout: Layout, node: u8) -> Result<NonNull<[u8]>, AllocError> {
+            self.acquired.fetch_add(1, Ordering::SeqCst);
+            System.acquire(layout, node)
+        }
+
+        unsafe fn release(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.released.fetch_add(1, Ordering::SeqCst);
+            System.release(ptr, layout)
+        }
+    }
+
+    impl CountingSource {
+        fn new() -> (Self, Arc<AtomicUsize>) {
+            let released: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+            let source: CountingSource = CountingSource {
+                acquired: Arc::new(AtomicUsize::new(0)),
+                released: Arc::clone(&released),
+            };
+            (source, released)
+        }
+    }
+
+    // Takes one region, frees the block again and drops the allocator
+    fn allocate_and_drop<A>(allocator: Locked<A>)
+    where
+        Locked<A>: Allocator,
+    {
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+    }
+
+    fn check_zero_size_alignment<A: MemStats>(allocator: Locked<A>)
+    where
+        Locked<A>: Allocator,
+    {
+        for align in [1, 8, 64, 4096] {
+            let layout: Layout = Layout::from_size_align(0, align).unwrap();
+            let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+            assert_eq!(ptr.len(), 0);
+            assert!(ptr.as_mut_ptr().addr().is_multiple_of(align));
+            unsafe {
+                allocator.deallocate(ptr.as_non_null_ptr(), layout);
+            }
+
+            // No region is needed, and zero-size requests are left out of the counters
+            let alloc: MutexGuard<'_, A> = allocator.lock();
+            assert_eq!(alloc.calculate_allocation_ratio().1, 0);
+            assert_eq!((alloc.alloc_success(), alloc.alloc_failure()), (0, 0));
+            assert!(alloc.size_class_histogram().iter().all(|live| *live == 0));
+        }
+    }
+
+    #[test]
+    fn test_zero_size_alignment() {
+        assert_eq!(
+            zero_size_block(Layout::from_size_align(1, 1).unwrap()),
+            None
+        );
+        check_zero_size_alignment(Locked::new(Buddy::new()));
+        check_zero_size_alignment(Locked::new(SegregatedFreeList::new()));
+        check_zero_size_alignment(Locked::new(SimpleSegregatedStorage::new()));
+    }
+
+    #[test]
+    fn test_freeze() {
+        let source: Arc<Freezable> = Arc::new(Freezable::new(System));
+        let mut buddy: Buddy<Arc<Freezable>> = Buddy::with_source(Arc::clone(&source));
+        buddy.grow_pool(1).unwrap();
+        let allocator: Locked<Buddy<Arc<Freezable>>> = Locked::new(buddy);
+        let layout: Layout = Layout::from_size_align(256, 8).unwrap();
+
+        // The reserved region holds two blocks, the third needs a region the source won't give
+        source.freeze();
+        let first: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        let second: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert!(allocator.allocate(layout).is_err());
+
+        source.unfreeze();
+        let third: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(allocator.lock().extension_count(), 2);
+        for block in [first, second, third] {
+            unsafe {
+                allocator.deallocate(block.as_non_null_ptr(), layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_prefault() {
+        // Straddles a page boundary, so two bytes are touched
+        let layout: Layout = Layout::from_size_align(2 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let page: NonNull<[u8]> = System.acquire(layout, DEFAULT_NODE).unwrap();
+        unsafe {
+            page.as_mut_ptr().write_bytes(0xAA, page.len());
+            let region: NonNull<[u8]> =
+                NonNull::slice_from_raw_parts(page.as_non_null_ptr().add(PAGE_SIZE - 256), 512);
+            prefault(region);
+            let bytes: &[u8] = region.as_ref();
+            assert_eq!((bytes[0], bytes[256]), (0, 0));
+            assert_eq!(bytes.iter().filter(|byte| **byte == 0).count(), 2);
+            System.release(page.as_non_null_ptr(), layout);
+        }
+    }
+
+    #[test]
+    fn test_prefault_on_acquire() {
+        let layout: Layout = Layout::from_size_align(256, 8).unwrap();
+        let mut buddy: Buddy = Buddy::new();
+        buddy.set_prefault_on_acquire(true);
+        buddy.grow_pool(1).unwrap();
+        let mut free_list: SegregatedFreeList = SegregatedFreeList::new();
+        free_list.set_prefault_on_acquire(true);
+        let mut storage: SimpleSegregatedStorage = SimpleSegregatedStorage::new();
+        storage.set_prefault_on_acquire(true);
+        storage.grow_pool(1).unwrap();
+
+        let buddy: Locked<Buddy> = Locked::new(buddy);
+        let free_list: Locked<SegregatedFreeList> = Locked::new(free_list);
+        let storage: Locked<SimpleSegregatedStorage> = Locked::new(storage);
+        let allocators: [&dyn Allocator; 3] = [&buddy, &free_list, &storage];
+        for allocator in allocators {
+            let blocks: Vec<NonNull<[u8]>> = (0..4)
+                .map(|_| allocator.allocate(layout).unwrap())
+                .collect();
+            for block in blocks {
+                unsafe {
+                    block.as_mut_ptr().write_bytes(0xAA, block.len());
+                    allocator.deallocate(block.as_non_null_ptr(), layout);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_leak_on_drop() {
+        let (source, released) = CountingSource::new();
+        let mut buddy: Buddy<CountingSource> = Buddy::with_source(source);
+        buddy.set_leak_on_drop(true);
+        allocate_and_drop(Locked::new(buddy));
+        assert_eq!(released.load(Ordering::SeqCst), 0);
+
+        let (source, released) = CountingSource::new();
+        let mut free_list: SegregatedFreeList<CountingSource> =
+            SegregatedFreeList::with_source(source);
+        free_list.set_leak_on_drop(true);
+        allocate_and_drop(Locked::new(free_list));
+        assert_eq!(released.load(Ordering::SeqCst), 0);
+
+        let (source, released) = CountingSource::new();
+        let mut storage: SimpleSegregatedStorage<CountingSource> =
+            SimpleSegregatedStorage::with_source(source);
+        storage.set_leak_on_drop(true);
+        allocate_and_drop(Locked::new(storage));
+        assert_eq!(released.load(Ordering::SeqCst), 0);
+
+        // Without the flag the same sequence releases its region
+        let (source, released) = CountingSource::new();
+        allocate_and_drop(Locked::new(SimpleSegregatedStorage::with_source(source)));
+        assert_eq!(released.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_counting_source() {
+        let acquired: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+        let released: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+        let allocator: Locked<Buddy<CountingSource>> =
+            Locked::new(Buddy::with_source(CountingSource {
+                acquired: Arc::clone(&acquired),
+                released: Arc::clone(&released),
+            }));
+
+        let layout: Layout = Layout::from_size_align(512, 16).unwrap();
+        let first: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        let second: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(acquired.load(Ordering::SeqCst), 2);
+        assert_eq!(released.load(Ordering::SeqCst), 0);
+        unsafe {
+            allocator.deallocate(first.as_non_null_ptr(), layout);
+            allocator.deallocate(second.as_non_null_ptr(), layout);
+        }
+
+        // Regions go back to the source when the allocator is dropped
+        drop(allocator);
+        assert_eq!(released.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,171 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::collections::BTreeMap;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Backend {
+    First,
+    Second,
+}
+
+// Sends even numbered allocations to the first allocator and odd ones to the second. Each served
+// block is recorded by address range so deallocate goes back to the allocator that served it.
+// Zero-size blocks share dangling addresses across both allocators, so they always go to the
+// first one and are never recorded.
+pub struct RoundRobinAllocator<A, B> {
+    first: A,
+    second: B,
+    next: AtomicUsize,
+    served: Mutex<BTreeMap<usize, (usize, Backend)>>,
+}
+
+impl<A, B> RoundRobinAllocator<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        RoundRobinAllocator {
+            first,
+            second,
+            next: AtomicUsize::new(0),
+            served: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn first(&self) -> &A {
+        &self.first
+    }
+
+    pub fn second(&self) -> &B {
+        &self.second
+    }
+}
+
+unsafe impl<A: Allocator, B: Allocator> Allocator for RoundRobinAllocator<A, B> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            return self.first.allocate(layout);
+        }
+        let (backend, ptr): (Backend, NonNull<[u8]>) =
+            if self.next.fetch_add(1, Ordering::Relaxed).is_multiple_of(2) {
+                (Backend::First, self.first.allocate(layout)?)
+            } else {
+                (Backend::Second, self.second.allocate(layout)?)
+            };
+
+        self.served
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(ptr.addr().get(), (ptr.len(), backend));
+        Ok(ptr)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return self.first.deallocate(ptr, layout);
+        }
+        let address: usize = ptr.addr().get();
+        let backend: Backend = {
+            let mut served = self.served.lock().unwrap_or_else(PoisonError::into_inner);
+            let found: Option<(usize, usize, Backend)> = served
+                .range(..=address)
+                .next_back()
+                .map(|(start, (len, backend))| (*start, *len, *backend))
+                .filter(|(start, len, _)| address < start + len);
+            // A pointer neither allocator served can't be handed to either, so it is left alone
+            // rather than unwinding out of deallocate
+            let Some((start, len, backend)) = found else {
+                return;
+            };
+            debug_assert!(address + layout.size() <= start + len);
+            served.remove(&start);
+            backend
+        };
+
+        match backend {
+            Backend::First => self.first.deallocate(ptr, layout),
+            Backend::Second => self.second.deallocate(ptr, layout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buddy::Buddy;
+    use crate::mutex::{Lock, Locked};
+    use crate::simple_segregated_storage::SimpleSegregatedStorage;
+    use crate::stats::MemStats;
+
+    #[test]
+    fn test_zero_size_round_trip() {
+        let allocator: RoundRobinAllocator<Locked<Buddy>, Locked<SimpleSegregatedStorage>> =
+            RoundRobinAllocator::new(
+                Locked::new(Buddy::new()),
+                Locked::new(SimpleSegregatedStorage::new()),
+            );
+        let empty: Layout = Layout::from_size_align(0, 8).unwrap();
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+
+        // Both backends hand out the same dangling address for zero-size requests
+        let zero_sized: Vec<NonNull<[u8]>> =
+            (0..4).map(|_| allocator.allocate(empty).unwrap()).collect();
+        assert!(zero_sized
+            .iter()
+            .all(|ptr| ptr.addr() == zero_sized[0].addr()));
+        assert!(allocator.served.lock().unwrap().is_empty());
+
+        // Zero-size requests don't take a turn, so sized ones still alternate
+        let first: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        let zero: NonNull<[u8]> = allocator.allocate(empty).unwrap();
+        let second: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(allocator.first().lock().calculate_allocation_ratio().0, 64);
+        assert_eq!(allocator.second().lock().calculate_allocation_ratio().0, 64);
+
+        unsafe {
+            for ptr in zero_sized.iter().chain([&zero]) {
+                allocator.deallocate(ptr.as_non_null_ptr(), empty);
+            }
+            allocator.deallocate(first.as_non_null_ptr(), layout);
+            allocator.deallocate(second.as_non_null_ptr(), layout);
+        }
+        assert!(allocator.served.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let allocator: RoundRobinAllocator<Locked<Buddy>, Locked<SimpleSegregatedStorage>> =
+            RoundRobinAllocator::new(
+                Locked::new(Buddy::new()),
+                Locked::new(SimpleSegregatedStorage::new()),
+            );
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let ptrs: Vec<NonNull<[u8]>> = (0..4)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+
+        // Each backend should have served two 64 byte blocks
+        let (first_peak, _, _): (usize, usize, f64) =
+            allocator.first().lock().calculate_allocation_ratio();
+        let (second_peak, _, _): (usize, usize, f64) =
+            allocator.second().lock().calculate_allocation_ratio();
+        assert_eq!(first_peak, 128);
+        assert_eq!(second_peak, 128);
+        assert_eq!(
+            allocator
+                .served
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|(_, backend)| *backend == Backend::First)
+                .count(),
+            2
+        );
+
+        for ptr in ptrs.iter().rev() {
+            unsafe {
+                allocator.deallocate(ptr.as_non_null_ptr(), layout);
+            }
+        }
+        assert!(allocator.served.lock().unwrap().is_empty());
+    }
+}
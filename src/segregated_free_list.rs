@@ -1,11 +1,27 @@
 use std::alloc::{AllocError, Allocator, Layout, System};
 use std::collections::linked_list::CursorMut;
-use std::collections::LinkedList;
+use std::collections::{LinkedList, VecDeque};
 use std::ptr::NonNull;
 use std::sync::MutexGuard;
 
-use crate::mutex::{Lock, Locked};
-use crate::stats::MemStats;
+#[cfg(all(test, feature = "dump"))]
+use crate::dump::encode_dump;
+#[cfg(test)]
+use crate::invariants::list_first_block_twice;
+#[cfg(test)]
+use crate::invariants::{check_free_lists, repair_free_lists, FreeListInvariants};
+use crate::invariants::{validate_free, InvalidFreeHook, ScanBound, ValidationLevel};
+use crate::mutex::{InFlight, Lock, Locked};
+#[cfg(any(test, feature = "global_fallback"))]
+use crate::region_source::Owns;
+#[cfg(test)]
+use crate::region_source::PAGE_SIZE;
+use crate::region_source::{prefault, zero_size_block, RegionSource, DEFAULT_NODE};
+#[cfg(test)]
+use crate::snapshot::{AllocatorSnapshot, Snapshot};
+use crate::stats::{fragmented_share, MemStats};
+#[cfg(test)]
+use crate::stats::{free_distribution, list_overhead, region_occupancy};
 
 /*
     Segregated Free List Ranges (Bytes):
@@ -15,6 +31,7 @@ use crate::stats::MemStats;
     - (128,256]
     - (256,MAX_ALLOWED]
     * MAX_ALLOWED is arbitrary but can keep it at 512 for now, aligned at 16.
+    * with_classes(n) keeps only the first n ranges, with MAX_ALLOWED (and the region size) becoming 32 << (n - 1).
 
     Allocations:
     - First fit method.
@@ -28,102 +45,754 @@ use crate::stats::MemStats;
     - Add freed block to corresponding list
     - Go through all values to see if there are any smaller or larger blocks that are connected to current blocks start/end address
         - If yes, connect the blocks together and place resulting block in corresponding list
-    * Can also offer deferred coalescing where each freed block is placed on a queue and on the following allocations when going through queue,
-      can also check if block can be coalesced. This will trade off speed for external fragmentation
+    * with_deferred_coalescing() places each freed block on a queue instead, and each following allocation coalesces
+      a few queued blocks before searching the lists. This trades external fragmentation for cheaper frees
 
 
 */
 
-pub struct SegregatedFreeList {
-    lists: [LinkedList<NonNull<[u8]>>; 5],
+// Which free block an allocation is carved from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FitStrategy {
+    // First block that fits, searching upward from the request's class
+    FirstFit,
+    // Largest block that fits, searching downward from the last class, leaving fewer tiny leftovers
+    WorstFit,
+}
+
+// What to do with a request aligned beyond what a region can guarantee
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OversizedAlignPolicy {
+    // Refuse requests aligned beyond the region size with AllocError
+    Fail,
+    // Acquire a region aligned to the request whenever a new one is needed for it
+    GrowAligned,
+}
+
+pub struct SegregatedFreeList<S: RegionSource = System> {
+    source: S,
+    lists: Vec<LinkedList<NonNull<[u8]>>>,
+    // Size of each region, which is also the largest allocation the last class can serve
+    region_size: usize,
     allocated_first_byte: Vec<NonNull<u8>>,
-    total_size: f64,
-    peak_allocated_size: f64,
-    current_allocated_size: f64,
+    // Alignment each region was acquired with, needed again to release it
+    region_aligns: Vec<usize>,
+    total_size: usize,
+    peak_allocated_size: usize,
+    current_allocated_size: usize,
+    // Live allocations in each class, by the size they were requested with
+    class_live: Vec<u64>,
+    // allocate calls that returned a block, and those that returned AllocError
+    alloc_success: u64,
+    alloc_failure: u64,
+    extension_count: u64,
+    split_count: u64,
+    merge_count: u64,
+    // Bytes skipped in front of blocks to satisfy alignment, handed back as small free blocks
+    alignment_waste: usize,
+    // Called with the new peak under the allocator lock, so it must not allocate from this allocator
+    on_peak: Option<Box<dyn Fn(usize) + Send + Sync>>,
+    // Scrub blocks on free so their contents don't linger in reused memory
+    zero_on_free: bool,
+    validation: ValidationLevel,
+    on_invalid_free: Option<InvalidFreeHook>,
+    // Alignment requested for region bases, 16 unless regions should start on a page boundary
+    region_align: usize,
+    fit_strategy: FitStrategy,
+    oversized_align_policy: OversizedAlignPolicy,
+    // Leave regions unreleased on drop
+    leak_on_drop: bool,
+    // Touch every page of a region as soon as it is acquired
+    prefault_on_acquire: bool,
+    // Queue frees instead of coalescing them, merging a few on each later allocate
+    deferred_coalescing: bool,
+    // Freed blocks not yet merged into the lists, still counted as free in the stats
+    deferred_queue: VecDeque<NonNull<[u8]>>,
 }
 
+// Queued frees merged at the start of each allocate in deferred coalescing mode
+const DEFERRED_DRAIN_LIMIT: usize = 8;
+
+// Free blocks and the frees waiting in deferred_queue are all pieces of the regions in
+// allocated_first_byte. No other owner can reach them, so they change threads along with the lists.
+unsafe impl<S: RegionSource + Send> Send for SegregatedFreeList<S> {}
+// Blocks are split, merged and zeroed behind &mut self only, &self just reads their bounds
+unsafe impl<S: RegionSource + Sync> Sync for SegregatedFreeList<S> {}
+
 impl SegregatedFreeList {
     pub fn new() -> Self {
+        SegregatedFreeList::with_source(System)
+    }
+
+    #[cfg(test)]
+    pub fn with_classes(classes: usize) -> Self {
+        SegregatedFreeList::with_source_and_classes(System, classes)
+    }
+
+    #[cfg(test)]
+    pub fn with_zero_on_free(zero_on_free: bool) -> Self {
+        let mut allocator: SegregatedFreeList = SegregatedFreeList::new();
+        allocator.zero_on_free = zero_on_free;
+        allocator
+    }
+
+    #[cfg(test)]
+    pub fn with_on_peak(on_peak: Box<dyn Fn(usize) + Send + Sync>) -> Self {
+        let mut allocator: SegregatedFreeList = SegregatedFreeList::new();
+        allocator.on_peak = Some(on_peak);
+        allocator
+    }
+
+    #[cfg(test)]
+    pub fn with_page_aligned_regions(page_aligned: bool) -> Self {
+        let mut allocator: SegregatedFreeList = SegregatedFreeList::new();
+        allocator.region_align = if page_aligned { PAGE_SIZE } else { 16 };
+        allocator
+    }
+
+    #[cfg(test)]
+    pub fn with_fit_strategy(fit_strategy: FitStrategy) -> Self {
+        let mut allocator: SegregatedFreeList = SegregatedFreeList::new();
+        allocator.fit_strategy = fit_strategy;
+        allocator
+    }
+
+    // Frees are queued and coalesced a few at a time by later allocations, making deallocate cheap
+    // at the cost of more fragmentation while blocks wait on the queue
+    #[cfg(test)]
+    pub fn with_deferred_coalescing() -> Self {
+        let mut allocator: SegregatedFreeList = SegregatedFreeList::new();
+        allocator.deferred_coalescing = true;
+        allocator
+    }
+}
+
+impl<S: RegionSource> SegregatedFreeList<S> {
+    pub fn with_source(source: S) -> Self {
+        SegregatedFreeList::with_source_and_classes(source, 5)
+    }
+
+    // Classes keep doubling from (0,32], so n classes serve allocations up to 32 << (n - 1) bytes
+    pub fn with_source_and_classes(source: S, classes: usize) -> Self {
+        assert!(
+            (1..=5).contains(&classes),
+            "segregated free list supports 1 to 5 classes"
+        );
         SegregatedFreeList {
-            lists: [
-                LinkedList::new(),
-                LinkedList::new(),
-                LinkedList::new(),
-                LinkedList::new(),
-                LinkedList::new(),
-            ],
+            source,
+            lists: (0..classes).map(|_| LinkedList::new()).collect(),
+            region_size: 32 << (classes - 1),
             allocated_first_byte: Vec::new(),
-            total_size: 0.0,
-            peak_allocated_size: 0.0,
-            current_allocated_size: 0.0,
+            region_aligns: Vec::new(),
+            total_size: 0,
+            peak_allocated_size: 0,
+            current_allocated_size: 0,
+            class_live: vec![0; classes],
+            alloc_success: 0,
+            alloc_failure: 0,
+            extension_count: 0,
+            split_count: 0,
+            merge_count: 0,
+            alignment_waste: 0,
+            on_peak: None,
+            zero_on_free: false,
+            validation: ValidationLevel::DEFAULT,
+            on_invalid_free: None,
+            region_align: 16,
+            fit_strategy: FitStrategy::FirstFit,
+            oversized_align_policy: OversizedAlignPolicy::Fail,
+            leak_on_drop: false,
+            prefault_on_acquire: false,
+            deferred_coalescing: false,
+            deferred_queue: VecDeque::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn set_validation(&mut self, level: ValidationLevel) {
+        self.validation = level;
+    }
+
+    // Replaces aborting when a deallocate fails validation. The block is left alone either way.
+    #[cfg(test)]
+    pub fn set_on_invalid_free(&mut self, on_invalid_free: InvalidFreeHook) {
+        self.on_invalid_free = Some(on_invalid_free);
+    }
+
+    // Skip handing regions back on drop, leaving them for the OS to reclaim at exit. Saves the
+    // release calls when many short lived allocators are created and dropped.
+    #[cfg(test)]
+    pub fn set_leak_on_drop(&mut self, leak_on_drop: bool) {
+        self.leak_on_drop = leak_on_drop;
+    }
+
+    #[cfg(test)]
+    pub fn set_prefault_on_acquire(&mut self, prefault_on_acquire: bool) {
+        self.prefault_on_acquire = prefault_on_acquire;
+    }
+
+    #[cfg(test)]
+    pub fn set_oversized_align_policy(&mut self, policy: OversizedAlignPolicy) {
+        self.oversized_align_policy = policy;
+    }
+
+    // Largest request that can be served, set by the class count the list was built with
+    #[cfg(test)]
+    pub fn max_alloc_size(&self) -> usize {
+        self.region_size
+    }
+
+    // Bytes reserved for a layout. Blocks are split to the exact request size (0 if too large)
+    #[cfg(test)]
+    pub fn usable_size(&self, layout: &Layout) -> usize {
+        if layout.size() > self.region_size {
+            return 0;
+        }
+        layout.size()
+    }
+
+    fn class_index(&self, size: usize) -> usize {
+        let mut rounded_size: usize = 1;
+        let mut index: usize = 0;
+        let mut temp: usize = size - 1;
+        while temp != 0 {
+            temp >>= 1;
+            rounded_size <<= 1;
+            if rounded_size > 32 && index < self.lists.len() - 1 {
+                index += 1;
+            }
+        }
+        index
+    }
+
+    // Allocated fraction of each region, a region reading 0.0 is entirely free
+    #[cfg(test)]
+    pub fn region_occupancy(&self) -> Vec<f64> {
+        region_occupancy(&self.lists, &self.allocated_first_byte, self.region_size)
+    }
+
+    // Free blocks and bytes currently sitting in each class
+    #[cfg(test)]
+    pub fn free_distribution(&self) -> Vec<(usize, usize, usize)> {
+        free_distribution(&self.lists)
+    }
+
+    // Estimated bytes of bookkeeping, to compare against total_size
+    #[cfg(test)]
+    pub fn overhead_bytes(&self) -> usize {
+        list_overhead(&self.lists, self.allocated_first_byte.capacity())
+            + self.region_aligns.capacity() * size_of::<usize>()
+    }
+
+    // Serializes regions, free lists and stats, read back offline with dump::parse_dump
+    #[cfg(all(test, feature = "dump"))]
+    pub fn dump_state(&self) -> Vec<u8> {
+        encode_dump(
+            &self.allocated_first_byte,
+            &self.snapshot(),
+            self.total_size,
+        )
+    }
+
+    // Merges adjacent free blocks in the region holding addr, leaving every other region untouched.
+    // Cheaper than a full pass when a large block is needed right after a burst of frees.
+    #[cfg(test)]
+    pub fn coalesce_around(&mut self, addr: usize) {
+        let region_size: usize = self.region_size;
+        let region_start: Option<usize> = self
+            .allocated_first_byte
+            .iter()
+            .map(|region| region.addr().get())
+            .find(|start| *start <= addr && addr < start + region_size);
+        let region_start: usize = match region_start {
+            Some(start) => start,
+            None => return,
+        };
+
+        let mut blocks: Vec<NonNull<[u8]>> = Vec::new();
+        for list in &mut self.lists {
+            let mut cursor: CursorMut<'_, NonNull<[u8]>> = list.cursor_front_mut();
+            while let Some(free) = cursor.current() {
+                if region_start <= free.addr().get()
+                    && free.addr().get() < region_start + region_size
+                {
+                    blocks.push(cursor.remove_current().unwrap());
+                } else {
+                    cursor.move_next();
+                }
+            }
+        }
+
+        blocks.sort_by_key(|block| block.addr());
+        let mut merged: Vec<NonNull<[u8]>> = Vec::new();
+        for block in blocks {
+            match merged.last_mut() {
+                Some(last) if last.addr().get() + last.len() == block.addr().get() => {
+                    *last = NonNull::slice_from_raw_parts(
+                        last.as_non_null_ptr(),
+                        last.len() + block.len(),
+                    );
+                    self.merge_count += 1;
+                }
+                _ => merged.push(block),
+            }
+        }
+        for block in merged {
+            let index: usize = self.class_index(block.len());
+            self.lists[index].push_back(block);
+        }
+    }
+
+    // Acquires more regions and lists them as whole free blocks. Live blocks are never moved, so
+    // this is safe to call while allocations are outstanding.
+    #[cfg(test)]
+    pub fn grow_pool(&mut self, additional_regions: usize) -> Result<(), AllocError> {
+        let class_count: usize = self.lists.len();
+        for _ in 0..additional_regions {
+            let region: NonNull<[u8]> = self.source.acquire(
+                Layout::from_size_align(self.region_size, self.region_align).unwrap(),
+                DEFAULT_NODE,
+            )?;
+            if self.prefault_on_acquire {
+                prefault(region);
+            }
+            self.allocated_first_byte.push(region.as_non_null_ptr());
+            self.region_aligns.push(self.region_align);
+            self.lists[class_count - 1].push_back(region);
+            self.total_size += self.region_size;
+            self.extension_count += 1;
+        }
+        Ok(())
+    }
+
+    // Smallest free block in any class that could hold the request, as (address, length). Nothing is
+    // allocated, so tooling can compare it against the block allocate actually picks.
+    #[cfg(test)]
+    pub fn best_fit_candidate(&self, layout: &Layout) -> Option<(usize, usize)> {
+        self.lists
+            .iter()
+            .flatten()
+            .filter(|block| {
+                block.as_mut_ptr().align_offset(layout.align()) + layout.size() <= block.len()
+            })
+            .min_by_key(|block| block.len())
+            .map(|block| (block.addr().get(), block.len()))
+    }
+}
+
+impl<S: RegionSource> SegregatedFreeList<S> {
+    fn record_allocation(&mut self, size: usize) {
+        self.current_allocated_size += size;
+        let previous_peak: usize = self.peak_allocated_size;
+        self.peak_allocated_size =
+            usize::max(self.current_allocated_size, self.peak_allocated_size);
+        if self.peak_allocated_size > previous_peak {
+            if let Some(on_peak) = &self.on_peak {
+                on_peak(self.peak_allocated_size);
+            }
+        }
+        debug_assert!(self.peak_allocated_size <= self.total_size);
+    }
+
+    // Removes the first free block matching the predicate from whichever list holds it
+    fn take_free_where(
+        &mut self,
+        matches: impl Fn(&NonNull<[u8]>) -> bool,
+    ) -> Option<NonNull<[u8]>> {
+        for (index, list) in self.lists.iter_mut().enumerate() {
+            let mut bound: ScanBound = ScanBound::new(index, list.len());
+            let mut cursor: CursorMut<'_, NonNull<[u8]>> = list.cursor_front_mut();
+            while let Some(free) = cursor.current() {
+                bound.step();
+                if matches(free) {
+                    return cursor.remove_current();
+                }
+                cursor.move_next();
+            }
+        }
+        None
+    }
+
+    unsafe fn free_block(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        // Zero-size blocks were never taken from a region
+        if layout.size() == 0 {
+            return;
+        }
+
+        if !validate_free(
+            self.validation,
+            &self.on_invalid_free,
+            &self.lists,
+            &self.allocated_first_byte,
+            self.region_size,
+            NonNull::slice_from_raw_parts(ptr, layout.size()),
+        ) {
+            return;
+        }
+        // Blocks are split to exactly the request, so layout.size() already covers the whole block
+        if self.zero_on_free {
+            ptr.as_ptr().write_bytes(0, layout.size());
         }
+        self.current_allocated_size -= layout.size();
+        let index: usize = self.class_index(layout.size());
+        self.class_live[index] -= 1;
+
+        let block: NonNull<[u8]> = NonNull::slice_from_raw_parts(ptr, layout.size());
+        if self.deferred_coalescing {
+            self.deferred_queue.push_back(block);
+        } else {
+            self.coalesce_free(block);
+        }
+    }
+
+    // Merges queued frees into the lists, at most limit of them so no allocate pays for a whole
+    // burst of frees
+    fn drain_deferred(&mut self, limit: usize) {
+        for _ in 0..limit {
+            match self.deferred_queue.pop_front() {
+                Some(block) => self.coalesce_free(block),
+                None => return,
+            }
+        }
+    }
+
+    // Merges every queued free at once. Queued blocks next to each other are joined first, so each
+    // run searches the lists for its neighbors once instead of once per block.
+    #[cfg(test)]
+    fn coalesce_queued(&mut self) {
+        let mut queued: Vec<NonNull<[u8]>> = self.deferred_queue.drain(..).collect();
+        queued.sort_unstable_by_key(|block| block.addr().get());
+        let mut runs: Vec<NonNull<[u8]>> = Vec::new();
+        for block in queued {
+            let joins: bool = runs.last().is_some_and(|run| {
+                run.addr().get() + run.len() == block.addr().get()
+                    && self.region_start(run.addr().get()) == self.region_start(block.addr().get())
+            });
+            if joins {
+                let run: &mut NonNull<[u8]> = runs.last_mut().unwrap();
+                *run =
+                    NonNull::slice_from_raw_parts(run.as_non_null_ptr(), run.len() + block.len());
+                self.merge_count += 1;
+            } else {
+                runs.push(block);
+            }
+        }
+        for run in runs {
+            self.coalesce_free(run);
+        }
+    }
+
+    // Start of the region holding the address
+    fn region_start(&self, address: usize) -> Option<usize> {
+        self.allocated_first_byte
+            .iter()
+            .map(|region| region.addr().get())
+            .find(|start| *start <= address && address < start + self.region_size)
+    }
+
+    // Coalesce to a larger sized block. Always join to address 1 less than deallocated block to ensure sizing constraints
+    fn coalesce_free(&mut self, block: NonNull<[u8]>) {
+        let class_count: usize = self.lists.len();
+        let ptr: NonNull<u8> = block.as_non_null_ptr();
+
+        // A block spanning a whole region can't grow any further, so skip the neighbor search.
+        // Smaller blocks in the max class still coalesce to reduce fragmentation.
+        if block.len() == self.region_size {
+            self.lists[class_count - 1].push_back(block);
+            return;
+        }
+
+        // Neighbors on either side merge into the freed block, as long as they share its region
+        let block_start: usize = ptr.addr().get();
+        let block_end: usize = block_start + block.len();
+        let region_size: usize = self.region_size;
+        let region_start: Option<usize> = self.region_start(block_start);
+        let in_region = |free: &NonNull<[u8]>| {
+            region_start.is_some_and(|start| {
+                start <= free.addr().get() && free.addr().get() + free.len() <= start + region_size
+            })
+        };
+        let after: Option<NonNull<[u8]>> =
+            self.take_free_where(|free| free.addr().get() == block_end && in_region(free));
+        let before: Option<NonNull<[u8]>> = self.take_free_where(|free| {
+            free.addr().get() + free.len() == block_start && in_region(free)
+        });
+
+        let mut slice: NonNull<[u8]> = block;
+        if let Some(after) = after {
+            self.merge_count += 1;
+            slice = NonNull::slice_from_raw_parts(ptr, slice.len() + after.len());
+        }
+        if let Some(before) = before {
+            self.merge_count += 1;
+            slice =
+                NonNull::slice_from_raw_parts(before.as_non_null_ptr(), before.len() + slice.len());
+        }
+        // Store in corresponding list for future use
+        let size: usize = slice.len();
+        let mut rounded_size = 1;
+        let mut index: usize = 0;
+        let mut temp: usize = size - 1;
+        while temp != 0 {
+            temp >>= 1;
+            rounded_size <<= 1;
+            if rounded_size > 32 && index < class_count - 1 {
+                index += 1;
+            }
+        }
+        self.lists[index].push_back(slice);
     }
 }
 
-impl Drop for SegregatedFreeList {
+impl<S: RegionSource> Locked<SegregatedFreeList<S>> {
+    // Serves a block as large as the class's upper bound, 32 << class bytes, the last class's bound
+    // being the region size
+    #[cfg(test)]
+    pub fn allocate_from_class(&self, class: usize) -> Result<NonNull<[u8]>, AllocError> {
+        if class >= self.lock().lists.len() {
+            return Err(AllocError);
+        }
+        self.allocate(Layout::from_size_align(32 << class, 1).unwrap())
+    }
+
+    // Frees every item under a single lock instead of locking once per block. The blocks are
+    // queued as in deferred coalescing mode and merged in one pass at the end, or left queued if
+    // the allocator defers merging anyway.
+    #[cfg(test)]
+    pub unsafe fn deallocate_batch(&self, items: &[(NonNull<u8>, Layout)]) {
+        let mut alloc: MutexGuard<'_, SegregatedFreeList<S>> = self.lock();
+        let deferred: bool = alloc.deferred_coalescing;
+        alloc.deferred_coalescing = true;
+        for (ptr, layout) in items {
+            alloc.free_block(*ptr, *layout);
+        }
+        alloc.deferred_coalescing = deferred;
+        if !deferred {
+            alloc.coalesce_queued();
+        }
+    }
+}
+
+impl<S: RegionSource> Drop for SegregatedFreeList<S> {
     fn drop(&mut self) {
-        for byte in &self.allocated_first_byte {
+        if self.leak_on_drop {
+            return;
+        }
+        for (byte, align) in self.allocated_first_byte.iter().zip(&self.region_aligns) {
             unsafe {
-                System.deallocate(*byte, Layout::from_size_align_unchecked(512, 16));
+                self.source.release(
+                    *byte,
+                    Layout::from_size_align_unchecked(self.region_size, *align),
+                );
             }
         }
     }
 }
 
-impl MemStats for SegregatedFreeList {
-    fn calculate_allocation_ratio(&self) -> (f64, f64, f64) {
+#[cfg(any(test, feature = "global_fallback"))]
+impl<S: RegionSource> Owns for SegregatedFreeList<S> {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        let address: usize = ptr.addr().get();
+        self.allocated_first_byte.iter().any(|region| {
+            region.addr().get() <= address && address < region.addr().get() + self.region_size
+        })
+    }
+}
+
+#[cfg(test)]
+impl<S: RegionSource> FreeListInvariants for SegregatedFreeList<S> {
+    fn check_invariants(&self) -> Result<(), String> {
+        check_free_lists(&self.lists, &self.allocated_first_byte, self.region_size)
+    }
+
+    fn repair(&mut self) -> usize {
+        repair_free_lists(
+            &mut self.lists,
+            &self.allocated_first_byte,
+            self.region_size,
+        )
+    }
+
+    fn inject_overlap(&mut self) {
+        list_first_block_twice(&mut self.lists);
+    }
+}
+
+#[cfg(test)]
+impl<S: RegionSource> Snapshot for SegregatedFreeList<S> {
+    fn snapshot(&self) -> AllocatorSnapshot {
+        let mut snap: AllocatorSnapshot = AllocatorSnapshot::capture(
+            &self.lists,
+            self.peak_allocated_size,
+            self.current_allocated_size,
+            &self.class_live,
+        );
+        // Queued frees already count as free in current_allocated_size, so they are saved with it
+        snap.deferred = self.deferred_queue.iter().copied().collect();
+        snap
+    }
+
+    fn restore(&mut self, snap: &AllocatorSnapshot) {
+        debug_assert!(snap.within_regions(&self.allocated_first_byte, self.region_size));
+        snap.restore_lists(&mut self.lists, &mut self.class_live);
+        self.peak_allocated_size = snap.peak_allocated_size;
+        self.current_allocated_size = snap.current_allocated_size;
+        self.deferred_queue = snap.deferred.iter().copied().collect();
+    }
+}
+
+impl<S: RegionSource> MemStats for SegregatedFreeList<S> {
+    fn calculate_allocation_ratio(&self) -> (usize, usize, f64) {
         (
             self.peak_allocated_size,
             self.total_size,
-            self.peak_allocated_size / self.total_size,
+            self.peak_allocated_size as f64 / self.total_size as f64,
+        )
+    }
+
+    fn current_allocated_size(&self) -> usize {
+        self.current_allocated_size
+    }
+
+    fn extension_count(&self) -> u64 {
+        self.extension_count
+    }
+
+    fn alignment_waste(&self) -> usize {
+        self.alignment_waste
+    }
+
+    fn split_count(&self) -> u64 {
+        self.split_count
+    }
+
+    fn merge_count(&self) -> u64 {
+        self.merge_count
+    }
+
+    fn alloc_success(&self) -> u64 {
+        self.alloc_success
+    }
+
+    fn alloc_failure(&self) -> u64 {
+        self.alloc_failure
+    }
+
+    fn size_class_histogram(&self) -> Vec<u64> {
+        self.class_live.clone()
+    }
+
+    // Queued frees haven't been merged yet, so they count at the length they were freed with
+    fn external_fragmentation(&self) -> f64 {
+        fragmented_share(
+            self.lists
+                .iter()
+                .flatten()
+                .chain(&self.deferred_queue)
+                .map(|block| block.len()),
+            self.region_size,
         )
     }
 
     fn reset(&mut self) {
-        self.total_size = 0.0;
-        self.peak_allocated_size = 0.0;
-        self.current_allocated_size = 0.0;
-        for byte in &self.allocated_first_byte {
+        self.total_size = 0;
+        self.peak_allocated_size = 0;
+        self.current_allocated_size = 0;
+        self.class_live.fill(0);
+        self.alloc_success = 0;
+        self.alloc_failure = 0;
+        self.extension_count = 0;
+        self.split_count = 0;
+        self.merge_count = 0;
+        self.alignment_waste = 0;
+        for (byte, align) in self.allocated_first_byte.iter().zip(&self.region_aligns) {
             unsafe {
-                System.deallocate(*byte, Layout::from_size_align_unchecked(512, 16));
+                self.source.release(
+                    *byte,
+                    Layout::from_size_align_unchecked(self.region_size, *align),
+                );
             }
         }
         self.allocated_first_byte.clear();
+        self.region_aligns.clear();
+        self.deferred_queue.clear();
         for list in &mut self.lists {
             while list.pop_front().is_some() {}
         }
     }
+
+    fn reset_peak(&mut self) {
+        self.peak_allocated_size = self.current_allocated_size;
+    }
 }
 
-unsafe impl Allocator for Locked<SegregatedFreeList> {
+unsafe impl<S: RegionSource> Allocator for Locked<SegregatedFreeList<S>> {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let _in_flight: InFlight<'_> = self.enter_allocate();
+        if let Some(block) = zero_size_block(layout) {
+            return Ok(block);
+        }
+
         let mut rounded_size: usize = 1;
         let mut index: usize = 0;
-        let mut alloc: MutexGuard<'_, SegregatedFreeList> = self.lock();
+        let mut alloc: MutexGuard<'_, SegregatedFreeList<S>> = self.lock();
+        alloc.drain_deferred(DEFERRED_DRAIN_LIMIT);
+        let class_count: usize = alloc.lists.len();
+        let region_size: usize = alloc.region_size;
 
-        if layout.size() > 512 {
+        // No region can promise an alignment beyond its size unless it is acquired with it
+        let oversized_align: bool = layout.align() > region_size
+            && alloc.oversized_align_policy == OversizedAlignPolicy::Fail;
+        if layout.size() > region_size || oversized_align {
+            alloc.alloc_failure += 1;
             return Err(AllocError);
         } else {
             let mut temp: usize = layout.size() - 1;
             while temp != 0 {
                 temp >>= 1;
                 rounded_size <<= 1;
-                if rounded_size > 32 && index < 4 {
+                if rounded_size > 32 && index < class_count - 1 {
                     index += 1;
                 }
             }
         }
 
-        // Go through corresponding and following lists
         let mut allocated_node: Option<NonNull<[u8]>> = None;
-        while index < 5 && allocated_node.is_none() {
+        if alloc.fit_strategy == FitStrategy::WorstFit {
+            // Classes only grow in block size, so the first class from the top with a fit holds the largest
+            for list_index in (index..class_count).rev() {
+                let largest: Option<usize> = alloc.lists[list_index]
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, block)| {
+                        block.as_mut_ptr().align_offset(layout.align()) + layout.size()
+                            <= block.len()
+                    })
+                    .max_by_key(|(_, block)| block.len())
+                    .map(|(position, _)| position);
+                if let Some(position) = largest {
+                    let mut cursor: CursorMut<'_, NonNull<[u8]>> =
+                        alloc.lists[list_index].cursor_front_mut();
+                    for _ in 0..position {
+                        cursor.move_next();
+                    }
+                    allocated_node = cursor.remove_current();
+                    break;
+                }
+            }
+            index = class_count;
+        }
+
+        // Go through corresponding and following lists
+        while index < class_count && allocated_node.is_none() {
             if !alloc.lists[index].is_empty() {
                 let mut cursor: CursorMut<'_, NonNull<[u8]>> =
                     alloc.lists[index].cursor_front_mut();
                 while cursor.current().is_some() {
                     // check size of space vs size needed
                     let ptr = cursor.current().unwrap();
-                    if layout.size() <= ptr.len() {
+                    let padding: usize = ptr.as_mut_ptr().align_offset(layout.align());
+                    if padding + layout.size() <= ptr.len() {
                         allocated_node = cursor.remove_current();
                         break;
                     }
@@ -135,14 +804,35 @@ unsafe impl Allocator for Locked<SegregatedFreeList> {
 
         if allocated_node.is_none() {
             // need to expand heap
+            let mut region_align: usize = alloc.region_align;
+            if alloc.oversized_align_policy == OversizedAlignPolicy::GrowAligned {
+                region_align = usize::max(region_align, layout.align());
+            }
             unsafe {
-                let modified_layout: Layout = Layout::from_size_align_unchecked(512, 16);
-                let ptr: NonNull<[u8]> = System.allocate(modified_layout).unwrap();
+                let modified_layout: Layout =
+                    Layout::from_size_align_unchecked(region_size, region_align);
+                let ptr: NonNull<[u8]> = alloc
+                    .source
+                    .acquire(modified_layout, DEFAULT_NODE)
+                    .inspect_err(|_| alloc.alloc_failure += 1)?;
+                if alloc.prefault_on_acquire {
+                    prefault(ptr);
+                }
                 alloc
                     .allocated_first_byte
                     .push(NonNull::new_unchecked(ptr.as_mut_ptr()));
+                alloc.region_aligns.push(region_align);
                 allocated_node = Some(ptr);
-                alloc.total_size += 512.0;
+                alloc.total_size += region_size;
+                alloc.extension_count += 1;
+            }
+
+            // Regions are only aligned at 16, so larger alignments may not leave enough room
+            let region: NonNull<[u8]> = allocated_node.unwrap();
+            if region.as_mut_ptr().align_offset(layout.align()) + layout.size() > region_size {
+                alloc.lists[class_count - 1].push_back(region);
+                alloc.alloc_failure += 1;
+                return Err(AllocError);
             }
         }
 
@@ -150,6 +840,18 @@ unsafe impl Allocator for Locked<SegregatedFreeList> {
         unsafe {
             let raw_ptr: &[u8] = allocated_node.unwrap().as_ref();
             // let s: &[u8] = & *raw_ptr;
+
+            // Hand the bytes skipped for alignment back as their own free block
+            let padding: usize = raw_ptr.as_ptr().align_offset(layout.align());
+            let (prefix, raw_ptr): (&[u8], &[u8]) = raw_ptr.split_at(padding);
+            if padding > 0 {
+                let prefix_index: usize = alloc.class_index(padding);
+                alloc.lists[prefix_index]
+                    .push_back(NonNull::new_unchecked(prefix as *const [u8] as *mut [u8]));
+                alloc.alignment_waste += padding;
+                alloc.split_count += 1;
+            }
+
             let (allocated, remaining): (&[u8], &[u8]) = (raw_ptr).split_at(layout.size());
             // println!("{} {}", allocated.len(), remaining.len());
             let ret: NonNull<[u8]> = NonNull::new_unchecked(allocated as *const [u8] as *mut [u8]);
@@ -160,12 +862,13 @@ unsafe impl Allocator for Locked<SegregatedFreeList> {
             rounded_size = 1;
             index = 0;
             if remaining_size > 0 {
+                alloc.split_count += 1;
                 let mut temp: usize = remaining_size - 1;
                 while temp != 0 {
                     // println!("{} {} {} ", temp, rounded_size, index);
                     temp >>= 1;
                     rounded_size <<= 1;
-                    if rounded_size > 32 && index < 4 {
+                    if rounded_size > 32 && index < class_count - 1 {
                         index += 1;
                     }
                 }
@@ -173,67 +876,71 @@ unsafe impl Allocator for Locked<SegregatedFreeList> {
                     NonNull::new_unchecked(remaining as *const [u8] as *mut [u8]);
                 // println!("{}", index);
                 alloc.lists[index].push_back(rem);
-
-                // update allocation stats
-                alloc.current_allocated_size += layout.size() as f64;
-                alloc.peak_allocated_size =
-                    f64::max(alloc.current_allocated_size, alloc.peak_allocated_size);
             }
+
+            // update allocation stats, exact fits included
+            alloc.record_allocation(layout.size());
+            let class: usize = alloc.class_index(layout.size());
+            alloc.class_live[class] += 1;
+            alloc.alloc_success += 1;
             Ok(ret)
         }
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        // Coalesce to a larger sized block. Always join to address 1 less than deallocated block to ensure sizing constraints
-        let mut alloc: MutexGuard<'_, SegregatedFreeList> = self.lock();
-        let address_to_find: usize = ptr.addr().get() + layout.size();
-
-        let mut index: usize = 0;
-        let mut node_to_coalesce: Option<NonNull<[u8]>> = None;
+        self.lock().free_block(ptr, layout);
+    }
 
-        while index < 5 && node_to_coalesce.is_none() {
-            if !alloc.lists[index].is_empty() {
-                let mut cursor: CursorMut<'_, NonNull<[u8]>> =
-                    alloc.lists[index].cursor_front_mut();
-                while cursor.current().is_some() {
-                    // check size of space vs size needed
-                    let curr = cursor.current().unwrap();
-                    // println!("curr: {}", curr.addr().get());
-                    if address_to_find == curr.addr().get() {
-                        node_to_coalesce = cursor.remove_current();
-                        break;
-                    }
-                    cursor.move_next();
+    // Extends the block into the free block right after it when that one is large enough, handing
+    // back whatever it doesn't need. Otherwise falls back to allocate, copy and free.
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if old_layout.size() != 0 && ptr.addr().get().is_multiple_of(new_layout.align()) {
+            let mut alloc: MutexGuard<'_, SegregatedFreeList<S>> = self.lock();
+            let block_end: usize = ptr.addr().get() + old_layout.size();
+            let needed: usize = new_layout.size() - old_layout.size();
+            let region_size: usize = alloc.region_size;
+            let region_start: Option<usize> = alloc
+                .allocated_first_byte
+                .iter()
+                .map(|region| region.addr().get())
+                .find(|start| *start <= ptr.addr().get() && ptr.addr().get() < start + region_size);
+            let next: Option<NonNull<[u8]>> = alloc.take_free_where(|free| {
+                free.addr().get() == block_end
+                    && free.len() >= needed
+                    && region_start
+                        .is_some_and(|start| free.addr().get() + free.len() <= start + region_size)
+            });
+            if let Some(next) = next {
+                alloc.merge_count += 1;
+                if next.len() > needed {
+                    let rest: NonNull<[u8]> = NonNull::slice_from_raw_parts(
+                        next.as_non_null_ptr().add(needed),
+                        next.len() - needed,
+                    );
+                    let index: usize = alloc.class_index(rest.len());
+                    alloc.lists[index].push_back(rest);
+                    alloc.split_count += 1;
                 }
+                alloc.record_allocation(needed);
+                let (old_class, new_class): (usize, usize) = (
+                    alloc.class_index(old_layout.size()),
+                    alloc.class_index(new_layout.size()),
+                );
+                alloc.class_live[old_class] -= 1;
+                alloc.class_live[new_class] += 1;
+                return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
             }
-            index += 1;
-        }
-
-        let mut slice: NonNull<[u8]> = NonNull::slice_from_raw_parts(ptr, layout.size());
-
-        if node_to_coalesce.is_some() {
-            // let to_append: &[u8] = &*node_to_coalesce.unwrap().as_ptr();
-            // vec.extend_from_slice(to_append);
-            // slice = vec.as_mut_slice();
-            slice =
-                NonNull::slice_from_raw_parts(ptr, layout.size() + node_to_coalesce.unwrap().len());
         }
-        node_to_coalesce = Some(slice);
 
-        // Store in corresponding list for future use
-        let size: usize = node_to_coalesce.unwrap().len();
-        let mut rounded_size = 1;
-        index = 0;
-        let mut temp: usize = size - 1;
-        while temp != 0 {
-            temp >>= 1;
-            rounded_size <<= 1;
-            if rounded_size > 32 && index < 4 {
-                index += 1;
-            }
-        }
-        alloc.lists[index].push_back(node_to_coalesce.unwrap());
-        alloc.current_allocated_size -= layout.size() as f64;
+        let new_ptr: NonNull<[u8]> = self.allocate(new_layout)?;
+        std::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_mut_ptr(), old_layout.size());
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
     }
 }
 
@@ -242,6 +949,264 @@ mod tests {
     use super::*;
     use std::sync::Mutex;
 
+    #[test]
+    fn test_external_fragmentation() {
+        let allocator: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        assert_eq!(allocator.lock().external_fragmentation(), 0.0);
+
+        let blocks: Vec<NonNull<[u8]>> = (0..8)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+        for block in blocks.iter().step_by(2) {
+            unsafe {
+                allocator.deallocate(block.as_non_null_ptr(), layout);
+            }
+        }
+        // Half the region is free, but only in 64 byte holes between live blocks
+        assert_eq!(allocator.lock().external_fragmentation(), 1.0);
+
+        for block in blocks.iter().skip(1).step_by(2) {
+            unsafe {
+                allocator.deallocate(block.as_non_null_ptr(), layout);
+            }
+        }
+        assert_eq!(allocator.lock().external_fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn test_grow_in_place() {
+        let allocator: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
+        let old: Layout = Layout::from_size_align(64, 8).unwrap();
+        let new: Layout = Layout::from_size_align(200, 8).unwrap();
+        // The rest of the region sits free right after the block
+        let ptr: NonNull<[u8]> = allocator.allocate_filled(old, 0x7E).unwrap();
+        let grown: NonNull<[u8]> =
+            unsafe { allocator.grow(ptr.as_non_null_ptr(), old, new).unwrap() };
+        assert_eq!(grown.as_mut_ptr(), ptr.as_mut_ptr());
+        assert_eq!(grown.len(), 200);
+        unsafe {
+            assert!(grown.as_ref()[..64].iter().all(|byte| *byte == 0x7E));
+        }
+        {
+            let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+            assert_eq!(alloc.current_allocated_size, 200);
+            let free: Vec<&NonNull<[u8]>> = alloc.lists.iter().flatten().collect();
+            assert_eq!(free.len(), 1);
+            assert_eq!(free[0].addr().get(), ptr.addr().get() + 200);
+            assert_eq!(free[0].len(), 512 - 200);
+        }
+
+        // With the next block taken the block has to move
+        let blocker: NonNull<[u8]> = allocator
+            .allocate(Layout::from_size_align(8, 8).unwrap())
+            .unwrap();
+        assert_eq!(blocker.addr().get(), ptr.addr().get() + 200);
+        let larger: Layout = Layout::from_size_align(256, 8).unwrap();
+        let moved: NonNull<[u8]> = unsafe {
+            allocator
+                .grow(grown.as_non_null_ptr(), new, larger)
+                .unwrap()
+        };
+        assert_ne!(moved.as_mut_ptr(), ptr.as_mut_ptr());
+    }
+
+    #[test]
+    fn test_deferred_coalescing() {
+        let allocator: Locked<SegregatedFreeList> =
+            Locked::new(SegregatedFreeList::with_deferred_coalescing());
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let blocks: Vec<NonNull<[u8]>> = (0..8)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+        unsafe {
+            for block in &blocks {
+                allocator.deallocate(block.as_non_null_ptr(), layout);
+            }
+        }
+        {
+            let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+            assert_eq!(alloc.deferred_queue.len(), 8);
+            assert!(alloc.lists.iter().all(|list| list.is_empty()));
+            assert_eq!(alloc.merge_count, 0);
+            assert_eq!(alloc.current_allocated_size, 0);
+        }
+
+        // The next allocate merges the queue back into the whole region before searching
+        let whole: Layout = Layout::from_size_align(512, 8).unwrap();
+        let region: NonNull<[u8]> = allocator.allocate(whole).unwrap();
+        let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+        assert!(alloc.deferred_queue.is_empty());
+        assert_eq!(alloc.merge_count, 7);
+        assert_eq!(alloc.extension_count, 1);
+        assert_eq!(region.as_mut_ptr(), alloc.allocated_first_byte[0].as_ptr());
+    }
+
+    #[test]
+    fn test_snapshot_keeps_deferred_frees() {
+        let allocator: Locked<SegregatedFreeList> =
+            Locked::new(SegregatedFreeList::with_deferred_coalescing());
+        let layout: Layout = Layout::from_size_align(256, 8).unwrap();
+        let freed: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        let kept: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        unsafe {
+            allocator.deallocate(freed.as_non_null_ptr(), layout);
+        }
+        let snap: AllocatorSnapshot = allocator.lock().snapshot();
+        assert_eq!(snap.deferred.len(), 1);
+
+        // Frees after the snapshot are undone by restoring it, the one queued before comes back
+        unsafe {
+            allocator.deallocate(kept.as_non_null_ptr(), layout);
+        }
+        allocator.lock().restore(&snap);
+        {
+            let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+            assert_eq!(alloc.deferred_queue.len(), 1);
+            assert_eq!(alloc.deferred_queue[0].as_mut_ptr(), freed.as_mut_ptr());
+            assert_eq!(alloc.current_allocated_size, 256);
+        }
+
+        // Once kept is freed again the queue merges back into the whole region
+        unsafe {
+            allocator.deallocate(kept.as_non_null_ptr(), layout);
+        }
+        let region: NonNull<[u8]> = allocator
+            .allocate(Layout::from_size_align(512, 8).unwrap())
+            .unwrap();
+        let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+        assert_eq!(region.as_mut_ptr(), alloc.allocated_first_byte[0].as_ptr());
+        assert_eq!(alloc.extension_count, 1);
+    }
+
+    #[test]
+    fn test_coalesce_both_neighbors() {
+        let allocator: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let blocks: Vec<NonNull<[u8]>> = (0..3)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+        // Keep the tail of the region allocated so only the three blocks can merge
+        let tail: Layout = Layout::from_size_align(512 - 3 * 64, 8).unwrap();
+        allocator.allocate(tail).unwrap();
+        assert!(allocator.lock().lists.iter().all(|list| list.is_empty()));
+
+        // The outer blocks have no free neighbor until the middle one joins them
+        unsafe {
+            allocator.deallocate(blocks[0].as_non_null_ptr(), layout);
+            allocator.deallocate(blocks[2].as_non_null_ptr(), layout);
+            allocator.deallocate(blocks[1].as_non_null_ptr(), layout);
+        }
+        let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+        let free: Vec<&NonNull<[u8]>> = alloc.lists.iter().flatten().collect();
+        assert_eq!(free.len(), 1);
+        assert_eq!(free[0].as_mut_ptr(), blocks[0].as_mut_ptr());
+        assert_eq!(free[0].len(), 3 * 64);
+        assert_eq!(alloc.merge_count, 2);
+    }
+
+    #[test]
+    fn test_exact_fit_counted() {
+        let allocator: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
+        // A whole region leaves no remainder
+        let whole: Layout = Layout::from_size_align(512, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(whole).unwrap();
+        {
+            let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+            assert_eq!(alloc.current_allocated_size, 512);
+            assert_eq!(alloc.calculate_allocation_ratio(), (512, 512, 1.0));
+        }
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), whole);
+        }
+        assert_eq!(allocator.lock().current_allocated_size, 0);
+    }
+
+    #[test]
+    fn test_allocate_from_class() {
+        let allocator: Locked<SegregatedFreeList> =
+            Locked::new(SegregatedFreeList::with_classes(3));
+        assert_eq!(allocator.allocate_from_class(0).unwrap().len(), 32);
+        assert_eq!(allocator.allocate_from_class(2).unwrap().len(), 128);
+        assert!(allocator.allocate_from_class(3).is_err());
+    }
+
+    #[test]
+    fn test_oversized_align_policy() {
+        let layout: Layout = Layout::from_size_align(64, 1024).unwrap();
+
+        let allocator: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
+        assert_eq!(allocator.allocate(layout), Err(AllocError));
+        assert_eq!(allocator.lock().extension_count(), 0);
+
+        let allocator: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
+        allocator
+            .lock()
+            .set_oversized_align_policy(OversizedAlignPolicy::GrowAligned);
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert!(ptr.as_mut_ptr().addr().is_multiple_of(1024));
+        assert_eq!(allocator.lock().region_aligns, vec![1024]);
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+    }
+
+    #[test]
+    fn test_best_fit_candidate() {
+        let allocator: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
+        let region: NonNull<[u8]> = allocator
+            .allocate(Layout::from_size_align(512, 16).unwrap())
+            .unwrap();
+
+        // Carve the region into free blocks of 320, 64 and 128 bytes, listed largest first
+        let mut alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+        let base: *mut u8 = region.as_mut_ptr();
+        for (offset, len) in [(192, 320), (0, 64), (64, 128)] {
+            let block: NonNull<[u8]> = unsafe {
+                NonNull::slice_from_raw_parts(NonNull::new(base.add(offset)).unwrap(), len)
+            };
+            let index: usize = alloc.class_index(len);
+            alloc.lists[index].push_back(block);
+        }
+
+        let base: usize = base.addr();
+        let layout: Layout = Layout::from_size_align(100, 8).unwrap();
+        assert_eq!(alloc.best_fit_candidate(&layout), Some((base + 64, 128)));
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        assert_eq!(alloc.best_fit_candidate(&layout), Some((base, 64)));
+        let layout: Layout = Layout::from_size_align(400, 8).unwrap();
+        assert_eq!(alloc.best_fit_candidate(&layout), None);
+    }
+
+    #[test]
+    fn test_coalesce_around() {
+        let allocator: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
+        let layout: Layout = Layout::from_size_align(128, 8).unwrap();
+        let blocks: Vec<NonNull<[u8]>> = (0..8)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+
+        // Deallocate merges neighbors itself, so list the blocks as free by hand to leave them split
+        let mut alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+        let class: usize = alloc.class_index(128);
+        for block in &blocks {
+            alloc.lists[class].push_back(*block);
+        }
+        alloc.current_allocated_size = 0;
+        let max_class: usize = alloc.lists.len() - 1;
+        assert!(alloc.lists[max_class].is_empty());
+
+        let first_region: usize = alloc.allocated_first_byte[0].addr().get();
+        alloc.coalesce_around(first_region);
+        assert_eq!(alloc.lists[max_class].len(), 1);
+        assert_eq!(
+            alloc.lists[max_class].front().unwrap().addr().get(),
+            first_region
+        );
+        // The second region's blocks are still separate
+        assert_eq!(alloc.lists.iter().flatten().count(), 1 + 4);
+    }
+
     #[test]
     fn test_allocate_fail() {
         let allocator: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
@@ -325,6 +1290,226 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_deallocate_max_class() {
+        let allocator: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
+        let layout: Layout = Layout::from_size_align(512, 16).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+
+        let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+        assert!(alloc.lists.iter().all(|list| list.is_empty()));
+        let allocated_size: usize = alloc.current_allocated_size;
+        Mutex::unlock(alloc);
+
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+
+        let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+        assert_eq!(alloc.lists[4].len(), 1);
+        assert_eq!(alloc.lists[4].front().unwrap().len(), 512);
+        assert_eq!(
+            alloc.lists[4].front().unwrap().as_mut_ptr(),
+            ptr.as_mut_ptr()
+        );
+        assert_eq!(allocated_size, 512);
+        assert_eq!(alloc.current_allocated_size, 0);
+        assert_eq!(alloc.total_size, 512);
+    }
+
+    #[test]
+    fn test_usable_size() {
+        let allocator: SegregatedFreeList = SegregatedFreeList::new();
+        assert_eq!(
+            allocator.usable_size(&Layout::from_size_align(100, 8).unwrap()),
+            100
+        );
+        assert_eq!(
+            allocator.usable_size(&Layout::from_size_align(1024, 8).unwrap()),
+            0
+        );
+    }
+
+    #[test]
+    fn test_with_classes() {
+        let allocator: Locked<SegregatedFreeList> =
+            Locked::new(SegregatedFreeList::with_classes(2));
+        let failing_layout: Layout = Layout::from_size_align(65, 8).unwrap();
+        assert_eq!(allocator.allocate(failing_layout), Err(AllocError));
+
+        let layout: Layout = Layout::from_size_align(40, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(ptr.len(), 40);
+
+        let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+        assert_eq!(alloc.lists.len(), 2);
+        assert_eq!(alloc.lists[0].len(), 1);
+        assert_eq!(alloc.lists[0].front().unwrap().len(), 24);
+        assert_eq!(alloc.total_size, 64);
+        Mutex::unlock(alloc);
+
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+        let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+        assert_eq!(alloc.lists[1].len(), 1);
+        assert_eq!(alloc.lists[1].front().unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_deallocate_batch() {
+        let layout: Layout = Layout::from_size_align(24, 8).unwrap();
+        let single: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
+        let batched: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
+        let single_ptrs: Vec<NonNull<[u8]>> =
+            (0..10).map(|_| single.allocate(layout).unwrap()).collect();
+        let batched_items: Vec<(NonNull<u8>, Layout)> = (0..10)
+            .map(|_| (batched.allocate(layout).unwrap().as_non_null_ptr(), layout))
+            .collect();
+
+        for ptr in &single_ptrs {
+            unsafe {
+                single.deallocate(ptr.as_non_null_ptr(), layout);
+            }
+        }
+        unsafe {
+            batched.deallocate_batch(&batched_items);
+        }
+
+        let single: MutexGuard<'_, SegregatedFreeList> = single.lock();
+        let batched: MutexGuard<'_, SegregatedFreeList> = batched.lock();
+        let lengths = |alloc: &SegregatedFreeList| -> Vec<Vec<usize>> {
+            alloc
+                .lists
+                .iter()
+                .map(|list| list.iter().map(|block| block.len()).collect())
+                .collect()
+        };
+        assert_eq!(lengths(&batched), lengths(&single));
+        assert_eq!(
+            batched.current_allocated_size,
+            single.current_allocated_size
+        );
+        assert_eq!(
+            batched.calculate_allocation_ratio(),
+            single.calculate_allocation_ratio()
+        );
+        assert_eq!(batched.merge_count, single.merge_count);
+        assert!(batched.deferred_queue.is_empty());
+    }
+
+    #[test]
+    fn test_max_alloc_size() {
+        assert_eq!(SegregatedFreeList::new().max_alloc_size(), 512);
+
+        let allocator: Locked<SegregatedFreeList> =
+            Locked::new(SegregatedFreeList::with_classes(3));
+        assert_eq!(allocator.lock().max_alloc_size(), 128);
+        assert!(allocator
+            .allocate(Layout::from_size_align(129, 8).unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_zero_on_free() {
+        let allocator: Locked<SegregatedFreeList> =
+            Locked::new(SegregatedFreeList::with_zero_on_free(true));
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate_filled(layout, 0x5A).unwrap();
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+
+        let reused: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(reused.as_mut_ptr(), ptr.as_mut_ptr());
+        unsafe {
+            assert!(reused.as_ref()[..64].iter().all(|byte| *byte == 0));
+        }
+
+        // A size off any power of 2 is still handed out and scrubbed as exactly its own bytes
+        let odd: Layout = Layout::from_size_align(33, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate_filled(odd, 0x5A).unwrap();
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), odd);
+        }
+        let reused: NonNull<[u8]> = allocator.allocate(odd).unwrap();
+        assert_eq!(reused.as_mut_ptr(), ptr.as_mut_ptr());
+        unsafe {
+            assert!(reused.as_ref().iter().all(|byte| *byte == 0));
+        }
+    }
+
+    // Hands out regions that are 16 aligned but never 64 aligned
+    struct Offset16Source;
+
+    impl RegionSource for Offset16Source {
+        fn acquire(&self, layout: Layout, node: u8) -> Result<NonNull<[u8]>, AllocError> {
+            let padded: Layout = Layout::from_size_align(layout.size() + 64, 64).unwrap();
+            let ptr: NonNull<[u8]> = System.acquire(padded, node)?;
+            unsafe {
+                Ok(NonNull::slice_from_raw_parts(
+                    ptr.as_non_null_ptr().add(16),
+                    layout.size(),
+                ))
+            }
+        }
+
+        unsafe fn release(&self, ptr: NonNull<u8>, layout: Layout) {
+            let padded: Layout = Layout::from_size_align(layout.size() + 64, 64).unwrap();
+            System.release(ptr.sub(16), padded)
+        }
+    }
+
+    #[test]
+    fn test_alignment_waste() {
+        let allocator: Locked<SegregatedFreeList<Offset16Source>> =
+            Locked::new(SegregatedFreeList::with_source(Offset16Source));
+        let layout: Layout = Layout::from_size_align(64, 64).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+
+        assert_eq!(ptr.as_mut_ptr().align_offset(64), 0);
+        let alloc: MutexGuard<'_, SegregatedFreeList<Offset16Source>> = allocator.lock();
+        assert_eq!(alloc.alignment_waste(), 48);
+        assert_eq!(alloc.lists[1].len(), 1);
+        assert_eq!(alloc.lists[1].front().unwrap().len(), 48);
+        assert_eq!(alloc.lists[4].front().unwrap().len(), 512 - 48 - 64);
+    }
+
+    #[test]
+    fn test_worst_fit() {
+        let allocator: Locked<SegregatedFreeList> =
+            Locked::new(SegregatedFreeList::with_fit_strategy(FitStrategy::WorstFit));
+        let layout: Layout = Layout::from_size_align(512, 16).unwrap();
+        let regions: Vec<NonNull<[u8]>> = (0..3)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+
+        // Hand-made free blocks of 64, 256 and 512 bytes
+        let mut alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+        alloc.lists[1].push_back(NonNull::slice_from_raw_parts(
+            regions[0].as_non_null_ptr(),
+            64,
+        ));
+        alloc.lists[3].push_back(NonNull::slice_from_raw_parts(
+            regions[1].as_non_null_ptr(),
+            256,
+        ));
+        alloc.lists[4].push_back(regions[2]);
+        // The listed bytes are free as far as the stats go too
+        alloc.current_allocated_size -= 64 + 256 + 512;
+        Mutex::unlock(alloc);
+
+        let ptr: NonNull<[u8]> = allocator
+            .allocate(Layout::from_size_align(32, 8).unwrap())
+            .unwrap();
+        assert_eq!(ptr.as_mut_ptr(), regions[2].as_mut_ptr());
+
+        let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
+        assert_eq!(alloc.lists[1].len(), 1);
+        assert_eq!(alloc.lists[3].len(), 1);
+        assert_eq!(alloc.lists[4].front().unwrap().len(), 512 - 32);
+    }
+
     #[test]
     fn test_allocation_stats() {
         let allocator: Locked<SegregatedFreeList> = Locked::new(SegregatedFreeList::new());
@@ -343,8 +1528,8 @@ mod tests {
         let _ = allocator.allocate(layout).unwrap();
 
         let alloc: MutexGuard<'_, SegregatedFreeList> = allocator.lock();
-        assert_eq!(alloc.total_size, 512 as f64);
-        assert_eq!(alloc.peak_allocated_size, 384 as f64);
-        assert_eq!(alloc.current_allocated_size, 288 as f64);
+        assert_eq!(alloc.total_size, 512);
+        assert_eq!(alloc.peak_allocated_size, 384);
+        assert_eq!(alloc.current_allocated_size, 288);
     }
 }
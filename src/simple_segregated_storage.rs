@@ -1,23 +1,156 @@
 use std::alloc::{AllocError, Allocator, Layout, System};
-use std::collections::LinkedList;
+use std::collections::{LinkedList, VecDeque};
 use std::ptr::NonNull;
+#[cfg(test)]
 use std::sync::MutexGuard;
 
-use crate::mutex::{Lock, Locked};
+#[cfg(all(test, feature = "dump"))]
+use crate::dump::encode_dump;
+#[cfg(test)]
+use crate::invariants::list_first_block_twice;
+#[cfg(test)]
+use crate::invariants::{check_free_lists, repair_free_lists, FreeListInvariants};
+use crate::invariants::{report_invalid_free, validate_free, InvalidFreeHook, ValidationLevel};
+use crate::mutex::{ExclusiveAllocator, InFlight, Lock, Locked};
+#[cfg(any(test, feature = "global_fallback"))]
+use crate::region_source::Owns;
+#[cfg(test)]
+use crate::region_source::PAGE_SIZE;
+use crate::region_source::{prefault, zero_size_block, RegionSource, DEFAULT_NODE};
+#[cfg(test)]
+use crate::snapshot::{AllocatorSnapshot, Snapshot};
+
+use crate::stats::{fragmented_share, MemStats};
+#[cfg(test)]
+use crate::stats::{free_distribution, list_overhead, region_occupancy};
+
+// Size class for every request size up to 512, i.e. the power of 2 each size rounds up to
+pub(crate) const CLASS_TABLE: [u8; 513] = build_class_table();
 
-use crate::stats::MemStats;
+const fn build_class_table() -> [u8; 513] {
+    let mut table: [u8; 513] = [0; 513];
+    let mut size: usize = 1;
+    while size <= 512 {
+        table[size] = size.next_power_of_two().trailing_zeros() as u8;
+        size += 1;
+    }
+    table
+}
+
+// Written over quarantined blocks so a dangling access reads an obvious pattern
+pub const QUARANTINE_POISON: u8 = 0xDE;
+
+// Stamped over the first bytes of listed free blocks when canaries are on
+pub const CANARY: u64 = 0x5AFE_C0DE_5AFE_C0DE;
 
-pub struct SimpleSegregatedStorage {
+pub struct SimpleSegregatedStorage<S: RegionSource = System> {
+    source: S,
     lists: [LinkedList<NonNull<[u8]>>; 10],
     allocated_first_byte: Vec<NonNull<u8>>,
-    total_size: f64,
-    peak_allocated_size: f64,
-    current_allocated_size: f64,
+    // Node each region was acquired on, in the same order as allocated_first_byte
+    region_nodes: Vec<u8>,
+    // Bytes handed out and not yet freed from each region, in the same order as allocated_first_byte
+    region_live: Vec<usize>,
+    total_size: usize,
+    peak_allocated_size: usize,
+    current_allocated_size: usize,
+    // Live allocations in each class, a span from allocate_contiguous counting once per block
+    class_live: [u64; 10],
+    // allocate calls that returned a block, and those that returned AllocError
+    alloc_success: u64,
+    alloc_failure: u64,
+    extension_count: u64,
+    // Called with the new peak under the allocator lock, so it must not allocate from this allocator
+    on_peak: Option<Box<dyn Fn(usize) + Send + Sync>>,
+    // Scrub blocks on free so their contents don't linger in reused memory
+    zero_on_free: bool,
+    // Stamp free blocks with CANARY and check it when they are handed out again, catching writes
+    // past the end of the block before them
+    canary: bool,
+    // Carve one block at a time from a class's newest region instead of splitting it up front
+    lazy_split: bool,
+    validation: ValidationLevel,
+    on_invalid_free: Option<InvalidFreeHook>,
+    // Alignment requested for region bases, 16 unless regions should start on a page boundary
+    region_align: usize,
+    // Hand out slices of exactly the requested length, the full block is still reserved
+    trim_to_request: bool,
+    // Per class, the part of its newest region that has not been carved into blocks yet
+    uncarved: [Option<NonNull<[u8]>>; 10],
+    // Fully free regions taken back from their class by rebalance, reused before acquiring more
+    spare_regions: Vec<NonNull<u8>>,
+    // Number of later frees a freed block waits through before it can be reused
+    quarantine_depth: usize,
+    // Freed blocks waiting to return to their free list, oldest first
+    quarantine: VecDeque<NonNull<[u8]>>,
+    // Leave regions unreleased on drop
+    leak_on_drop: bool,
+    // Touch every page of a region as soon as it is acquired
+    prefault_on_acquire: bool,
+    // Hand a region back to the source as soon as its last live block is freed
+    release_empty_regions: bool,
 }
 
+// Besides the free lists, the uncarved tails, spare regions and quarantined blocks all sit in
+// regions this allocator acquired and hands back itself, none of them shared with another owner
+unsafe impl<S: RegionSource + Send> Send for SimpleSegregatedStorage<S> {}
+// check_canary reads free blocks through &self, but every write to a block or a list needs
+// &mut self
+unsafe impl<S: RegionSource + Sync> Sync for SimpleSegregatedStorage<S> {}
+
 impl SimpleSegregatedStorage {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
+        SimpleSegregatedStorage::with_source(System)
+    }
+
+    #[cfg(test)]
+    pub fn with_zero_on_free(zero_on_free: bool) -> Self {
+        let mut allocator: SimpleSegregatedStorage = SimpleSegregatedStorage::new();
+        allocator.zero_on_free = zero_on_free;
+        allocator
+    }
+
+    #[cfg(test)]
+    pub fn with_canary(canary: bool) -> Self {
+        let mut allocator: SimpleSegregatedStorage = SimpleSegregatedStorage::new();
+        allocator.canary = canary;
+        allocator
+    }
+
+    #[cfg(test)]
+    pub fn with_quarantine(quarantine_depth: usize) -> Self {
+        let mut allocator: SimpleSegregatedStorage = SimpleSegregatedStorage::new();
+        allocator.quarantine_depth = quarantine_depth;
+        allocator
+    }
+
+    #[cfg(test)]
+    pub fn with_on_peak(on_peak: Box<dyn Fn(usize) + Send + Sync>) -> Self {
+        let mut allocator: SimpleSegregatedStorage = SimpleSegregatedStorage::new();
+        allocator.on_peak = Some(on_peak);
+        allocator
+    }
+
+    #[cfg(test)]
+    pub fn with_page_aligned_regions(page_aligned: bool) -> Self {
+        let mut allocator: SimpleSegregatedStorage = SimpleSegregatedStorage::new();
+        allocator.region_align = if page_aligned { PAGE_SIZE } else { 16 };
+        allocator
+    }
+
+    #[cfg(test)]
+    pub fn with_lazy_split(lazy_split: bool) -> Self {
+        let mut allocator: SimpleSegregatedStorage = SimpleSegregatedStorage::new();
+        allocator.lazy_split = lazy_split;
+        allocator
+    }
+}
+
+impl<S: RegionSource> SimpleSegregatedStorage<S> {
+    // const so it can back a global allocator static
+    pub const fn with_source(source: S) -> Self {
         SimpleSegregatedStorage {
+            source,
             lists: [
                 LinkedList::new(),
                 LinkedList::new(),
@@ -31,160 +164,1606 @@ impl SimpleSegregatedStorage {
                 LinkedList::new(),
             ],
             allocated_first_byte: Vec::new(),
-            total_size: 0.0,
-            peak_allocated_size: 0.0,
-            current_allocated_size: 0.0,
+            region_nodes: Vec::new(),
+            region_live: Vec::new(),
+            total_size: 0,
+            peak_allocated_size: 0,
+            current_allocated_size: 0,
+            class_live: [0; 10],
+            alloc_success: 0,
+            alloc_failure: 0,
+            extension_count: 0,
+            on_peak: None,
+            zero_on_free: false,
+            canary: false,
+            lazy_split: false,
+            validation: ValidationLevel::DEFAULT,
+            on_invalid_free: None,
+            region_align: 16,
+            trim_to_request: false,
+            uncarved: [None; 10],
+            spare_regions: Vec::new(),
+            quarantine_depth: 0,
+            quarantine: VecDeque::new(),
+            leak_on_drop: false,
+            prefault_on_acquire: false,
+            release_empty_regions: false,
         }
     }
-}
 
-impl MemStats for SimpleSegregatedStorage {
-    fn calculate_allocation_ratio(&self) -> (f64, f64, f64) {
-        (
-            self.peak_allocated_size,
-            self.total_size,
-            self.peak_allocated_size / self.total_size,
-        )
+    fn acquire_region(&mut self, node: u8) -> Result<NonNull<[u8]>, AllocError> {
+        let spare: Option<usize> = self
+            .spare_regions
+            .iter()
+            .position(|region| self.node_of(region.addr().get()) == Some(node));
+        if let Some(position) = spare {
+            let region: NonNull<u8> = self.spare_regions.swap_remove(position);
+            return Ok(NonNull::slice_from_raw_parts(region, 512));
+        }
+
+        let modified_layout: Layout =
+            unsafe { Layout::from_size_align_unchecked(512, self.region_align) };
+        let ptr: NonNull<[u8]> = self.source.acquire(modified_layout, node)?;
+        if self.prefault_on_acquire {
+            prefault(ptr);
+        }
+        self.allocated_first_byte.push(ptr.as_non_null_ptr());
+        self.region_nodes.push(node);
+        self.region_live.push(0);
+
+        // Increment total size due to new allocation
+        self.total_size += 512;
+        self.extension_count += 1;
+        Ok(ptr)
     }
 
-    fn reset(&mut self) {
-        self.total_size = 0.0;
-        self.peak_allocated_size = 0.0;
-        self.current_allocated_size = 0.0;
-        for byte in &self.allocated_first_byte {
+    // Puts a block on its class's free list, stamping it first when canaries are on
+    fn push_free(&mut self, class_index: usize, block: NonNull<[u8]>) {
+        if self.canary {
+            let stamp: usize = usize::min(block.len(), size_of::<u64>());
             unsafe {
-                System.deallocate(*byte, Layout::from_size_align_unchecked(512, 16));
+                block
+                    .as_mut_ptr()
+                    .copy_from_nonoverlapping(CANARY.to_ne_bytes().as_ptr(), stamp);
             }
         }
-        self.allocated_first_byte.clear();
-        for list in &mut self.lists {
-            while list.pop_front().is_some() {}
+        self.lists[class_index].push_back(block);
+    }
+
+    // Panics if the block taken off a free list no longer carries its canary
+    fn check_canary(&self, block: NonNull<[u8]>) {
+        if !self.canary {
+            return;
         }
+        let stamp: usize = usize::min(block.len(), size_of::<u64>());
+        let found: &[u8] = unsafe { std::slice::from_raw_parts(block.as_mut_ptr(), stamp) };
+        assert!(
+            found == &CANARY.to_ne_bytes()[..stamp],
+            "canary of free block {:#x} was overwritten, the block before it overflowed",
+            block.addr().get()
+        );
     }
-}
 
-impl Drop for SimpleSegregatedStorage {
-    fn drop(&mut self) {
-        for byte in &self.allocated_first_byte {
+    // Eagerly splits new regions into blocks of the given class instead of waiting for a request
+    pub fn prepare_class(&mut self, class_index: usize, regions: usize) -> Result<(), AllocError> {
+        if class_index >= self.lists.len() {
+            return Err(AllocError);
+        }
+        let block_size: usize = 1 << class_index;
+        for _ in 0..regions {
+            let ptr: NonNull<[u8]> = self.acquire_region(DEFAULT_NODE)?;
+            unsafe {
+                let raw_ptr: *mut [u8] = ptr.as_ptr();
+                let chunks = (*raw_ptr).chunks_exact_mut(block_size);
+                for chunk in chunks {
+                    self.push_free(class_index, NonNull::new_unchecked(chunk as *mut [u8]));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Takes the next block off the class's uncarved tail, starting a new region once it runs out
+    fn carve_block(&mut self, class_index: usize) -> Result<NonNull<[u8]>, AllocError> {
+        let block_size: usize = 1 << class_index;
+        let tail: NonNull<[u8]> = match self.uncarved[class_index].take() {
+            Some(tail) => tail,
+            None => self.acquire_region(DEFAULT_NODE)?,
+        };
+        if tail.len() > block_size {
             unsafe {
-                System.deallocate(*byte, Layout::from_size_align_unchecked(512, 16));
+                self.uncarved[class_index] = Some(NonNull::slice_from_raw_parts(
+                    NonNull::new_unchecked(tail.as_mut_ptr().add(block_size)),
+                    tail.len() - block_size,
+                ));
             }
         }
+        Ok(NonNull::slice_from_raw_parts(
+            tail.as_non_null_ptr(),
+            block_size,
+        ))
+    }
+
+    // Pulls every region whose blocks are all free out of its class so the next class that runs
+    // dry re-splits it instead of acquiring a new region. Returns the number of regions reclaimed.
+    #[cfg(test)]
+    pub fn rebalance(&mut self) -> usize {
+        let mut free_bytes: Vec<usize> = vec![0; self.allocated_first_byte.len()];
+        for block in self.lists.iter().flatten() {
+            let start: usize = self.region_of(block.addr().get()).unwrap();
+            let region: usize = self
+                .allocated_first_byte
+                .iter()
+                .position(|byte| byte.addr().get() == start)
+                .unwrap();
+            free_bytes[region] += block.len();
+        }
+
+        let reclaimed: Vec<NonNull<u8>> = self
+            .allocated_first_byte
+            .iter()
+            .zip(&free_bytes)
+            .filter(|(_, free)| **free == 512)
+            .map(|(byte, _)| *byte)
+            .collect();
         for list in &mut self.lists {
-            while list.pop_front().is_some() {}
+            let blocks: LinkedList<NonNull<[u8]>> = std::mem::take(list);
+            *list = blocks
+                .into_iter()
+                .filter(|block| {
+                    !reclaimed.iter().any(|region| {
+                        let start: usize = region.addr().get();
+                        start <= block.addr().get() && block.addr().get() < start + 512
+                    })
+                })
+                .collect();
+        }
+
+        self.spare_regions.extend(&reclaimed);
+        reclaimed.len()
+    }
+
+    // Hands the whole region containing ptr back to the source at once, dropping every free block
+    // carved from it. The caller guarantees no block of the region is still allocated.
+    pub fn free_region_of(&mut self, ptr: NonNull<u8>) {
+        let start: usize = match self.region_of(ptr.addr().get()) {
+            Some(start) => start,
+            None => return,
+        };
+        let in_region =
+            |block: &NonNull<[u8]>| start <= block.addr().get() && block.addr().get() < start + 512;
+
+        let mut free_bytes: usize = 0;
+        for list in &mut self.lists {
+            let blocks: LinkedList<NonNull<[u8]>> = std::mem::take(list);
+            for block in blocks {
+                if in_region(&block) {
+                    free_bytes += block.len();
+                } else {
+                    list.push_back(block);
+                }
+            }
+        }
+        for tail in &mut self.uncarved {
+            if tail.is_some_and(|tail| in_region(&tail)) {
+                free_bytes += tail.take().unwrap().len();
+            }
+        }
+        free_bytes += self
+            .quarantine
+            .iter()
+            .filter(|block| in_region(block))
+            .map(|block| block.len())
+            .sum::<usize>();
+        self.quarantine.retain(|block| !in_region(block));
+        if let Some(spare) = self
+            .spare_regions
+            .iter()
+            .position(|region| region.addr().get() == start)
+        {
+            self.spare_regions.swap_remove(spare);
+            free_bytes = 512;
+        }
+        debug_assert_eq!(free_bytes, 512, "region {:#x} still has live blocks", start);
+
+        let position: usize = self
+            .allocated_first_byte
+            .iter()
+            .position(|region| region.addr().get() == start)
+            .unwrap();
+        let region: NonNull<u8> = self.allocated_first_byte.remove(position);
+        self.region_nodes.remove(position);
+        self.region_live.remove(position);
+        unsafe {
+            self.source.release(
+                region,
+                Layout::from_size_align_unchecked(512, self.region_align),
+            );
         }
+        self.total_size -= 512;
+        // The peak may have been reached with this region's blocks, but it can't exceed what is
+        // still owned
+        self.peak_allocated_size = usize::min(self.peak_allocated_size, self.total_size);
     }
-}
 
-unsafe impl Allocator for Locked<SimpleSegregatedStorage> {
-    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        // Round up allocation to nearest power of 2. Options are 1B, 2B, 4B, 8B, 16B, 32B, 64B, 128B, 256B, 512B
-        let mut alloc: MutexGuard<'_, SimpleSegregatedStorage> = self.lock();
-        let mut rounded_size: usize = 1;
-        let mut index: usize = 0;
+    // Releases every region with no allocated or quarantined block, leaving partially used
+    // regions alone. Returns the number of regions released.
+    #[cfg(test)]
+    pub fn consolidate(&mut self) -> usize {
+        let free_regions: Vec<NonNull<u8>> = self
+            .allocated_first_byte
+            .iter()
+            .copied()
+            .filter(|region| {
+                let start: usize = region.addr().get();
+                if self.spare_regions.contains(region) {
+                    return true;
+                }
+                let in_region = |block: &NonNull<[u8]>| {
+                    start <= block.addr().get() && block.addr().get() < start + 512
+                };
+                let listed: usize = self
+                    .lists
+                    .iter()
+                    .flatten()
+                    .filter(|block| in_region(block))
+                    .map(|block| block.len())
+                    .sum();
+                let uncarved: usize = self
+                    .uncarved
+                    .iter()
+                    .flatten()
+                    .filter(|tail| in_region(tail))
+                    .map(|tail| tail.len())
+                    .sum();
+                listed + uncarved == 512
+            })
+            .collect();
+        for region in &free_regions {
+            self.free_region_of(*region);
+        }
+        free_regions.len()
+    }
 
-        if layout.size() > 512 {
+    // The allocate path behind both allocate and allocate_on_node. With a node, only blocks from
+    // that node's regions are taken, and a new region is acquired on it.
+    fn allocate_block(
+        &mut self,
+        layout: Layout,
+        node: Option<u8>,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() > 512 || layout.align() > self.guaranteed_alignment(&layout) {
+            self.alloc_failure += 1;
             return Err(AllocError);
-        } else {
-            let mut temp: usize = layout.size() - 1;
-            while temp != 0 {
-                temp >>= 1;
-                rounded_size <<= 1;
-                index += 1;
+        }
+        let index: usize = CLASS_TABLE[layout.size()] as usize;
+        let block: NonNull<[u8]> = match node {
+            None => self.take_block(index),
+            Some(node) => self.take_block_on_node(index, node),
+        }
+        .inspect_err(|_| self.alloc_failure += 1)?;
+
+        // update allocation stats
+        self.record_allocation(block, index);
+        self.alloc_success += 1;
+        Ok(self.returned_slice(block, layout.size()))
+    }
+
+    fn take_block(&mut self, index: usize) -> Result<NonNull<[u8]>, AllocError> {
+        if self.lists[index].is_empty() {
+            if self.lazy_split {
+                return self.carve_block(index);
+            }
+            self.prepare_class(index, 1)?;
+        }
+        let block: NonNull<[u8]> = self.lists[index].pop_front().unwrap();
+        self.check_canary(block);
+        Ok(block)
+    }
+
+    fn take_block_on_node(&mut self, index: usize, node: u8) -> Result<NonNull<[u8]>, AllocError> {
+        let position: Option<usize> = self.lists[index]
+            .iter()
+            .position(|block| self.node_of(block.addr().get()) == Some(node));
+        if let Some(position) = position {
+            let mut cursor = self.lists[index].cursor_front_mut();
+            for _ in 0..position {
+                cursor.move_next();
             }
+            let block: NonNull<[u8]> = cursor.remove_current().unwrap();
+            self.check_canary(block);
+            return Ok(block);
         }
 
+        let region: NonNull<[u8]> = self.acquire_region(node)?;
         unsafe {
-            let modified_layout: Layout = Layout::from_size_align_unchecked(512, 16);
-            if alloc.lists[index].is_empty() {
-                let ptr: NonNull<[u8]> = System.allocate(modified_layout).unwrap();
-                alloc
-                    .allocated_first_byte
-                    .push(NonNull::new_unchecked(ptr.as_mut_ptr()));
-                let raw_ptr: *mut [u8] = ptr.as_ptr();
-                let chunks = (*raw_ptr).chunks_exact_mut(rounded_size);
-                for chunk in chunks {
-                    alloc.lists[index].push_back(NonNull::new_unchecked(chunk as *mut [u8]));
-                }
+            let mut chunks = (*region.as_ptr()).chunks_exact_mut(1 << index);
+            let first: NonNull<[u8]> = NonNull::from(chunks.next().unwrap());
+            for chunk in chunks {
+                self.push_free(index, NonNull::from(chunk));
+            }
+            Ok(first)
+        }
+    }
 
-                // Increment total size due to new allocation
-                alloc.total_size += 512.0;
+    fn record_allocation(&mut self, block: NonNull<[u8]>, class: usize) {
+        let size: usize = block.len();
+        self.class_live[class] += (size >> class) as u64;
+        if let Some(region) = self.region_index(block.addr().get()) {
+            self.region_live[region] += size;
+        }
+        self.current_allocated_size += size;
+        let previous_peak: usize = self.peak_allocated_size;
+        self.peak_allocated_size =
+            usize::max(self.current_allocated_size, self.peak_allocated_size);
+        if self.peak_allocated_size > previous_peak {
+            if let Some(on_peak) = &self.on_peak {
+                on_peak(self.peak_allocated_size);
             }
+        }
+        debug_assert!(self.peak_allocated_size <= self.total_size);
+    }
 
-            // update allocation stats
-            alloc.current_allocated_size += rounded_size as f64;
-            alloc.peak_allocated_size =
-                f64::max(alloc.current_allocated_size, alloc.peak_allocated_size);
+    fn node_of(&self, address: usize) -> Option<u8> {
+        self.allocated_first_byte
+            .iter()
+            .position(|byte| byte.addr().get() <= address && address < byte.addr().get() + 512)
+            .map(|position| self.region_nodes[position])
+    }
 
-            Ok(alloc.lists[index].pop_front().unwrap())
+    // Takes a freed block off the usage counts, returning whether its region has no live block left
+    fn record_free(&mut self, block: NonNull<[u8]>, class: usize) -> bool {
+        self.current_allocated_size -= block.len();
+        self.class_live[class] -= (block.len() >> class) as u64;
+        match self.region_index(block.addr().get()) {
+            Some(region) => {
+                self.region_live[region] -= block.len();
+                self.region_live[region] == 0
+            }
+            None => false,
         }
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        let mut alloc: MutexGuard<'_, SimpleSegregatedStorage> = self.lock();
-        let mut rounded_size: usize = 1;
-        let mut index: usize = 0;
+    fn region_index(&self, address: usize) -> Option<usize> {
+        self.allocated_first_byte
+            .iter()
+            .position(|byte| byte.addr().get() <= address && address < byte.addr().get() + 512)
+    }
 
-        if layout.size() > 512 {
-            return;
+    fn region_of(&self, address: usize) -> Option<usize> {
+        self.allocated_first_byte
+            .iter()
+            .map(|byte| byte.addr().get())
+            .find(|start| *start <= address && address < start + 512)
+    }
+
+    #[cfg(test)]
+    pub fn set_validation(&mut self, level: ValidationLevel) {
+        self.validation = level;
+    }
+
+    // Replaces aborting when a deallocate fails validation. The block is left alone either way.
+    #[cfg(test)]
+    pub fn set_on_invalid_free(&mut self, on_invalid_free: InvalidFreeHook) {
+        self.on_invalid_free = Some(on_invalid_free);
+    }
+
+    // Skip handing regions back on drop, leaving them for the OS to reclaim at exit. Saves the
+    // release calls when many short lived allocators are created and dropped.
+    #[cfg(test)]
+    pub fn set_leak_on_drop(&mut self, leak_on_drop: bool) {
+        self.leak_on_drop = leak_on_drop;
+    }
+
+    // Release each region from deallocate once every block carved from it is free again, so usage
+    // spikes don't hold on to memory until drop. A class that keeps allocating and freeing a
+    // single block will acquire and release a region each time.
+    #[cfg(test)]
+    pub fn set_release_empty_regions(&mut self, release_empty_regions: bool) {
+        self.release_empty_regions = release_empty_regions;
+    }
+
+    #[cfg(test)]
+    pub fn set_prefault_on_acquire(&mut self, prefault_on_acquire: bool) {
+        self.prefault_on_acquire = prefault_on_acquire;
+    }
+
+    #[cfg(test)]
+    pub fn set_trim_to_request(&mut self, trim_to_request: bool) {
+        self.trim_to_request = trim_to_request;
+    }
+
+    // Alignment every block served for the layout has. Blocks sit at multiples of their size from
+    // a region base, and region bases are only aligned to region_align.
+    pub fn guaranteed_alignment(&self, layout: &Layout) -> usize {
+        usize::min(layout.size().next_power_of_two(), self.region_align)
+    }
+
+    // The slice allocate returns for a block, cut down to the request when trimming
+    fn returned_slice(&self, block: NonNull<[u8]>, size: usize) -> NonNull<[u8]> {
+        let layout: Layout = Layout::from_size_align(size, 1).unwrap();
+        debug_assert!(block
+            .addr()
+            .get()
+            .is_multiple_of(self.guaranteed_alignment(&layout)));
+        if self.trim_to_request {
+            NonNull::slice_from_raw_parts(block.as_non_null_ptr(), size)
         } else {
-            let mut temp: usize = layout.size() - 1;
-            while temp != 0 {
-                temp >>= 1;
-                rounded_size <<= 1;
-                index += 1;
+            block
+        }
+    }
+
+    // Largest request that can be served, the top class
+    #[cfg(test)]
+    pub fn max_alloc_size(&self) -> usize {
+        512
+    }
+
+    // Bytes reserved for a layout, the block size of its class (0 if too large)
+    #[cfg(test)]
+    pub fn usable_size(&self, layout: &Layout) -> usize {
+        if layout.size() > 512 {
+            return 0;
+        }
+        1 << CLASS_TABLE[layout.size()]
+    }
+
+    // Allocated fraction of each region, a region reading 0.0 is entirely free
+    #[cfg(test)]
+    pub fn region_occupancy(&self) -> Vec<f64> {
+        region_occupancy(&self.lists, &self.allocated_first_byte, 512)
+    }
+
+    // Free blocks and bytes currently sitting in each class
+    #[cfg(test)]
+    pub fn free_distribution(&self) -> Vec<(usize, usize, usize)> {
+        free_distribution(&self.lists)
+    }
+
+    // Estimated bytes of bookkeeping, to compare against total_size
+    #[cfg(test)]
+    pub fn overhead_bytes(&self) -> usize {
+        list_overhead(&self.lists, self.allocated_first_byte.capacity())
+            + self.region_nodes.capacity()
+            + self.spare_regions.capacity() * size_of::<NonNull<u8>>()
+            + self.quarantine.capacity() * size_of::<NonNull<[u8]>>()
+    }
+
+    // Serializes regions, free lists and stats, read back offline with dump::parse_dump
+    #[cfg(all(test, feature = "dump"))]
+    pub fn dump_state(&self) -> Vec<u8> {
+        encode_dump(
+            &self.allocated_first_byte,
+            &self.snapshot(),
+            self.total_size,
+        )
+    }
+
+    // Acquires more regions up front. They wait as spare regions until a class runs out of blocks,
+    // so live blocks are never touched and this is safe to call mid-operation.
+    #[cfg(test)]
+    pub fn grow_pool(&mut self, additional_regions: usize) -> Result<(), AllocError> {
+        for _ in 0..additional_regions {
+            let region: NonNull<[u8]> = self.source.acquire(
+                Layout::from_size_align(512, self.region_align).unwrap(),
+                DEFAULT_NODE,
+            )?;
+            if self.prefault_on_acquire {
+                prefault(region);
             }
+            self.allocated_first_byte.push(region.as_non_null_ptr());
+            self.region_nodes.push(DEFAULT_NODE);
+            self.region_live.push(0);
+            self.spare_regions.push(region.as_non_null_ptr());
+            self.total_size += 512;
+            self.extension_count += 1;
         }
+        Ok(())
+    }
+}
 
-        // let mut vec: Vec<u8> = Vec::new();
-        // for i in 0..rounded_size {
-        //     vec.push(*(ptr.as_ptr().add(i)));
-        // }
-        // let slice: &mut [u8] = &mut vec.as_mut_slice();
-        let slice: NonNull<[u8]> = NonNull::slice_from_raw_parts(ptr, layout.size());
+impl<S: RegionSource> Locked<SimpleSegregatedStorage<S>> {
+    // Serves a 1 << class byte block straight from that class, refilling it like any request would
+    #[cfg(test)]
+    pub fn allocate_from_class(&self, class: usize) -> Result<NonNull<[u8]>, AllocError> {
+        if class >= self.lock().lists.len() {
+            return Err(AllocError);
+        }
+        self.allocate(Layout::from_size_align(1 << class, 1).unwrap())
+    }
+
+    // Frees every item under a single lock instead of locking once per block
+    #[cfg(test)]
+    pub unsafe fn deallocate_batch(&self, items: &[(NonNull<u8>, Layout)]) {
+        let mut alloc: MutexGuard<'_, SimpleSegregatedStorage<S>> = self.lock();
+        for (ptr, layout) in items {
+            alloc.free_block(*ptr, *layout);
+        }
+    }
 
-        alloc.lists[index].push_back(slice);
+    // Serves the request only from regions on the given node, acquiring a new one there if the
+    // class has no free block on it. Plain allocate may still hand out blocks from any node.
+    #[cfg(test)]
+    pub fn allocate_on_node(&self, layout: Layout, node: u8) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            return self.allocate(layout);
+        }
+        self.lock().allocate_block(layout, Some(node))
+    }
 
-        // Decrement current allocation size
-        alloc.current_allocated_size -= rounded_size as f64;
+    // Hands out a run of adjacent free blocks of one class as a single span. Blocks of a class are
+    // only adjacent within the same region, so runs never cross a region boundary.
+    #[cfg(test)]
+    pub fn allocate_contiguous(
+        &self,
+        blocks: usize,
+        class_index: usize,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let mut alloc: MutexGuard<'_, SimpleSegregatedStorage<S>> = self.lock();
+        if blocks == 0 || class_index >= alloc.lists.len() {
+            return Err(AllocError);
+        }
+        let block_size: usize = 1 << class_index;
+
+        let mut free_blocks: Vec<NonNull<[u8]>> =
+            alloc.lists[class_index].iter().copied().collect();
+        free_blocks.sort_unstable_by_key(|block| block.addr().get());
+
+        let mut run_start: usize = 0;
+        let mut found: Option<usize> = None;
+        for i in 0..free_blocks.len() {
+            let address: usize = free_blocks[i].addr().get();
+            if i > 0 {
+                let previous: usize = free_blocks[i - 1].addr().get();
+                if previous + block_size != address
+                    || alloc.region_of(previous) != alloc.region_of(address)
+                {
+                    run_start = i;
+                }
+            }
+            if i + 1 - run_start == blocks {
+                found = Some(run_start);
+                break;
+            }
+        }
+
+        let first_block: NonNull<[u8]> = free_blocks[found.ok_or(AllocError)?];
+        let span_start: usize = first_block.addr().get();
+        let span_end: usize = span_start + blocks * block_size;
+
+        // Take the blocks making up the run out of the free list
+        let list: LinkedList<NonNull<[u8]>> = std::mem::take(&mut alloc.lists[class_index]);
+        alloc.lists[class_index] = list
+            .into_iter()
+            .filter(|block| !(span_start..span_end).contains(&block.addr().get()))
+            .collect();
+
+        let span: NonNull<[u8]> =
+            NonNull::slice_from_raw_parts(first_block.as_non_null_ptr(), blocks * block_size);
+        alloc.record_allocation(span, class_index);
+        Ok(span)
+    }
+
+    // Returns a span from allocate_contiguous to the free list as individual blocks
+    #[cfg(test)]
+    pub unsafe fn deallocate_contiguous(
+        &self,
+        ptr: NonNull<u8>,
+        blocks: usize,
+        class_index: usize,
+    ) {
+        let mut alloc: MutexGuard<'_, SimpleSegregatedStorage<S>> = self.lock();
+        let block_size: usize = 1 << class_index;
+        for i in 0..blocks {
+            alloc.push_free(
+                class_index,
+                NonNull::slice_from_raw_parts(ptr.add(i * block_size), block_size),
+            );
+        }
+        let span: NonNull<[u8]> = NonNull::slice_from_raw_parts(ptr, blocks * block_size);
+        if alloc.record_free(span, class_index) && alloc.release_empty_regions {
+            alloc.free_region_of(ptr);
+        }
+    }
+}
+
+#[cfg(any(test, feature = "global_fallback"))]
+impl<S: RegionSource> Owns for SimpleSegregatedStorage<S> {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        self.region_of(ptr.addr().get()).is_some()
     }
 }
 
 #[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::Mutex;
+impl<S: RegionSource> FreeListInvariants for SimpleSegregatedStorage<S> {
+    fn check_invariants(&self) -> Result<(), String> {
+        check_free_lists(&self.lists, &self.allocated_first_byte, 512)
+    }
 
-    #[test]
-    fn test_allocate_fail() {
-        let allocator: Locked<SimpleSegregatedStorage> =
-            Locked::new(SimpleSegregatedStorage::new());
-        let layout: Layout = Layout::from_size_align(1024, 8).unwrap();
-        assert_eq!(allocator.allocate(layout), Err(AllocError));
+    fn repair(&mut self) -> usize {
+        repair_free_lists(&mut self.lists, &self.allocated_first_byte, 512)
     }
 
-    #[test]
-    fn test_allocate_deallocate_success() {
-        let allocator: Locked<SimpleSegregatedStorage> =
-            Locked::new(SimpleSegregatedStorage::new());
-        let layout: Layout = Layout::from_size_align(128, 8).unwrap();
+    fn inject_overlap(&mut self) {
+        list_first_block_twice(&mut self.lists);
+    }
+}
 
-        // Allocate with corresponding layout
-        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+#[cfg(test)]
+impl<S: RegionSource> Snapshot for SimpleSegregatedStorage<S> {
+    fn snapshot(&self) -> AllocatorSnapshot {
+        AllocatorSnapshot::capture(
+            &self.lists,
+            self.peak_allocated_size,
+            self.current_allocated_size,
+            &self.class_live,
+        )
+    }
 
-        // Verify blocks created correctly and allocated
-        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
-        assert_eq!(alloc.lists[7].len(), 3); // 4 created, 3 stored while 1 is used for the allocation
-        Mutex::unlock(alloc);
+    fn restore(&mut self, snap: &AllocatorSnapshot) {
+        debug_assert!(snap.within_regions(&self.allocated_first_byte, 512));
+        snap.restore_lists(&mut self.lists, &mut self.class_live);
+        // Spare regions may be listed again by the snapshot, so they are kept owned but not reused
+        self.spare_regions.clear();
+        self.peak_allocated_size = snap.peak_allocated_size;
+        self.current_allocated_size = snap.current_allocated_size;
+    }
+}
 
-        unsafe {
-            let raw_first_byte: *mut u8 = ptr.as_mut_ptr();
-            allocator.deallocate(NonNull::new_unchecked(raw_first_byte), layout);
+impl<S: RegionSource> MemStats for SimpleSegregatedStorage<S> {
+    fn calculate_allocation_ratio(&self) -> (usize, usize, f64) {
+        (
+            self.peak_allocated_size,
+            self.total_size,
+            self.peak_allocated_size as f64 / self.total_size as f64,
+        )
+    }
 
-            // Verify deallocated block still exists and is added to correct list
-            let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
-            assert_eq!(alloc.lists[7].len(), 4) // deallocated block should be added to corresponding list
+    fn current_allocated_size(&self) -> usize {
+        self.current_allocated_size
+    }
+
+    fn extension_count(&self) -> u64 {
+        self.extension_count
+    }
+
+    fn alloc_success(&self) -> u64 {
+        self.alloc_success
+    }
+
+    fn alloc_failure(&self) -> u64 {
+        self.alloc_failure
+    }
+
+    fn size_class_histogram(&self) -> Vec<u64> {
+        self.class_live.to_vec()
+    }
+
+    // Blocks never merge, so only spare regions and 512 byte blocks can serve the largest class
+    fn external_fragmentation(&self) -> f64 {
+        let listed = self.lists.iter().flatten().chain(&self.quarantine);
+        let uncarved = self.uncarved.iter().flatten();
+        fragmented_share(
+            listed
+                .chain(uncarved)
+                .map(|block| block.len())
+                .chain(self.spare_regions.iter().map(|_| 512)),
+            512,
+        )
+    }
+
+    fn reset(&mut self) {
+        self.total_size = 0;
+        self.peak_allocated_size = 0;
+        self.current_allocated_size = 0;
+        self.class_live = [0; 10];
+        self.alloc_success = 0;
+        self.alloc_failure = 0;
+        self.extension_count = 0;
+        for byte in &self.allocated_first_byte {
+            unsafe {
+                self.source.release(
+                    *byte,
+                    Layout::from_size_align_unchecked(512, self.region_align),
+                );
+            }
+        }
+        self.allocated_first_byte.clear();
+        self.region_nodes.clear();
+        self.region_live.clear();
+        self.uncarved = [None; 10];
+        self.spare_regions.clear();
+        self.quarantine.clear();
+        for list in &mut self.lists {
+            while list.pop_front().is_some() {}
+        }
+    }
+
+    fn reset_peak(&mut self) {
+        self.peak_allocated_size = self.current_allocated_size;
+    }
+}
+
+impl<S: RegionSource> SimpleSegregatedStorage<S> {
+    unsafe fn free_block(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        // Neither zero-size nor oversized blocks were taken from a region
+        if layout.size() == 0 || layout.size() > 512 {
+            return;
+        }
+        let index: usize = CLASS_TABLE[layout.size()] as usize;
+        let rounded_size: usize = 1 << index;
+        if !validate_free(
+            self.validation,
+            &self.on_invalid_free,
+            &self.lists,
+            &self.allocated_first_byte,
+            512,
+            NonNull::slice_from_raw_parts(ptr, rounded_size),
+        ) {
+            return;
+        }
+        if self.validation == ValidationLevel::Full
+            && self
+                .quarantine
+                .iter()
+                .any(|block| block.addr() == ptr.addr())
+        {
+            let reason: String = format!("double free of {:#x}", ptr.addr().get());
+            report_invalid_free(&self.on_invalid_free, &reason);
+            return;
+        }
+        // The whole class block is handed out again, including the slack past the request
+        if self.zero_on_free {
+            ptr.as_ptr().write_bytes(0, rounded_size);
+        }
+
+        // let mut vec: Vec<u8> = Vec::new();
+        // for i in 0..rounded_size {
+        //     vec.push(*(ptr.as_ptr().add(i)));
+        // }
+        // let slice: &mut [u8] = &mut vec.as_mut_slice();
+        // Blocks go back at their full class size so later allocations get the whole block
+        let slice: NonNull<[u8]> = NonNull::slice_from_raw_parts(ptr, rounded_size);
+
+        // Decrement current allocation size
+        let region_empty: bool = self.record_free(slice, index);
+
+        if self.quarantine_depth == 0 {
+            self.push_free(index, slice);
+        } else {
+            ptr.as_ptr().write_bytes(QUARANTINE_POISON, rounded_size);
+            self.quarantine.push_back(slice);
+            if self.quarantine.len() > self.quarantine_depth {
+                let released: NonNull<[u8]> = self.quarantine.pop_front().unwrap();
+                self.push_free(CLASS_TABLE[released.len()] as usize, released);
+            }
+        }
+        if region_empty && self.release_empty_regions {
+            self.free_region_of(ptr);
+        }
+    }
+}
+
+impl<S: RegionSource> Drop for SimpleSegregatedStorage<S> {
+    fn drop(&mut self) {
+        if self.leak_on_drop {
+            return;
+        }
+        for byte in &self.allocated_first_byte {
+            unsafe {
+                self.source.release(
+                    *byte,
+                    Layout::from_size_align_unchecked(512, self.region_align),
+                );
+            }
+        }
+        for list in &mut self.lists {
+            while list.pop_front().is_some() {}
+        }
+    }
+}
+
+impl<S: RegionSource> ExclusiveAllocator for SimpleSegregatedStorage<S> {
+    fn allocate_exclusive(&mut self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(block) = zero_size_block(layout) {
+            return Ok(block);
+        }
+        self.allocate_block(layout, None)
+    }
+
+    unsafe fn deallocate_exclusive(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.free_block(ptr, layout);
+    }
+}
+
+unsafe impl<S: RegionSource> Allocator for Locked<SimpleSegregatedStorage<S>> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let _in_flight: InFlight<'_> = self.enter_allocate();
+        // Round up allocation to nearest power of 2. Options are 1B, 2B, 4B, 8B, 16B, 32B, 64B, 128B, 256B, 512B
+        if let Some(block) = zero_size_block(layout) {
+            return Ok(block);
+        }
+        self.lock().allocate_block(layout, None)
+    }
+
+    // Zeroes the whole class block rather than just the request, so a later grow within the class
+    // sees zeroes too. Fresh regions are zeroed as well, since the source doesn't promise zeroed
+    // memory.
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let block: NonNull<[u8]> = self.allocate(layout)?;
+        if layout.size() != 0 {
+            let rounded_size: usize = 1 << CLASS_TABLE[layout.size()];
+            unsafe {
+                block.as_mut_ptr().write_bytes(0, rounded_size);
+            }
+        }
+        Ok(block)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.lock().free_block(ptr, layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        // The block already spans its whole class, so growing within the class needs no copy
+        if old_layout.size() != 0
+            && new_layout.size() <= 512
+            && CLASS_TABLE[new_layout.size()] == CLASS_TABLE[old_layout.size()]
+            && ptr.addr().get().is_multiple_of(new_layout.align())
+        {
+            let rounded_size: usize = 1 << CLASS_TABLE[new_layout.size()];
+            let block: NonNull<[u8]> = NonNull::slice_from_raw_parts(ptr, rounded_size);
+            return Ok(self.lock().returned_slice(block, new_layout.size()));
+        }
+
+        let new_ptr: NonNull<[u8]> = self.allocate(new_layout)?;
+        std::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_mut_ptr(), old_layout.size());
+        self.deallocate(ptr, old_layout);
+        Ok(new_ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::invariants::ValidationLevel;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_size_class_histogram() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let large: Layout = Layout::from_size_align(128, 8).unwrap();
+        let small: Layout = Layout::from_size_align(32, 8).unwrap();
+
+        let blocks: Vec<NonNull<[u8]>> =
+            (0..3).map(|_| allocator.allocate(large).unwrap()).collect();
+        let single: NonNull<[u8]> = allocator.allocate(small).unwrap();
+        let histogram: Vec<u64> = allocator.lock().size_class_histogram();
+        assert_eq!(histogram.len(), 10);
+        assert_eq!(histogram[7], 3);
+        assert_eq!(histogram[5], 1);
+        assert_eq!(histogram.iter().sum::<u64>(), 4);
+
+        unsafe {
+            allocator.deallocate(blocks[0].as_non_null_ptr(), large);
+            allocator.deallocate(single.as_non_null_ptr(), small);
+        }
+        let histogram: Vec<u64> = allocator.lock().size_class_histogram();
+        assert_eq!((histogram[7], histogram[5]), (2, 0));
+    }
+
+    #[test]
+    fn test_overhead_bytes() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        assert_eq!(allocator.lock().overhead_bytes(), 0);
+
+        // Each new class splits a region into free list nodes, 63 of 8 bytes then 31 of 16 bytes
+        let _ = allocator.allocate(Layout::from_size_align(8, 8).unwrap());
+        let eight: usize = allocator.lock().overhead_bytes();
+        assert!(eight >= 63 * size_of::<NonNull<[u8]>>());
+        let _ = allocator.allocate(Layout::from_size_align(16, 8).unwrap());
+        let sixteen: usize = allocator.lock().overhead_bytes();
+        assert!(sixteen >= eight + 31 * size_of::<NonNull<[u8]>>());
+    }
+
+    #[test]
+    fn test_allocate_from_class() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let block: NonNull<[u8]> = allocator.allocate_from_class(7).unwrap();
+        assert_eq!(block.len(), 128);
+        assert_eq!(allocator.lock().calculate_allocation_ratio().0, 128);
+        assert!(allocator.allocate_from_class(10).is_err());
+    }
+
+    #[test]
+    fn test_guaranteed_alignment() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        for size in [1, 3, 8, 12, 16, 24, 100, 7, 64, 200, 2, 512] {
+            let layout: Layout = Layout::from_size_align(size, 1).unwrap();
+            let guaranteed: usize = allocator.lock().guaranteed_alignment(&layout);
+            assert_eq!(guaranteed, usize::min(size.next_power_of_two(), 16));
+            let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+            assert!(ptr.as_mut_ptr().addr().is_multiple_of(guaranteed));
+        }
+    }
+
+    #[test]
+    fn test_trim_to_request() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        allocator.lock().set_trim_to_request(true);
+        let layout: Layout = Layout::from_size_align(100, 8).unwrap();
+
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(ptr.len(), 100);
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+        // The whole 128 byte block went back
+        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        assert_eq!(alloc.current_allocated_size, 0);
+        assert_eq!(alloc.lists[7].len(), 512 / 128);
+    }
+
+    #[test]
+    fn test_release_empty_regions() {
+        let mut storage: SimpleSegregatedStorage = SimpleSegregatedStorage::new();
+        storage.set_release_empty_regions(true);
+        let allocator: Locked<SimpleSegregatedStorage> = Locked::new(storage);
+        let small: Layout = Layout::from_size_align(64, 8).unwrap();
+        let large: Layout = Layout::from_size_align(256, 8).unwrap();
+        let smalls: Vec<NonNull<[u8]>> = (0..12)
+            .map(|_| allocator.allocate(small).unwrap())
+            .collect();
+        let larges: Vec<NonNull<[u8]>> =
+            (0..3).map(|_| allocator.allocate(large).unwrap()).collect();
+        assert_eq!(allocator.lock().calculate_allocation_ratio().1, 4 * 512);
+
+        unsafe {
+            for block in &smalls[..8] {
+                allocator.deallocate(block.as_non_null_ptr(), small);
+            }
+        }
+        // Only the region every freed block came from is released
+        assert_eq!(allocator.lock().calculate_allocation_ratio().1, 3 * 512);
+        assert!(!allocator.lock().owns(smalls[0].as_non_null_ptr()));
+
+        unsafe {
+            for block in &smalls[8..] {
+                allocator.deallocate(block.as_non_null_ptr(), small);
+            }
+            for block in &larges {
+                allocator.deallocate(block.as_non_null_ptr(), large);
+            }
+        }
+        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        assert_eq!(alloc.calculate_allocation_ratio().1, 0);
+        assert!(alloc.allocated_first_byte.is_empty());
+        assert!(alloc.lists.iter().all(|list| list.is_empty()));
+        assert!(alloc.stats_consistent());
+        Mutex::unlock(alloc);
+
+        // With every region released, the next allocation starts over from a fresh one
+        let again: NonNull<[u8]> = allocator.allocate(large).unwrap();
+        assert_eq!(again.len(), 256);
+        assert_eq!(
+            allocator.lock().calculate_allocation_ratio(),
+            (256, 512, 0.5)
+        );
+    }
+
+    #[test]
+    fn test_consolidate() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let layout: Layout = Layout::from_size_align(256, 8).unwrap();
+        let blocks: Vec<NonNull<[u8]>> = (0..3)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+        // The first region keeps blocks[1], the second is left entirely free
+        unsafe {
+            allocator.deallocate(blocks[0].as_non_null_ptr(), layout);
+            allocator.deallocate(blocks[2].as_non_null_ptr(), layout);
+        }
+
+        let mut alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        assert_eq!(alloc.consolidate(), 1);
+        assert_eq!(alloc.calculate_allocation_ratio().1, 512);
+        assert!(alloc.owns(blocks[1].as_non_null_ptr()));
+        assert!(!alloc.owns(blocks[2].as_non_null_ptr()));
+        assert_eq!(alloc.lists[8].len(), 1);
+        assert_eq!(alloc.consolidate(), 0);
+        Mutex::unlock(alloc);
+
+        // The peak of 768 bytes was clamped to the 512 still owned, so allocating goes on
+        let refill: Vec<NonNull<[u8]>> = (0..2)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+        assert!(refill.iter().all(|block| block.len() == 256));
+        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        assert!(alloc.stats_consistent());
+        assert_eq!(alloc.calculate_allocation_ratio().1, 2 * 512);
+    }
+
+    #[test]
+    fn test_free_region_of() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let small: Layout = Layout::from_size_align(128, 8).unwrap();
+        let large: Layout = Layout::from_size_align(256, 8).unwrap();
+        let blocks: Vec<NonNull<[u8]>> =
+            (0..4).map(|_| allocator.allocate(small).unwrap()).collect();
+        let kept: NonNull<[u8]> = allocator.allocate(large).unwrap();
+        unsafe {
+            allocator.deallocate_batch(
+                &blocks
+                    .iter()
+                    .map(|block| (block.as_non_null_ptr(), small))
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        // Any pointer into the region releases all of it
+        let mut alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        let inside: NonNull<u8> = unsafe { blocks[2].as_non_null_ptr().add(5) };
+        alloc.free_region_of(inside);
+        assert_eq!(alloc.calculate_allocation_ratio().1, 512);
+        assert!(alloc.lists[7].is_empty());
+        assert!(!alloc.owns(blocks[0].as_non_null_ptr()));
+        assert!(alloc.owns(kept.as_non_null_ptr()));
+        assert!(alloc.stats_consistent());
+        Mutex::unlock(alloc);
+
+        // Allocating again acquires a new region with the peak back within total_size
+        let again: NonNull<[u8]> = allocator.allocate(small).unwrap();
+        assert!(allocator.lock().owns(again.as_non_null_ptr()));
+        assert_eq!(allocator.lock().calculate_allocation_ratio().1, 2 * 512);
+        assert!(allocator.lock().stats_consistent());
+    }
+
+    #[test]
+    fn test_allocate_on_node() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+
+        // A freed node 0 block stays listed next to the node 1 ones
+        let on_zero: NonNull<[u8]> = allocator.allocate_on_node(layout, 0).unwrap();
+        let _kept: NonNull<[u8]> = allocator.allocate_on_node(layout, 0).unwrap();
+        unsafe {
+            allocator.deallocate(on_zero.as_non_null_ptr(), layout);
+        }
+        let on_one: Vec<NonNull<[u8]>> = (0..12)
+            .map(|_| allocator.allocate_on_node(layout, 1).unwrap())
+            .collect();
+
+        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        assert_eq!(alloc.region_nodes, vec![0, 1, 1]);
+        assert!(on_one
+            .iter()
+            .all(|block| alloc.node_of(block.addr().get()) == Some(1)));
+        assert_eq!(alloc.alloc_success, 14);
+        Mutex::unlock(alloc);
+
+        // Goes through the same checks and trim as allocate
+        let over_aligned: Layout = Layout::from_size_align(64, 64).unwrap();
+        assert_eq!(allocator.allocate_on_node(over_aligned, 1), Err(AllocError));
+        allocator.lock().set_trim_to_request(true);
+        let trimmed: NonNull<[u8]> = allocator
+            .allocate_on_node(Layout::from_size_align(40, 8).unwrap(), 1)
+            .unwrap();
+        assert_eq!(trimmed.len(), 40);
+        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        assert_eq!((alloc.alloc_success, alloc.alloc_failure), (15, 1));
+    }
+
+    #[test]
+    fn test_grow_pool() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let live: NonNull<[u8]> = allocator.allocate_filled(layout, 0x5A).unwrap();
+        let total_before: usize = allocator.lock().calculate_allocation_ratio().1;
+
+        allocator.lock().grow_pool(2).unwrap();
+        assert_eq!(
+            allocator.lock().calculate_allocation_ratio().1,
+            total_before + 1024
+        );
+        unsafe {
+            assert!(live.as_ref().iter().all(|byte| *byte == 0x5A));
+        }
+        // New classes draw on the grown pool instead of acquiring
+        allocator
+            .allocate(Layout::from_size_align(8, 8).unwrap())
+            .unwrap();
+        assert_eq!(allocator.lock().extension_count(), 3);
+    }
+
+    #[test]
+    fn test_allocate_zeroed_recycled_block() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let layout: Layout = Layout::from_size_align(24, 8).unwrap();
+        // A whole region of 32 byte blocks, so every later request is served from a recycled one
+        let blocks: Vec<NonNull<[u8]>> = (0..16)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+        for block in &blocks {
+            unsafe {
+                block.as_mut_ptr().write_bytes(0xAB, 32);
+                allocator.deallocate(block.as_non_null_ptr(), layout);
+            }
+        }
+
+        for _ in 0..16 {
+            let zeroed: NonNull<[u8]> = allocator.allocate_zeroed(layout).unwrap();
+            assert!(blocks
+                .iter()
+                .any(|block| block.as_mut_ptr() == zeroed.as_mut_ptr()));
+            let bytes: &[u8] = unsafe { std::slice::from_raw_parts(zeroed.as_mut_ptr(), 32) };
+            assert!(bytes.iter().all(|byte| *byte == 0));
+        }
+        assert_eq!(allocator.lock().calculate_allocation_ratio().1, 512);
+    }
+
+    #[test]
+    fn test_class_table() {
+        for (size, class) in CLASS_TABLE.iter().enumerate().skip(1) {
+            // Previous shift loop used to find the size class
+            let mut rounded_size: usize = 1;
+            let mut index: usize = 0;
+            let mut temp: usize = size - 1;
+            while temp != 0 {
+                temp >>= 1;
+                rounded_size <<= 1;
+                index += 1;
+            }
+
+            assert_eq!(*class as usize, index);
+            assert_eq!(1 << class, rounded_size);
+        }
+    }
+
+    #[test]
+    fn test_allocate_fail() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let layout: Layout = Layout::from_size_align(1024, 8).unwrap();
+        assert_eq!(allocator.allocate(layout), Err(AllocError));
+    }
+
+    #[test]
+    fn test_allocate_deallocate_success() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let layout: Layout = Layout::from_size_align(128, 8).unwrap();
+
+        // Allocate with corresponding layout
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+
+        // Verify blocks created correctly and allocated
+        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        assert_eq!(alloc.lists[7].len(), 3); // 4 created, 3 stored while 1 is used for the allocation
+        Mutex::unlock(alloc);
+
+        unsafe {
+            let raw_first_byte: *mut u8 = ptr.as_mut_ptr();
+            allocator.deallocate(NonNull::new_unchecked(raw_first_byte), layout);
+
+            // Verify deallocated block still exists and is added to correct list
+            let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+            assert_eq!(alloc.lists[7].len(), 4) // deallocated block should be added to corresponding list
+        }
+    }
+
+    #[test]
+    fn test_prepare_class() {
+        let mut allocator: SimpleSegregatedStorage = SimpleSegregatedStorage::new();
+        allocator.prepare_class(6, 3).unwrap();
+
+        assert_eq!(allocator.lists[6].len(), 512 / 64 * 3);
+        assert!(allocator.lists[6].iter().all(|block| block.len() == 64));
+        assert_eq!(allocator.total_size, 1536);
+        assert_eq!(allocator.current_allocated_size, 0);
+
+        assert_eq!(allocator.prepare_class(10, 1), Err(AllocError));
+        assert_eq!(allocator.prepare_class(usize::MAX, 1), Err(AllocError));
+        assert_eq!(allocator.total_size, 1536);
+    }
+
+    #[test]
+    fn test_grow_in_place() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let mut vec: Vec<u8, &Locked<SimpleSegregatedStorage>> =
+            Vec::with_capacity_in(70, &allocator);
+        vec.extend(0..70);
+        let before: *const u8 = vec.as_ptr();
+        assert_eq!(allocator.lock().lists[7].len(), 3);
+
+        // 70 and 120 bytes both round to the 128-byte class
+        vec.reserve_exact(50);
+        assert_eq!(vec.capacity(), 120);
+        assert_eq!(vec.as_ptr(), before);
+        assert_eq!(allocator.lock().lists[7].len(), 3);
+        assert!(vec.iter().enumerate().all(|(i, value)| *value == i as u8));
+    }
+
+    #[test]
+    fn test_page_aligned_regions() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::with_page_aligned_regions(true));
+        let layout: Layout = Layout::from_size_align(512, 16).unwrap();
+        for _ in 0..3 {
+            let _ = allocator.allocate(layout).unwrap();
+        }
+
+        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        assert_eq!(alloc.allocated_first_byte.len(), 3);
+        assert!(alloc
+            .allocated_first_byte
+            .iter()
+            .all(|region| region.addr().get() % PAGE_SIZE == 0));
+    }
+
+    // Frees a block twice, returning the allocator and the reasons it reported for refused frees
+    fn free_twice(
+        level: ValidationLevel,
+    ) -> (Locked<SimpleSegregatedStorage>, Arc<Mutex<Vec<String>>>) {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let reasons: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+        let reported: Arc<Mutex<Vec<String>>> = Arc::clone(&reasons);
+        allocator.lock().set_validation(level);
+        allocator
+            .lock()
+            .set_on_invalid_free(Box::new(move |reason: &str| {
+                reported.lock().unwrap().push(reason.to_string())
+            }));
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        // Keep a second block live so the usage counter doesn't underflow on the repeat free
+        let _held: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+        (allocator, reasons)
+    }
+
+    #[test]
+    fn test_validation_none_ignores_double_free() {
+        let (allocator, reasons) = free_twice(ValidationLevel::None);
+        // The block is now listed twice
+        assert_eq!(allocator.lock().lists[6].len(), 512 / 64);
+        assert!(reasons.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_validation_bounds() {
+        let (allocator, reasons) = free_twice(ValidationLevel::Bounds);
+        assert_eq!(allocator.lock().lists[6].len(), 512 / 64);
+        assert!(reasons.lock().unwrap().is_empty());
+
+        // Refused without unwinding, and the lists are left as they were
+        let outside: Box<[u8; 64]> = Box::new([0; 64]);
+        unsafe {
+            allocator.deallocate(
+                NonNull::from(&*outside).cast(),
+                Layout::from_size_align(64, 8).unwrap(),
+            );
+        }
+        let reasons: MutexGuard<'_, Vec<String>> = reasons.lock().unwrap();
+        assert_eq!(reasons.len(), 1);
+        assert!(reasons[0].contains("is not in any region"));
+        assert_eq!(allocator.lock().lists[6].len(), 512 / 64);
+    }
+
+    #[test]
+    fn test_default_validation_level() {
+        // The same in debug and release builds
+        let allocator: SimpleSegregatedStorage = SimpleSegregatedStorage::new();
+        assert_eq!(allocator.validation, ValidationLevel::Bounds);
+    }
+
+    #[test]
+    fn test_canary_intact() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::with_canary(true));
+        for size in [1, 4, 32] {
+            let layout: Layout = Layout::from_size_align(size, 1).unwrap();
+            for _ in 0..3 {
+                let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+                unsafe {
+                    ptr.as_mut_ptr().write_bytes(0xAA, size);
+                    allocator.deallocate(ptr.as_non_null_ptr(), layout);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "canary")]
+    fn test_canary_detects_overflow() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::with_canary(true));
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        unsafe {
+            // Runs 4 bytes into the free block after it, which is still inside the region
+            ptr.as_mut_ptr().write_bytes(0xAA, 36);
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+        let _ = allocator.allocate(layout);
+    }
+
+    #[test]
+    fn test_validation_full_catches_double_free() {
+        let (allocator, reasons) = free_twice(ValidationLevel::Full);
+        let reasons: MutexGuard<'_, Vec<String>> = reasons.lock().unwrap();
+        assert_eq!(reasons.len(), 1);
+        assert!(reasons[0].starts_with("double free"));
+        // Only the first free went back on the list
+        assert_eq!(allocator.lock().lists[6].len(), 512 / 64 - 1);
+    }
+
+    #[test]
+    fn test_warmup() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        allocator.warmup();
+
+        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        assert_eq!(alloc.current_allocated_size, 0);
+        assert_eq!(alloc.total_size, 10 * 512);
+        assert!(alloc.lists.iter().all(|list| !list.is_empty()));
+    }
+
+    #[test]
+    fn test_allocate_contiguous() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+
+        // Use up the whole region so only freed blocks are available
+        let blocks: Vec<NonNull<[u8]>> = (0..8)
+            .map(|_| allocator.allocate(layout).unwrap())
+            .collect();
+        unsafe {
+            allocator.deallocate(blocks[0].as_non_null_ptr(), layout);
+            allocator.deallocate(blocks[5].as_non_null_ptr(), layout);
+        }
+        assert_eq!(allocator.allocate_contiguous(2, 6), Err(AllocError));
+        // Classes past the table are refused before the block size is computed
+        assert_eq!(allocator.allocate_contiguous(1, 10), Err(AllocError));
+        assert_eq!(allocator.allocate_contiguous(1, 64), Err(AllocError));
+
+        unsafe {
+            allocator.deallocate(blocks[3].as_non_null_ptr(), layout);
+            allocator.deallocate(blocks[2].as_non_null_ptr(), layout);
+        }
+        let span: NonNull<[u8]> = allocator.allocate_contiguous(2, 6).unwrap();
+        assert_eq!(span.len(), 128);
+        assert_eq!(span.as_mut_ptr(), blocks[2].as_mut_ptr());
+
+        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        assert_eq!(alloc.lists[6].len(), 2);
+        assert_eq!(alloc.current_allocated_size, 384);
+        assert_eq!(alloc.total_size, 512);
+        Mutex::unlock(alloc);
+
+        unsafe {
+            allocator.deallocate_contiguous(span.as_non_null_ptr(), 2, 6);
+        }
+        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        assert_eq!(alloc.lists[6].len(), 4);
+        assert_eq!(alloc.current_allocated_size, 256);
+    }
+
+    #[test]
+    fn test_usable_size() {
+        let allocator: SimpleSegregatedStorage = SimpleSegregatedStorage::new();
+        assert_eq!(allocator.max_alloc_size(), 512);
+        assert_eq!(
+            allocator.usable_size(&Layout::from_size_align(100, 8).unwrap()),
+            128
+        );
+        assert_eq!(
+            allocator.usable_size(&Layout::from_size_align(1024, 8).unwrap()),
+            0
+        );
+    }
+
+    #[test]
+    fn test_deallocate_same_class_sizes() {
+        // (allocate size, deallocate size) pairs that round to the same class
+        let pairs: [(usize, usize); 6] = [
+            (120, 128),
+            (120, 100),
+            (120, 70),
+            (65, 128),
+            (3, 4),
+            (300, 257),
+        ];
+        for (allocate_size, deallocate_size) in pairs {
+            let allocator: Locked<SimpleSegregatedStorage> =
+                Locked::new(SimpleSegregatedStorage::new());
+            for _ in 0..3 {
+                let ptr: NonNull<[u8]> = allocator
+                    .allocate(Layout::from_size_align(allocate_size, 1).unwrap())
+                    .unwrap();
+                assert_eq!(ptr.len(), allocate_size.next_power_of_two());
+                unsafe {
+                    allocator.deallocate(
+                        ptr.as_non_null_ptr(),
+                        Layout::from_size_align(deallocate_size, 1).unwrap(),
+                    );
+                }
+
+                let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+                let index: usize = CLASS_TABLE[allocate_size] as usize;
+                assert_eq!(alloc.current_allocated_size, 0);
+                assert_eq!(alloc.lists[index].len(), 512 >> index);
+                assert!(alloc.lists[index]
+                    .iter()
+                    .all(|block| block.len() == 1 << index));
+            }
+        }
+    }
+
+    #[test]
+    fn test_zero_on_free() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::with_zero_on_free(true));
+        let layout: Layout = Layout::from_size_align(512, 16).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate_filled(layout, 0x5A).unwrap();
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+
+        let reused: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(reused.as_mut_ptr(), ptr.as_mut_ptr());
+        unsafe {
+            assert!(reused.as_ref()[..512].iter().all(|byte| *byte == 0));
+        }
+
+        // 33 bytes fall in the 64 byte class, the bytes past the request come back zeroed too.
+        // The whole region is filled and freed, so every block handed out next was dirtied.
+        let odd: Layout = Layout::from_size_align(33, 8).unwrap();
+        let dirty: Vec<NonNull<[u8]>> = (0..8)
+            .map(|_| allocator.allocate_filled(odd, 0x5A).unwrap())
+            .collect();
+        assert!(dirty.iter().all(|block| block.len() == 64));
+        unsafe {
+            for block in &dirty {
+                allocator.deallocate(block.as_non_null_ptr(), odd);
+            }
+        }
+        for _ in 0..8 {
+            let reused: NonNull<[u8]> = allocator
+                .allocate(Layout::from_size_align(64, 8).unwrap())
+                .unwrap();
+            assert!(dirty.contains(&reused));
+            unsafe {
+                assert!(reused.as_ref().iter().all(|byte| *byte == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::with_quarantine(2));
+        let layout: Layout = Layout::from_size_align(512, 16).unwrap();
+        let blocks: Vec<NonNull<[u8]>> = (0..3)
+            .map(|_| allocator.allocate_filled(layout, 0x5A).unwrap())
+            .collect();
+
+        unsafe {
+            allocator.deallocate(blocks[0].as_non_null_ptr(), layout);
+            allocator.deallocate(blocks[1].as_non_null_ptr(), layout);
+        }
+        // Both frees are still quarantined, so a new region is carved instead
+        let fresh: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert!(blocks.iter().all(|block| block.addr() != fresh.addr()));
+        unsafe {
+            assert!(blocks[0]
+                .as_ref()
+                .iter()
+                .all(|byte| *byte == QUARANTINE_POISON));
+        }
+
+        // A second later free releases the first block for reuse
+        unsafe {
+            allocator.deallocate(blocks[2].as_non_null_ptr(), layout);
+        }
+        let reused: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(reused.addr(), blocks[0].addr());
+        unsafe {
+            assert!(reused
+                .as_ref()
+                .iter()
+                .all(|byte| *byte == QUARANTINE_POISON));
+        }
+    }
+
+    #[test]
+    fn test_lazy_split() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::with_lazy_split(true));
+        let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+
+        let first: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        {
+            let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+            // Nothing else was carved, the rest of the region is left for later requests
+            assert_eq!(alloc.lists[6].len(), 0);
+            assert_eq!(alloc.uncarved[6].unwrap().len(), 512 - 64);
+        }
+
+        for _ in 1..512 / 64 {
+            let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+            assert_eq!(ptr.len(), 64);
+        }
+        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        assert_eq!(alloc.extension_count(), 1);
+        assert_eq!(alloc.allocated_first_byte[0], first.as_non_null_ptr());
+        assert!(alloc.uncarved[6].is_none());
+    }
+
+    #[test]
+    fn test_rebalance() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let small: Layout = Layout::from_size_align(64, 8).unwrap();
+        let ptrs: Vec<NonNull<[u8]>> = (0..512 / 64)
+            .map(|_| allocator.allocate(small).unwrap())
+            .collect();
+        for ptr in ptrs {
+            unsafe {
+                allocator.deallocate(ptr.as_non_null_ptr(), small);
+            }
+        }
+
+        assert_eq!(allocator.lock().rebalance(), 1);
+        assert_eq!(allocator.lock().lists[6].len(), 0);
+
+        // The 128-byte class re-splits the reclaimed region instead of acquiring a new one
+        let _ = allocator
+            .allocate(Layout::from_size_align(128, 8).unwrap())
+            .unwrap();
+        let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
+        let (_, total_size, _): (usize, usize, f64) = alloc.calculate_allocation_ratio();
+        assert_eq!(total_size, 512);
+        assert_eq!(alloc.extension_count(), 1);
+        assert_eq!(alloc.lists[7].len(), 3);
+    }
+
+    #[test]
+    fn test_region_occupancy() {
+        let allocator: Locked<SimpleSegregatedStorage> =
+            Locked::new(SimpleSegregatedStorage::new());
+        let _ = allocator
+            .allocate(Layout::from_size_align(128, 8).unwrap())
+            .unwrap();
+        let layout: Layout = Layout::from_size_align(512, 16).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(allocator.lock().region_occupancy(), vec![0.25, 1.0]);
+
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
         }
+        assert_eq!(allocator.lock().region_occupancy(), vec![0.25, 0.0]);
     }
 
     #[test]
@@ -206,8 +1785,8 @@ mod tests {
         let _ = allocator.allocate(layout).unwrap();
 
         let alloc: MutexGuard<'_, SimpleSegregatedStorage> = allocator.lock();
-        assert_eq!(alloc.total_size, 1536 as f64);
-        assert_eq!(alloc.peak_allocated_size, 384 as f64);
-        assert_eq!(alloc.current_allocated_size, 288 as f64);
+        assert_eq!(alloc.total_size, 1536);
+        assert_eq!(alloc.peak_allocated_size, 384);
+        assert_eq!(alloc.current_allocated_size, 288);
     }
 }
@@ -0,0 +1,75 @@
+use std::collections::LinkedList;
+use std::ptr::NonNull;
+
+use crate::region_source::RegionSizes;
+
+// Copy of an allocator's free lists and usage stats. Blocks point into the allocator's regions, so
+// a snapshot can only be restored while those regions are still owned, i.e. with no reset between.
+pub struct AllocatorSnapshot {
+    pub lists: Vec<Vec<NonNull<[u8]>>>,
+    pub peak_allocated_size: usize,
+    pub current_allocated_size: usize,
+    // Live allocations per size class, as reported by size_class_histogram
+    pub class_live: Vec<u64>,
+    // Frees still waiting to be merged, for allocators that defer coalescing
+    pub deferred: Vec<NonNull<[u8]>>,
+}
+
+// Allocators that can save their free lists and put them back later, to replay from one state
+pub trait Snapshot {
+    fn snapshot(&self) -> AllocatorSnapshot;
+    // Rebuilds the free lists from a snapshot. Regions acquired since then stay owned (and counted
+    // in total_size), but blocks carved from them are no longer listed as free.
+    fn restore(&mut self, snap: &AllocatorSnapshot);
+}
+
+impl AllocatorSnapshot {
+    pub fn capture(
+        lists: &[LinkedList<NonNull<[u8]>>],
+        peak_allocated_size: usize,
+        current_allocated_size: usize,
+        class_live: &[u64],
+    ) -> Self {
+        AllocatorSnapshot {
+            lists: lists
+                .iter()
+                .map(|list| list.iter().copied().collect())
+                .collect(),
+            peak_allocated_size,
+            current_allocated_size,
+            class_live: class_live.to_vec(),
+            deferred: Vec::new(),
+        }
+    }
+
+    // Whether every saved block still lies inside one of the regions
+    pub fn within_regions(&self, regions: &[NonNull<u8>], region_sizes: impl RegionSizes) -> bool {
+        self.lists
+            .iter()
+            .flatten()
+            .chain(&self.deferred)
+            .all(|block| {
+                regions.iter().enumerate().any(|(index, region)| {
+                    let start: usize = region.addr().get();
+                    start <= block.addr().get()
+                        && block.addr().get() + block.len()
+                            <= start + region_sizes.region_size(index)
+                })
+            })
+    }
+
+    // Puts the saved free lists and live counts back. Lists are only ever added, for classes the
+    // allocator grew after the snapshot, and those come back empty.
+    pub fn restore_lists(&self, lists: &mut [LinkedList<NonNull<[u8]>>], class_live: &mut [u64]) {
+        assert!(self.lists.len() <= lists.len());
+        for (index, list) in lists.iter_mut().enumerate() {
+            *list = self
+                .lists
+                .get(index)
+                .map(|blocks| blocks.iter().copied().collect())
+                .unwrap_or_default();
+        }
+        class_live.fill(0);
+        class_live[..self.class_live.len()].copy_from_slice(&self.class_live);
+    }
+}
@@ -0,0 +1,308 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::collections::LinkedList;
+use std::ptr::NonNull;
+use std::sync::MutexGuard;
+
+use crate::mutex::{Lock, Locked};
+use crate::region_source::{zero_size_block, Owns};
+use crate::simple_segregated_storage::CLASS_TABLE;
+use crate::stats::{fragmented_share, MemStats};
+
+/*
+    Fixed capacity version of the simple segregated storage allocator for environments without System.
+    - The caller provides the backing buffer, so handed out blocks stay valid even if the pool is moved.
+    - At construction the buffer is carved into 512 byte regions (aligned at 16). Any leftover tail is unused.
+    - A size class with no free blocks takes the next region and chunks it into blocks of that class.
+    - Once all regions are used up, requests for an empty class fail with AllocError.
+*/
+
+pub struct StaticPool<const N: usize> {
+    lists: [LinkedList<NonNull<[u8]>>; 10],
+    regions: LinkedList<NonNull<[u8]>>,
+    // The caller's buffer, every block handed out lies inside it
+    buffer: NonNull<[u8]>,
+    total_size: usize,
+    peak_allocated_size: usize,
+    current_allocated_size: usize,
+    // allocate calls that returned a block, and those that returned AllocError
+    alloc_success: u64,
+    alloc_failure: u64,
+    // Live allocations in each class
+    class_live: [u64; 10],
+}
+
+// The buffer came in as a &'static mut, so the pool holds the only reference to it and it outlives
+// every thread. Each list entry points into that buffer, which leaves the pool as movable as the
+// &'static mut [u8] it was built from.
+unsafe impl<const N: usize> Send for StaticPool<N> {}
+
+impl<const N: usize> StaticPool<N> {
+    pub fn new(buffer: &'static mut [u8; N]) -> Self {
+        let whole: NonNull<[u8]> = NonNull::from(&mut buffer[..]);
+        let mut regions: LinkedList<NonNull<[u8]>> = LinkedList::new();
+        let start: usize = usize::min(buffer.as_ptr().align_offset(16), N);
+        for region in buffer[start..].chunks_exact_mut(512) {
+            regions.push_back(NonNull::from(region));
+        }
+
+        StaticPool {
+            lists: [
+                LinkedList::new(),
+                LinkedList::new(),
+                LinkedList::new(),
+                LinkedList::new(),
+                LinkedList::new(),
+                LinkedList::new(),
+                LinkedList::new(),
+                LinkedList::new(),
+                LinkedList::new(),
+                LinkedList::new(),
+            ],
+            regions,
+            buffer: whole,
+            total_size: N,
+            peak_allocated_size: 0,
+            current_allocated_size: 0,
+            alloc_success: 0,
+            alloc_failure: 0,
+            class_live: [0; 10],
+        }
+    }
+
+    // Largest request that can be served, a whole region
+    pub fn max_alloc_size(&self) -> usize {
+        512
+    }
+
+    // Bytes reserved for a layout, the request rounded up to the next power of 2 (0 if empty or
+    // too large)
+    pub fn usable_size(&self, layout: &Layout) -> usize {
+        if layout.size() == 0 || layout.size() > 512 {
+            return 0;
+        }
+        layout.size().next_power_of_two()
+    }
+
+    // List index and block size serving a request of 1 to 512 bytes. Options are 1B, 2B, 4B, 8B,
+    // 16B, 32B, 64B, 128B, 256B, 512B
+    fn size_class(size: usize) -> (usize, usize) {
+        let index: usize = CLASS_TABLE[size] as usize;
+        (index, 1 << index)
+    }
+}
+
+impl<const N: usize> Owns for StaticPool<N> {
+    fn owns(&self, ptr: NonNull<u8>) -> bool {
+        let start: usize = self.buffer.addr().get();
+        start <= ptr.addr().get() && ptr.addr().get() < start + N
+    }
+}
+
+impl<const N: usize> MemStats for StaticPool<N> {
+    fn calculate_allocation_ratio(&self) -> (usize, usize, f64) {
+        (
+            self.peak_allocated_size,
+            self.total_size,
+            self.peak_allocated_size as f64 / self.total_size as f64,
+        )
+    }
+
+    fn current_allocated_size(&self) -> usize {
+        self.current_allocated_size
+    }
+
+    fn alloc_success(&self) -> u64 {
+        self.alloc_success
+    }
+
+    fn alloc_failure(&self) -> u64 {
+        self.alloc_failure
+    }
+
+    fn size_class_histogram(&self) -> Vec<u64> {
+        self.class_live.to_vec()
+    }
+
+    // Blocks never merge, so only untouched regions and 512 byte blocks can serve the largest class
+    fn external_fragmentation(&self) -> f64 {
+        fragmented_share(
+            self.lists
+                .iter()
+                .chain([&self.regions])
+                .flatten()
+                .map(|block| block.len()),
+            512,
+        )
+    }
+
+    fn reset(&mut self) {
+        // Regions can't be handed back, so only the usage counters are cleared
+        self.peak_allocated_size = 0;
+        self.current_allocated_size = 0;
+        self.alloc_success = 0;
+        self.alloc_failure = 0;
+        self.class_live = [0; 10];
+    }
+
+    fn reset_peak(&mut self) {
+        self.peak_allocated_size = self.current_allocated_size;
+    }
+}
+
+unsafe impl<const N: usize> Allocator for Locked<StaticPool<N>> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(block) = zero_size_block(layout) {
+            return Ok(block);
+        }
+        let mut alloc: MutexGuard<'_, StaticPool<N>> = self.lock();
+        if layout.size() > 512 {
+            alloc.alloc_failure += 1;
+            return Err(AllocError);
+        }
+        let (index, rounded_size): (usize, usize) = StaticPool::<N>::size_class(layout.size());
+        // Blocks sit at multiples of their size from a region start, which is only aligned at 16
+        if layout.align() > usize::min(rounded_size, 16) {
+            alloc.alloc_failure += 1;
+            return Err(AllocError);
+        }
+
+        if alloc.lists[index].is_empty() {
+            // Pool is exhausted once there are no regions left to carve
+            let region: NonNull<[u8]> = alloc
+                .regions
+                .pop_front()
+                .ok_or(AllocError)
+                .inspect_err(|_| alloc.alloc_failure += 1)?;
+            unsafe {
+                let raw_ptr: *mut [u8] = region.as_ptr();
+                let chunks = (*raw_ptr).chunks_exact_mut(rounded_size);
+                for chunk in chunks {
+                    alloc.lists[index].push_back(NonNull::new_unchecked(chunk as *mut [u8]));
+                }
+            }
+        }
+
+        // update allocation stats
+        alloc.current_allocated_size += rounded_size;
+        alloc.class_live[index] += 1;
+        alloc.alloc_success += 1;
+        alloc.peak_allocated_size =
+            usize::max(alloc.current_allocated_size, alloc.peak_allocated_size);
+        debug_assert!(alloc.peak_allocated_size <= alloc.total_size);
+
+        Ok(alloc.lists[index].pop_front().unwrap())
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // Neither zero-size nor oversized blocks were taken from a region
+        if layout.size() == 0 || layout.size() > 512 {
+            return;
+        }
+        let mut alloc: MutexGuard<'_, StaticPool<N>> = self.lock();
+        let (index, rounded_size): (usize, usize) = StaticPool::<N>::size_class(layout.size());
+
+        let slice: NonNull<[u8]> = NonNull::slice_from_raw_parts(ptr, rounded_size);
+        alloc.lists[index].push_back(slice);
+
+        // Decrement current allocation size
+        alloc.current_allocated_size -= rounded_size;
+        alloc.class_live[index] -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_failure_past_capacity() {
+        let buffer: &'static mut [u8; 1024] = Box::leak(Box::new([0; 1024]));
+        let allocator: Locked<StaticPool<1024>> = Locked::new(StaticPool::new(buffer));
+        let layout: Layout = Layout::from_size_align(512, 8).unwrap();
+
+        // The leaked buffer starts 16 aligned, so two whole regions fit and the rest is rejected
+        let results: Vec<bool> = (0..5).map(|_| allocator.allocate(layout).is_ok()).collect();
+        assert_eq!(results, vec![true, true, false, false, false]);
+
+        let mut alloc: MutexGuard<'_, StaticPool<1024>> = allocator.lock();
+        assert_eq!(alloc.alloc_failure(), 3);
+        assert_eq!(alloc.alloc_success(), 2);
+        alloc.reset();
+        assert_eq!((alloc.alloc_success(), alloc.alloc_failure()), (0, 0));
+    }
+
+    #[test]
+    fn test_allocate_until_exhausted() {
+        let buffer: &'static mut [u8; 1024] = Box::leak(Box::new([0; 1024]));
+        let allocator: Locked<StaticPool<1024>> = Locked::new(StaticPool::new(buffer));
+        let layout: Layout = Layout::from_size_align(256, 8).unwrap();
+
+        let mut allocated: Vec<NonNull<[u8]>> = Vec::new();
+        while let Ok(ptr) = allocator.allocate(layout) {
+            assert_eq!(ptr.len(), 256);
+            allocated.push(ptr);
+        }
+        assert_eq!(allocated.len(), 4);
+
+        // Failure should leave the pool untouched
+        let alloc: MutexGuard<'_, StaticPool<1024>> = allocator.lock();
+        assert_eq!(alloc.total_size, 1024);
+        assert_eq!(alloc.current_allocated_size, allocated.len() * 256);
+        assert!(alloc.regions.is_empty());
+        drop(alloc);
+
+        // Freed blocks can be reused after exhaustion
+        unsafe {
+            allocator.deallocate(allocated.pop().unwrap().as_non_null_ptr(), layout);
+        }
+        assert!(allocator.allocate(layout).is_ok());
+        assert_eq!(allocator.allocate(layout), Err(AllocError));
+    }
+
+    #[test]
+    fn test_zero_size_and_alignment() {
+        let buffer: &'static mut [u8; 1024] = Box::leak(Box::new([0; 1024]));
+        let allocator: Locked<StaticPool<1024>> = Locked::new(StaticPool::new(buffer));
+
+        // Zero-size requests never touch a region
+        let empty: Layout = Layout::from_size_align(0, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(empty).unwrap();
+        assert_eq!(ptr.len(), 0);
+        assert!(ptr.addr().get().is_multiple_of(8));
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), empty);
+        }
+
+        // A 1 byte block is only aligned at 1, a 64 byte block only at 16
+        assert!(allocator
+            .allocate(Layout::from_size_align(1, 64).unwrap())
+            .is_err());
+        assert!(allocator
+            .allocate(Layout::from_size_align(64, 32).unwrap())
+            .is_err());
+        let aligned: NonNull<[u8]> = allocator
+            .allocate(Layout::from_size_align(64, 16).unwrap())
+            .unwrap();
+        assert!(aligned.addr().get().is_multiple_of(16));
+
+        let alloc: MutexGuard<'_, StaticPool<1024>> = allocator.lock();
+        assert_eq!((alloc.alloc_success(), alloc.alloc_failure()), (1, 2));
+        assert_eq!(alloc.regions.len(), 1);
+    }
+    #[test]
+    fn test_usable_size() {
+        let buffer: &'static mut [u8; 1024] = Box::leak(Box::new([0; 1024]));
+        let pool: StaticPool<1024> = StaticPool::new(buffer);
+        let max: usize = pool.max_alloc_size();
+        assert_eq!(max, 512);
+        assert_eq!(
+            pool.usable_size(&Layout::from_size_align(100, 8).unwrap()),
+            128
+        );
+        assert_eq!(
+            pool.usable_size(&Layout::from_size_align(max + 1, 8).unwrap()),
+            0
+        );
+        assert_eq!(pool.usable_size(&Layout::from_size_align(0, 8).unwrap()), 0);
+    }
+}
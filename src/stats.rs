@@ -1,4 +1,210 @@
+#[cfg(test)]
+use std::collections::LinkedList;
+#[cfg(test)]
+use std::ptr::NonNull;
+
+#[cfg(test)]
+use crate::region_source::RegionSizes;
+
 pub trait MemStats {
-    fn calculate_allocation_ratio(&self) -> (f64, f64, f64);
+    fn calculate_allocation_ratio(&self) -> (usize, usize, f64);
+    fn current_allocated_size(&self) -> usize;
+    // Whether current <= peak <= total still holds
+    fn stats_consistent(&self) -> bool {
+        let (peak, total, _): (usize, usize, f64) = self.calculate_allocation_ratio();
+        self.current_allocated_size() <= peak && peak <= total
+    }
+    // The counters below stay 0 for allocators that never do the thing they count
+    fn extension_count(&self) -> u64 {
+        0
+    }
+    // Bytes skipped in front of blocks to meet their alignment
+    fn alignment_waste(&self) -> usize {
+        0
+    }
+    // Blocks divided to serve an allocation, and neighbors joined back together on free
+    fn split_count(&self) -> u64 {
+        0
+    }
+    fn merge_count(&self) -> u64 {
+        0
+    }
+    // allocate calls that succeeded and that failed, zero-size requests aside
+    fn alloc_success(&self) -> u64;
+    fn alloc_failure(&self) -> u64;
+    // Live allocations per size class, indexed like the allocator's free lists
+    fn size_class_histogram(&self) -> Vec<u64>;
+    // Share of free bytes in blocks too small for the largest request, 0 when nothing is free
+    fn external_fragmentation(&self) -> f64;
     fn reset(&mut self);
+    // Starts a new peak interval from the current usage, leaving regions and total_size alone
+    fn reset_peak(&mut self);
+}
+
+// Fraction of each region that is not sitting in a free list, in the same order as the regions
+#[cfg(test)]
+pub fn region_occupancy(
+    lists: &[LinkedList<NonNull<[u8]>>],
+    regions: &[NonNull<u8>],
+    region_sizes: impl RegionSizes,
+) -> Vec<f64> {
+    regions
+        .iter()
+        .enumerate()
+        .map(|(index, region)| {
+            let start: usize = region.addr().get();
+            let region_size: usize = region_sizes.region_size(index);
+            let free_bytes: usize = lists
+                .iter()
+                .flatten()
+                .filter(|block| {
+                    start <= block.addr().get() && block.addr().get() < start + region_size
+                })
+                .map(|block| block.len())
+                .sum();
+            1.0 - free_bytes as f64 / region_size as f64
+        })
+        .collect()
+}
+
+// Share of the free bytes that sit in blocks shorter than largest, given every free block's length
+pub fn fragmented_share(free_blocks: impl IntoIterator<Item = usize>, largest: usize) -> f64 {
+    let (stuck, free): (usize, usize) =
+        free_blocks.into_iter().fold((0, 0), |(stuck, free), len| {
+            (stuck + if len < largest { len } else { 0 }, free + len)
+        });
+    if free == 0 {
+        return 0.0;
+    }
+    stuck as f64 / free as f64
+}
+
+// (class_index, block_count, total_bytes) of every free list, empty classes included. A snapshot
+// of free memory right now, unlike counters accumulated over the allocator's lifetime.
+#[cfg(test)]
+pub fn free_distribution(lists: &[LinkedList<NonNull<[u8]>>]) -> Vec<(usize, usize, usize)> {
+    lists
+        .iter()
+        .enumerate()
+        .map(|(index, list)| {
+            (
+                index,
+                list.len(),
+                list.iter().map(|block| block.len()).sum(),
+            )
+        })
+        .collect()
+}
+
+// Estimated heap bytes spent tracking free blocks and regions. Every LinkedList node is its own
+// allocation holding the block and both links, the region table costs its whole capacity.
+#[cfg(test)]
+pub fn list_overhead(lists: &[LinkedList<NonNull<[u8]>>], region_capacity: usize) -> usize {
+    let node_size: usize = size_of::<NonNull<[u8]>>() + 2 * size_of::<usize>();
+    let nodes: usize = lists.iter().map(|list| list.len()).sum();
+    nodes * node_size + region_capacity * size_of::<NonNull<u8>>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buddy::Buddy;
+    use crate::mutex::{Lock, Locked};
+    use crate::segregated_free_list::SegregatedFreeList;
+    use crate::simple_segregated_storage::SimpleSegregatedStorage;
+    use std::alloc::{Allocator, Layout};
+    use std::sync::MutexGuard;
+
+    // Same sequence as each allocator's test_allocation_stats, checking the stats after every step
+    fn assert_consistent_throughout<A: MemStats>(allocator: &Locked<A>)
+    where
+        Locked<A>: Allocator,
+    {
+        let large: Layout = Layout::from_size_align(256, 8).unwrap();
+        let large_ptr: NonNull<[u8]> = allocator.allocate(large).unwrap();
+        assert!(allocator.lock().stats_consistent());
+
+        let layout: Layout = Layout::from_size_align(128, 8).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert!(allocator.lock().stats_consistent());
+
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+        assert!(allocator.lock().stats_consistent());
+
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+        let small_ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert!(allocator.lock().stats_consistent());
+
+        unsafe {
+            allocator.deallocate(small_ptr.as_non_null_ptr(), layout);
+            allocator.deallocate(large_ptr.as_non_null_ptr(), large);
+        }
+        assert!(allocator.lock().stats_consistent());
+    }
+
+    fn assert_peak_after_reset<A: MemStats>(allocator: &Locked<A>)
+    where
+        Locked<A>: Allocator,
+    {
+        let large: Layout = Layout::from_size_align(256, 8).unwrap();
+        let small: Layout = Layout::from_size_align(128, 8).unwrap();
+        let large_ptr: NonNull<[u8]> = allocator.allocate(large).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(small).unwrap();
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), small);
+        }
+        let (peak, total, _) = allocator.lock().calculate_allocation_ratio();
+        assert_eq!(peak, 384);
+
+        // Regions stay, only the peak restarts from current usage
+        allocator.lock().reset_peak();
+        assert_eq!(allocator.lock().calculate_allocation_ratio().0, 256);
+        assert_eq!(allocator.lock().calculate_allocation_ratio().1, total);
+        let tiny: Layout = Layout::from_size_align(32, 8).unwrap();
+        let tiny_ptr: NonNull<[u8]> = allocator.allocate(tiny).unwrap();
+        assert_eq!(allocator.lock().calculate_allocation_ratio().0, 288);
+        unsafe {
+            allocator.deallocate(tiny_ptr.as_non_null_ptr(), tiny);
+            allocator.deallocate(large_ptr.as_non_null_ptr(), large);
+        }
+    }
+
+    // Each 512 byte block fills a region of its own, so every allocation extends the pool
+    fn assert_extensions_counted<A: MemStats>(allocator: &Locked<A>)
+    where
+        Locked<A>: Allocator,
+    {
+        let layout: Layout = Layout::from_size_align(512, 16).unwrap();
+        for _ in 0..2 {
+            let _ = allocator.allocate(layout).unwrap();
+        }
+
+        let mut alloc: MutexGuard<'_, A> = allocator.lock();
+        assert_eq!(alloc.extension_count(), 2);
+        alloc.reset();
+        assert_eq!(alloc.extension_count(), 0);
+    }
+
+    #[test]
+    fn test_extension_count() {
+        assert_extensions_counted(&Locked::new(Buddy::new()));
+        assert_extensions_counted(&Locked::new(SegregatedFreeList::new()));
+        assert_extensions_counted(&Locked::new(SimpleSegregatedStorage::new()));
+    }
+
+    #[test]
+    fn test_reset_peak() {
+        assert_peak_after_reset(&Locked::new(Buddy::new()));
+        assert_peak_after_reset(&Locked::new(SegregatedFreeList::new()));
+        assert_peak_after_reset(&Locked::new(SimpleSegregatedStorage::new()));
+    }
+
+    #[test]
+    fn test_stats_consistent() {
+        assert_consistent_throughout(&Locked::new(Buddy::new()));
+        assert_consistent_throughout(&Locked::new(SegregatedFreeList::new()));
+        assert_consistent_throughout(&Locked::new(SimpleSegregatedStorage::new()));
+    }
 }
@@ -0,0 +1,345 @@
+use std::alloc::{AllocError, Allocator, Layout, System};
+use std::collections::LinkedList;
+use std::ptr::NonNull;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::region_source::{zero_size_block, RegionSource, DEFAULT_NODE};
+use crate::simple_segregated_storage::CLASS_TABLE;
+use crate::stats::{fragmented_share, MemStats};
+
+/*
+    Simple segregated storage with a lock per size class instead of one for the whole allocator.
+    - Threads allocating from different classes never wait on each other.
+    - Regions are acquired under their own lock, taken only while a class's list is empty and held.
+      Class locks are always taken before the region lock, so the two can't deadlock.
+    - Usage stats sit behind a third lock, only ever taken last and held just to update them.
+*/
+
+struct Regions<S> {
+    source: S,
+    allocated_first_byte: Vec<NonNull<u8>>,
+    total_size: usize,
+    extension_count: u64,
+}
+
+struct Stats {
+    peak_allocated_size: usize,
+    current_allocated_size: usize,
+    class_live: [u64; 10],
+    alloc_success: u64,
+    alloc_failure: u64,
+}
+
+pub struct StripedLocked<S: RegionSource = System> {
+    lists: [Mutex<LinkedList<NonNull<[u8]>>>; 10],
+    regions: Mutex<Regions<S>>,
+    stats: Mutex<Stats>,
+}
+
+// Each list, the region table and the stats sit behind their own Mutex, so shared access is
+// already serialized. The raw pointers inside are blocks of regions only this allocator releases.
+unsafe impl<S: RegionSource + Send> Send for StripedLocked<S> {}
+unsafe impl<S: RegionSource + Send> Sync for StripedLocked<S> {}
+
+impl StripedLocked {
+    pub fn new() -> Self {
+        StripedLocked::with_source(System)
+    }
+}
+
+impl<S: RegionSource> StripedLocked<S> {
+    pub fn with_source(source: S) -> Self {
+        StripedLocked {
+            lists: Default::default(),
+            regions: Mutex::new(Regions {
+                source,
+                allocated_first_byte: Vec::new(),
+                total_size: 0,
+                extension_count: 0,
+            }),
+            stats: Mutex::new(Stats {
+                peak_allocated_size: 0,
+                current_allocated_size: 0,
+                class_live: [0; 10],
+                alloc_success: 0,
+                alloc_failure: 0,
+            }),
+        }
+    }
+
+    // Holds one class's list, used to show other classes keep allocating meanwhile
+    #[cfg(test)]
+    fn lock_class(&self, class_index: usize) -> MutexGuard<'_, LinkedList<NonNull<[u8]>>> {
+        self.lists[class_index].lock().unwrap()
+    }
+
+    fn stats(&self) -> MutexGuard<'_, Stats> {
+        self.stats.lock().unwrap()
+    }
+
+    // Regions are only aligned at 16, blocks sit at multiples of their size from a region base
+    fn guaranteed_alignment(rounded_size: usize) -> usize {
+        usize::min(rounded_size, 16)
+    }
+
+    fn release_regions(regions: &mut Regions<S>) {
+        for byte in &regions.allocated_first_byte {
+            unsafe {
+                regions
+                    .source
+                    .release(*byte, Layout::from_size_align_unchecked(512, 16));
+            }
+        }
+        regions.allocated_first_byte.clear();
+    }
+}
+
+impl<S: RegionSource> Drop for StripedLocked<S> {
+    fn drop(&mut self) {
+        StripedLocked::release_regions(self.regions.get_mut().unwrap());
+    }
+}
+
+// Every method reads or resets the stats under their own lock, taking the region lock separately
+// for the totals it keeps
+impl<S: RegionSource> MemStats for StripedLocked<S> {
+    fn calculate_allocation_ratio(&self) -> (usize, usize, f64) {
+        let peak: usize = self.stats().peak_allocated_size;
+        let total: usize = self.regions.lock().unwrap().total_size;
+        (peak, total, peak as f64 / total as f64)
+    }
+
+    fn current_allocated_size(&self) -> usize {
+        self.stats().current_allocated_size
+    }
+
+    fn extension_count(&self) -> u64 {
+        self.regions.lock().unwrap().extension_count
+    }
+
+    fn alloc_success(&self) -> u64 {
+        self.stats().alloc_success
+    }
+
+    fn alloc_failure(&self) -> u64 {
+        self.stats().alloc_failure
+    }
+
+    fn size_class_histogram(&self) -> Vec<u64> {
+        self.stats().class_live.to_vec()
+    }
+
+    // Blocks never merge, so only 512 byte blocks can serve the largest class
+    fn external_fragmentation(&self) -> f64 {
+        let free_blocks: Vec<usize> = self
+            .lists
+            .iter()
+            .flat_map(|list| {
+                let list: MutexGuard<'_, LinkedList<NonNull<[u8]>>> = list.lock().unwrap();
+                list.iter().map(|block| block.len()).collect::<Vec<usize>>()
+            })
+            .collect();
+        fragmented_share(free_blocks, 512)
+    }
+
+    fn reset(&mut self) {
+        for list in &mut self.lists {
+            list.get_mut().unwrap().clear();
+        }
+        let regions: &mut Regions<S> = self.regions.get_mut().unwrap();
+        StripedLocked::release_regions(regions);
+        regions.total_size = 0;
+        regions.extension_count = 0;
+        let stats: &mut Stats = self.stats.get_mut().unwrap();
+        stats.peak_allocated_size = 0;
+        stats.current_allocated_size = 0;
+        stats.class_live = [0; 10];
+        stats.alloc_success = 0;
+        stats.alloc_failure = 0;
+    }
+
+    fn reset_peak(&mut self) {
+        let stats: &mut Stats = self.stats.get_mut().unwrap();
+        stats.peak_allocated_size = stats.current_allocated_size;
+    }
+}
+
+unsafe impl<S: RegionSource> Allocator for StripedLocked<S> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if let Some(block) = zero_size_block(layout) {
+            return Ok(block);
+        }
+        if layout.size() > 512 {
+            self.stats().alloc_failure += 1;
+            return Err(AllocError);
+        }
+        let index: usize = CLASS_TABLE[layout.size()] as usize;
+        let rounded_size: usize = 1 << index;
+        if layout.align() > StripedLocked::<S>::guaranteed_alignment(rounded_size) {
+            self.stats().alloc_failure += 1;
+            return Err(AllocError);
+        }
+
+        let mut list: MutexGuard<'_, LinkedList<NonNull<[u8]>>> = self.lists[index].lock().unwrap();
+        if list.is_empty() {
+            let mut regions: MutexGuard<'_, Regions<S>> = self.regions.lock().unwrap();
+            let ptr: NonNull<[u8]> = regions
+                .source
+                .acquire(Layout::from_size_align(512, 16).unwrap(), DEFAULT_NODE)
+                .inspect_err(|_| self.stats().alloc_failure += 1)?;
+            regions.allocated_first_byte.push(ptr.as_non_null_ptr());
+            regions.total_size += 512;
+            regions.extension_count += 1;
+            drop(regions);
+
+            unsafe {
+                for chunk in (*ptr.as_ptr()).chunks_exact_mut(rounded_size) {
+                    list.push_back(NonNull::new_unchecked(chunk as *mut [u8]));
+                }
+            }
+        }
+        let block: NonNull<[u8]> = list.pop_front().unwrap();
+        drop(list);
+
+        let mut stats: MutexGuard<'_, Stats> = self.stats();
+        stats.current_allocated_size += rounded_size;
+        stats.peak_allocated_size =
+            usize::max(stats.peak_allocated_size, stats.current_allocated_size);
+        stats.class_live[index] += 1;
+        stats.alloc_success += 1;
+        Ok(block)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 || layout.size() > 512 {
+            return;
+        }
+        let index: usize = CLASS_TABLE[layout.size()] as usize;
+        let rounded_size: usize = 1 << index;
+
+        self.lists[index]
+            .lock()
+            .unwrap()
+            .push_back(NonNull::slice_from_raw_parts(ptr, rounded_size));
+        let mut stats: MutexGuard<'_, Stats> = self.stats();
+        stats.current_allocated_size -= rounded_size;
+        stats.class_live[index] -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn test_distinct_classes_proceed_in_parallel() {
+        let allocator: StripedLocked = StripedLocked::new();
+        // With one class locked, a thread using another class must still finish
+        let held = allocator.lock_class(3);
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let layout: Layout = Layout::from_size_align(64, 8).unwrap();
+                for _ in 0..100 {
+                    let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+                    unsafe {
+                        allocator.deallocate(ptr.as_non_null_ptr(), layout);
+                    }
+                }
+                sender.send(()).unwrap();
+            });
+            receiver
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .expect("allocation in another class was blocked");
+            drop(held);
+        });
+    }
+
+    #[test]
+    fn test_concurrent_classes() {
+        let allocator: StripedLocked = StripedLocked::new();
+        thread::scope(|scope| {
+            for class_index in 0..10 {
+                let allocator: &StripedLocked = &allocator;
+                scope.spawn(move || {
+                    let layout: Layout = Layout::from_size_align(1 << class_index, 1).unwrap();
+                    let blocks: Vec<NonNull<[u8]>> = (0..64)
+                        .map(|_| allocator.allocate(layout).unwrap())
+                        .collect();
+                    for block in &blocks {
+                        assert_eq!(block.len(), 1 << class_index);
+                        unsafe {
+                            block
+                                .as_mut_ptr()
+                                .write_bytes(class_index as u8, block.len());
+                        }
+                    }
+                    for block in blocks {
+                        unsafe {
+                            assert!(block.as_ref().iter().all(|b| *b == class_index as u8));
+                            allocator.deallocate(block.as_non_null_ptr(), layout);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(allocator.current_allocated_size(), 0);
+        assert!(allocator.stats_consistent());
+        assert_eq!(allocator.alloc_success(), 640);
+        assert!(allocator
+            .size_class_histogram()
+            .iter()
+            .all(|live| *live == 0));
+    }
+
+    #[test]
+    fn test_over_aligned() {
+        let allocator: StripedLocked = StripedLocked::new();
+
+        // Blocks are only aligned to their size, and regions only to 16
+        assert!(allocator
+            .allocate(Layout::from_size_align(8, 16).unwrap())
+            .is_err());
+        assert!(allocator
+            .allocate(Layout::from_size_align(64, 32).unwrap())
+            .is_err());
+        assert_eq!(allocator.alloc_failure(), 2);
+        assert_eq!(allocator.extension_count(), 0);
+
+        let layout: Layout = Layout::from_size_align(64, 16).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert!(ptr.addr().get().is_multiple_of(16));
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+    }
+
+    #[test]
+    fn test_allocation_stats() {
+        let mut allocator: StripedLocked = StripedLocked::new();
+        let large: Layout = Layout::from_size_align(256, 8).unwrap();
+        let small: Layout = Layout::from_size_align(32, 8).unwrap();
+        let large_ptr: NonNull<[u8]> = allocator.allocate(large).unwrap();
+        let small_ptr: NonNull<[u8]> = allocator.allocate(small).unwrap();
+        unsafe {
+            allocator.deallocate(large_ptr.as_non_null_ptr(), large);
+        }
+
+        assert_eq!(
+            allocator.calculate_allocation_ratio(),
+            (288, 1024, 288.0 / 1024.0)
+        );
+        assert_eq!(allocator.current_allocated_size(), 32);
+        allocator.reset_peak();
+        assert_eq!(allocator.calculate_allocation_ratio().0, 32);
+
+        unsafe {
+            allocator.deallocate(small_ptr.as_non_null_ptr(), small);
+        }
+        allocator.reset();
+        assert_eq!(allocator.calculate_allocation_ratio().1, 0);
+        assert_eq!(allocator.extension_count(), 0);
+    }
+}
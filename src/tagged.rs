@@ -0,0 +1,169 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::collections::HashMap;
+use std::ptr::NonNull;
+use std::sync::Mutex;
+
+// Wraps an allocator and remembers the size of each live block and the tag it was allocated under,
+// if any, so leaks can be traced back to the subsystem that made them
+pub struct TaggedAllocator<A> {
+    inner: A,
+    live: Mutex<HashMap<usize, (usize, Option<u32>)>>,
+}
+
+impl<A: Allocator> TaggedAllocator<A> {
+    pub fn new(inner: A) -> Self {
+        TaggedAllocator {
+            inner,
+            live: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn allocate_tagged(&self, layout: Layout, tag: u32) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr: NonNull<[u8]> = self.inner.allocate(layout)?;
+        self.track(ptr, layout, Some(tag));
+        Ok(ptr)
+    }
+
+    // Zero-size blocks all share one dangling address and own no memory, so they are left out
+    // rather than overwriting each other's entry
+    fn track(&self, ptr: NonNull<[u8]>, layout: Layout, tag: Option<u32>) {
+        if layout.size() == 0 {
+            return;
+        }
+        self.live
+            .lock()
+            .unwrap()
+            .insert(ptr.addr().get(), (layout.size(), tag));
+    }
+
+    // Addresses of live blocks allocated under the tag, in ascending order
+    pub fn allocations_by_tag(&self, tag: u32) -> Vec<usize> {
+        let mut addresses: Vec<usize> = self
+            .live
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (_, block_tag))| *block_tag == Some(tag))
+            .map(|(address, _)| *address)
+            .collect();
+        addresses.sort_unstable();
+        addresses
+    }
+
+    // Every live (address, size), tagged or not, in ascending address order so a compactor can
+    // work out how far each block slides
+    pub fn live_allocations_sorted(&self) -> Vec<(usize, usize)> {
+        let mut blocks: Vec<(usize, usize)> = self
+            .live
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(address, (size, _))| (*address, *size))
+            .collect();
+        blocks.sort_unstable();
+        blocks
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for TaggedAllocator<A> {
+    // Untagged allocations are tracked without a tag and never show up in a tag query
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr: NonNull<[u8]> = self.inner.allocate(layout)?;
+        self.track(ptr, layout, None);
+        Ok(ptr)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.live.lock().unwrap().remove(&ptr.addr().get());
+        }
+        self.inner.deallocate(ptr, layout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buddy::Buddy;
+    use crate::mutex::Locked;
+
+    #[test]
+    fn test_allocations_by_tag() {
+        let allocator: TaggedAllocator<Locked<Buddy>> =
+            TaggedAllocator::new(Locked::new(Buddy::new()));
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+
+        let first: NonNull<[u8]> = allocator.allocate_tagged(layout, 1).unwrap();
+        let second: NonNull<[u8]> = allocator.allocate_tagged(layout, 1).unwrap();
+        let other: NonNull<[u8]> = allocator.allocate_tagged(layout, 2).unwrap();
+
+        let mut expected: Vec<usize> = vec![first.addr().get(), second.addr().get()];
+        expected.sort_unstable();
+        assert_eq!(allocator.allocations_by_tag(1), expected);
+        assert_eq!(allocator.allocations_by_tag(2), vec![other.addr().get()]);
+
+        unsafe {
+            allocator.deallocate(first.as_non_null_ptr(), layout);
+        }
+        assert_eq!(allocator.allocations_by_tag(1), vec![second.addr().get()]);
+
+        unsafe {
+            allocator.deallocate(second.as_non_null_ptr(), layout);
+            allocator.deallocate(other.as_non_null_ptr(), layout);
+        }
+        assert!(allocator.live_allocations_sorted().is_empty());
+    }
+
+    #[test]
+    fn test_live_allocations_sorted() {
+        let allocator: TaggedAllocator<Locked<Buddy>> =
+            TaggedAllocator::new(Locked::new(Buddy::new()));
+        let small: Layout = Layout::from_size_align(16, 8).unwrap();
+        let large: Layout = Layout::from_size_align(64, 8).unwrap();
+
+        let first: NonNull<[u8]> = allocator.allocate(small).unwrap();
+        let middle: NonNull<[u8]> = allocator.allocate_tagged(large, 1).unwrap();
+        let last: NonNull<[u8]> = allocator.allocate(large).unwrap();
+        unsafe {
+            allocator.deallocate(middle.as_non_null_ptr(), large);
+        }
+
+        let mut expected: Vec<(usize, usize)> =
+            vec![(first.addr().get(), 16), (last.addr().get(), 64)];
+        expected.sort_unstable();
+        assert_eq!(allocator.live_allocations_sorted(), expected);
+
+        unsafe {
+            allocator.deallocate(first.as_non_null_ptr(), small);
+            allocator.deallocate(last.as_non_null_ptr(), large);
+        }
+    }
+
+    #[test]
+    fn test_zero_size_untracked() {
+        let allocator: TaggedAllocator<Locked<Buddy>> =
+            TaggedAllocator::new(Locked::new(Buddy::new()));
+        let empty: Layout = Layout::from_size_align(0, 8).unwrap();
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+
+        let block: NonNull<[u8]> = allocator.allocate_tagged(layout, 1).unwrap();
+        let first: NonNull<[u8]> = allocator.allocate_tagged(empty, 1).unwrap();
+        let second: NonNull<[u8]> = allocator.allocate_tagged(empty, 2).unwrap();
+        assert_eq!(first.addr(), second.addr());
+        assert_eq!(allocator.allocations_by_tag(1), vec![block.addr().get()]);
+        assert!(allocator.allocations_by_tag(2).is_empty());
+
+        unsafe {
+            allocator.deallocate(first.as_non_null_ptr(), empty);
+        }
+        assert_eq!(
+            allocator.live_allocations_sorted(),
+            vec![(block.addr().get(), 32)]
+        );
+        unsafe {
+            allocator.deallocate(second.as_non_null_ptr(), empty);
+            allocator.deallocate(block.as_non_null_ptr(), layout);
+        }
+        assert!(allocator.live_allocations_sorted().is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::collections::VecDeque;
+use std::ptr::NonNull;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Number of recent calls the percentiles are taken over, per operation
+const SAMPLE_CAPACITY: usize = 4096;
+
+// Wraps an allocator and keeps a sample of how long each recent allocate and deallocate call took
+pub struct TimingAllocator<A> {
+    inner: A,
+    allocate_samples: Mutex<VecDeque<Duration>>,
+    deallocate_samples: Mutex<VecDeque<Duration>>,
+}
+
+impl<A> TimingAllocator<A> {
+    pub fn new(inner: A) -> Self {
+        TimingAllocator {
+            inner,
+            allocate_samples: Mutex::new(VecDeque::with_capacity(SAMPLE_CAPACITY)),
+            deallocate_samples: Mutex::new(VecDeque::with_capacity(SAMPLE_CAPACITY)),
+        }
+    }
+
+    // p is a percentage, e.g. 99.0 for the p99 allocate latency
+    pub fn latency_percentile(&self, p: f64) -> Duration {
+        percentile(&self.allocate_samples.lock().unwrap(), p)
+    }
+
+    pub fn deallocate_latency_percentile(&self, p: f64) -> Duration {
+        percentile(&self.deallocate_samples.lock().unwrap(), p)
+    }
+}
+
+// Ring buffer: once full, the oldest sample is dropped for each new one
+fn record(samples: &Mutex<VecDeque<Duration>>, elapsed: Duration) {
+    let mut samples: MutexGuard<'_, VecDeque<Duration>> = samples.lock().unwrap();
+    if samples.len() == SAMPLE_CAPACITY {
+        samples.pop_front();
+    }
+    samples.push_back(elapsed);
+}
+
+fn percentile(samples: &VecDeque<Duration>, p: f64) -> Duration {
+    if samples.is_empty() {
+        return Duration::ZERO;
+    }
+
+    let mut sorted: Vec<Duration> = samples.iter().copied().collect();
+    sorted.sort_unstable();
+    let rank: f64 = (p.clamp(0.0, 100.0) / 100.0) * (sorted.len() - 1) as f64;
+    sorted[rank.round() as usize]
+}
+
+unsafe impl<A: Allocator> Allocator for TimingAllocator<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let start: Instant = Instant::now();
+        let result: Result<NonNull<[u8]>, AllocError> = self.inner.allocate(layout);
+        let elapsed: Duration = start.elapsed();
+
+        record(&self.allocate_samples, elapsed);
+        result
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let start: Instant = Instant::now();
+        self.inner.deallocate(ptr, layout);
+        let elapsed: Duration = start.elapsed();
+
+        record(&self.deallocate_samples, elapsed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buddy::Buddy;
+    use crate::mutex::Locked;
+
+    #[test]
+    fn test_latency_percentile() {
+        let allocator: TimingAllocator<Locked<Buddy>> =
+            TimingAllocator::new(Locked::new(Buddy::new()));
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+
+        for _ in 0..1000 {
+            let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+            unsafe {
+                allocator.deallocate(ptr.as_non_null_ptr(), layout);
+            }
+        }
+
+        let p50: Duration = allocator.latency_percentile(50.0);
+        let p99: Duration = allocator.latency_percentile(99.0);
+        assert!(p50 > Duration::ZERO);
+        assert!(p50 <= p99);
+        assert!(p99 < Duration::from_secs(1));
+        assert!(allocator.deallocate_latency_percentile(50.0) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_samples_bounded() {
+        let allocator: TimingAllocator<Locked<Buddy>> =
+            TimingAllocator::new(Locked::new(Buddy::new()));
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+
+        for _ in 0..SAMPLE_CAPACITY + 100 {
+            let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+            unsafe {
+                allocator.deallocate(ptr.as_non_null_ptr(), layout);
+            }
+        }
+
+        assert_eq!(
+            allocator.allocate_samples.lock().unwrap().len(),
+            SAMPLE_CAPACITY
+        );
+        assert_eq!(
+            allocator.deallocate_samples.lock().unwrap().len(),
+            SAMPLE_CAPACITY
+        );
+    }
+}
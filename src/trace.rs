@@ -0,0 +1,217 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::collections::HashMap;
+use std::ptr::NonNull;
+use std::sync::{Mutex, MutexGuard};
+
+// One call made against a traced allocator. Blocks are named by the order they were allocated in,
+// so a trace doesn't depend on the addresses of the run that captured it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceEvent {
+    Allocate {
+        id: usize,
+        size: usize,
+        align: usize,
+    },
+    Deallocate {
+        id: usize,
+    },
+}
+
+struct TraceState {
+    events: Vec<TraceEvent>,
+    // Live block address to its id
+    ids: HashMap<usize, usize>,
+    next_id: usize,
+}
+
+// Wraps an allocator and records every successful allocate and deallocate so the same sequence can
+// be replayed later to rebuild a specific free list state
+pub struct TracingAllocator<A> {
+    inner: A,
+    state: Mutex<TraceState>,
+}
+
+impl<A: Allocator> TracingAllocator<A> {
+    pub fn new(inner: A) -> Self {
+        TracingAllocator {
+            inner,
+            state: Mutex::new(TraceState {
+                events: Vec::new(),
+                ids: HashMap::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn trace(&self) -> Vec<TraceEvent> {
+        self.state.lock().unwrap().events.clone()
+    }
+
+    // Issues the trace's calls in order, mapping its ids to the blocks handed out by this run.
+    // Blocks the trace never frees are left allocated and returned for the caller to free.
+    pub fn replay(&self, trace: &[TraceEvent]) -> Vec<(NonNull<u8>, Layout)> {
+        let mut blocks: HashMap<usize, (NonNull<u8>, Layout)> = HashMap::new();
+        for event in trace {
+            match *event {
+                TraceEvent::Allocate { id, size, align } => {
+                    let layout: Layout = Layout::from_size_align(size, align).unwrap();
+                    let ptr: NonNull<[u8]> = self
+                        .allocate(layout)
+                        .unwrap_or_else(|_| panic!("replayed allocation {} failed", id));
+                    blocks.insert(id, (ptr.as_non_null_ptr(), layout));
+                }
+                TraceEvent::Deallocate { id } => {
+                    let (ptr, layout): (NonNull<u8>, Layout) = blocks
+                        .remove(&id)
+                        .unwrap_or_else(|| panic!("trace frees block {} that isn't live", id));
+                    unsafe {
+                        self.deallocate(ptr, layout);
+                    }
+                }
+            }
+        }
+        blocks.into_values().collect()
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for TracingAllocator<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr: NonNull<[u8]> = self.inner.allocate(layout)?;
+        // Zero-size blocks share one dangling address, so their frees couldn't be told apart by
+        // id. They leave the free lists untouched either way, so the trace skips them.
+        if layout.size() == 0 {
+            return Ok(ptr);
+        }
+
+        let mut state: MutexGuard<'_, TraceState> = self.state.lock().unwrap();
+        let id: usize = state.next_id;
+        state.next_id += 1;
+        state.ids.insert(ptr.addr().get(), id);
+        state.events.push(TraceEvent::Allocate {
+            id,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        Ok(ptr)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            let mut state: MutexGuard<'_, TraceState> = self.state.lock().unwrap();
+            if let Some(id) = state.ids.remove(&ptr.addr().get()) {
+                state.events.push(TraceEvent::Deallocate { id });
+            }
+        }
+
+        self.inner.deallocate(ptr, layout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buddy::Buddy;
+    use crate::mutex::{Lock, Locked};
+    use crate::snapshot::Snapshot;
+
+    // Block sizes in each free list, which is what a replay has to reproduce
+    fn free_list_layout(allocator: &Locked<Buddy>) -> Vec<Vec<usize>> {
+        let mut lists: Vec<Vec<usize>> = allocator
+            .lock()
+            .snapshot()
+            .lists
+            .iter()
+            .map(|list| list.iter().map(|block| block.len()).collect())
+            .collect();
+        lists.iter_mut().for_each(|list| list.sort_unstable());
+        lists
+    }
+
+    #[test]
+    fn test_replay_reproduces_free_lists() {
+        let recorded: TracingAllocator<Locked<Buddy>> =
+            TracingAllocator::new(Locked::new(Buddy::new()));
+        let small: Layout = Layout::from_size_align(16, 8).unwrap();
+        let large: Layout = Layout::from_size_align(100, 8).unwrap();
+
+        let a: NonNull<[u8]> = recorded.allocate(small).unwrap();
+        let b: NonNull<[u8]> = recorded.allocate(large).unwrap();
+        let c: NonNull<[u8]> = recorded.allocate(small).unwrap();
+        unsafe {
+            recorded.deallocate(a.as_non_null_ptr(), small);
+            recorded.deallocate(b.as_non_null_ptr(), large);
+        }
+        let d: NonNull<[u8]> = recorded.allocate(large).unwrap();
+
+        let trace: Vec<TraceEvent> = recorded.trace();
+        assert_eq!(trace.len(), 6);
+        assert_eq!(trace[3], TraceEvent::Deallocate { id: 0 });
+
+        let replayed: TracingAllocator<Locked<Buddy>> =
+            TracingAllocator::new(Locked::new(Buddy::new()));
+        let live: Vec<(NonNull<u8>, Layout)> = replayed.replay(&trace);
+        assert_eq!(live.len(), 2);
+
+        assert_eq!(replayed.trace(), trace);
+        assert_eq!(
+            free_list_layout(replayed.inner()),
+            free_list_layout(recorded.inner())
+        );
+        assert_eq!(
+            replayed.inner().lock().region_occupancy(),
+            recorded.inner().lock().region_occupancy()
+        );
+
+        unsafe {
+            for (ptr, layout) in live {
+                replayed.deallocate(ptr, layout);
+            }
+            recorded.deallocate(c.as_non_null_ptr(), small);
+            recorded.deallocate(d.as_non_null_ptr(), large);
+        }
+    }
+
+    #[test]
+    fn test_zero_size_not_traced() {
+        let recorded: TracingAllocator<Locked<Buddy>> =
+            TracingAllocator::new(Locked::new(Buddy::new()));
+        let empty: Layout = Layout::from_size_align(0, 8).unwrap();
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+
+        let first: NonNull<[u8]> = recorded.allocate(empty).unwrap();
+        let block: NonNull<[u8]> = recorded.allocate(layout).unwrap();
+        let second: NonNull<[u8]> = recorded.allocate(empty).unwrap();
+        unsafe {
+            recorded.deallocate(first.as_non_null_ptr(), empty);
+            recorded.deallocate(block.as_non_null_ptr(), layout);
+        }
+
+        let trace: Vec<TraceEvent> = recorded.trace();
+        assert_eq!(
+            trace,
+            vec![
+                TraceEvent::Allocate {
+                    id: 0,
+                    size: 32,
+                    align: 8
+                },
+                TraceEvent::Deallocate { id: 0 },
+            ]
+        );
+
+        let replayed: TracingAllocator<Locked<Buddy>> =
+            TracingAllocator::new(Locked::new(Buddy::new()));
+        assert!(replayed.replay(&trace).is_empty());
+        assert_eq!(
+            free_list_layout(replayed.inner()),
+            free_list_layout(recorded.inner())
+        );
+        unsafe {
+            recorded.deallocate(second.as_non_null_ptr(), empty);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::ptr::NonNull;
+
+// Wraps an allocator and emits a trace-level event for every allocate and deallocate, so
+// allocator traffic shows up alongside the rest of an application's tracing output. The whole
+// wrapper only exists with the tracing feature, and the events are free when trace level is
+// disabled at compile time.
+pub struct LogAllocator<A> {
+    inner: A,
+}
+
+impl<A: Allocator> LogAllocator<A> {
+    pub fn new(inner: A) -> Self {
+        LogAllocator { inner }
+    }
+}
+
+unsafe impl<A: Allocator> Allocator for LogAllocator<A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let result: Result<NonNull<[u8]>, AllocError> = self.inner.allocate(layout);
+        match result {
+            Ok(ptr) => tracing::trace!(
+                size = layout.size(),
+                align = layout.align(),
+                addr = ptr.addr().get(),
+                "allocate"
+            ),
+            Err(_) => tracing::trace!(
+                size = layout.size(),
+                align = layout.align(),
+                "allocate failed"
+            ),
+        }
+        result
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        tracing::trace!(
+            size = layout.size(),
+            align = layout.align(),
+            addr = ptr.addr().get(),
+            "deallocate"
+        );
+        self.inner.deallocate(ptr, layout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buddy::Buddy;
+    use crate::mutex::Locked;
+    use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    type Fields = Vec<(String, String)>;
+
+    // Just enough of a subscriber to record the fields of every event it sees
+    struct Capture {
+        events: Arc<Mutex<Vec<Fields>>>,
+    }
+
+    struct FieldVisitor<'a>(&'a mut Fields);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.0.push((field.name().to_string(), value.to_string()));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0
+                .push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _span: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields: Fields = Vec::new();
+            event.record(&mut FieldVisitor(&mut fields));
+            self.events.lock().unwrap().push(fields);
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn test_log_allocator_events() {
+        let events: Arc<Mutex<Vec<Fields>>> = Arc::new(Mutex::new(Vec::new()));
+        let subscriber: Capture = Capture {
+            events: Arc::clone(&events),
+        };
+        let allocator: LogAllocator<Locked<Buddy>> = LogAllocator::new(Locked::new(Buddy::new()));
+        let layout: Layout = Layout::from_size_align(32, 8).unwrap();
+
+        let addr: usize = tracing::subscriber::with_default(subscriber, || {
+            let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+            unsafe {
+                allocator.deallocate(ptr.as_non_null_ptr(), layout);
+            }
+            ptr.addr().get()
+        });
+
+        let field = |name: &str, value: String| (name.to_string(), value);
+        let events: Vec<Fields> = events.lock().unwrap().clone();
+        assert_eq!(events.len(), 2);
+        for (fields, message) in events.iter().zip(["allocate", "deallocate"]) {
+            assert_eq!(
+                *fields,
+                vec![
+                    field("message", message.to_string()),
+                    field("size", "32".to_string()),
+                    field("align", "8".to_string()),
+                    field("addr", addr.to_string()),
+                ]
+            );
+        }
+    }
+}
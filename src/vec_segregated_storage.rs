@@ -0,0 +1,209 @@
+use std::alloc::{AllocError, Allocator, GlobalAlloc, Layout, System};
+use std::ptr::NonNull;
+use std::sync::MutexGuard;
+
+use crate::mutex::{Lock, Locked};
+use crate::simple_segregated_storage::CLASS_TABLE;
+use crate::stats::{fragmented_share, MemStats};
+
+// Same policy as SimpleSegregatedStorage but with Vec free lists, kept so the cost of a LinkedList
+// node allocation per free block can be measured against it
+pub struct VecSegregatedStorage {
+    lists: [Vec<NonNull<[u8]>>; 10],
+    allocated_first_byte: Vec<NonNull<u8>>,
+    total_size: usize,
+    peak_allocated_size: usize,
+    current_allocated_size: usize,
+    // allocate calls that returned a block, and those that returned AllocError
+    alloc_success: u64,
+    alloc_failure: u64,
+    extension_count: u64,
+    // Live allocations in each class
+    class_live: [u64; 10],
+}
+
+// Regions come from System, which any thread may free them to, and every pointer in the lists is
+// a block of one of them
+unsafe impl Send for VecSegregatedStorage {}
+
+impl VecSegregatedStorage {
+    pub fn new() -> Self {
+        VecSegregatedStorage {
+            lists: Default::default(),
+            allocated_first_byte: Vec::new(),
+            total_size: 0,
+            peak_allocated_size: 0,
+            current_allocated_size: 0,
+            alloc_success: 0,
+            alloc_failure: 0,
+            extension_count: 0,
+            class_live: [0; 10],
+        }
+    }
+
+    // Alignment every block served for the layout has. Blocks sit at multiples of their size from
+    // a region base, and region bases are only aligned at 16.
+    pub fn guaranteed_alignment(&self, layout: &Layout) -> usize {
+        usize::min(layout.size().next_power_of_two(), 16)
+    }
+}
+
+impl MemStats for VecSegregatedStorage {
+    fn calculate_allocation_ratio(&self) -> (usize, usize, f64) {
+        (
+            self.peak_allocated_size,
+            self.total_size,
+            self.peak_allocated_size as f64 / self.total_size as f64,
+        )
+    }
+
+    fn current_allocated_size(&self) -> usize {
+        self.current_allocated_size
+    }
+
+    fn extension_count(&self) -> u64 {
+        self.extension_count
+    }
+
+    fn alloc_success(&self) -> u64 {
+        self.alloc_success
+    }
+
+    fn alloc_failure(&self) -> u64 {
+        self.alloc_failure
+    }
+
+    fn size_class_histogram(&self) -> Vec<u64> {
+        self.class_live.to_vec()
+    }
+
+    // Blocks never merge, so only 512 byte blocks can serve the largest class
+    fn external_fragmentation(&self) -> f64 {
+        fragmented_share(self.lists.iter().flatten().map(|block| block.len()), 512)
+    }
+
+    fn reset(&mut self) {
+        self.total_size = 0;
+        self.peak_allocated_size = 0;
+        self.current_allocated_size = 0;
+        self.alloc_success = 0;
+        self.alloc_failure = 0;
+        self.extension_count = 0;
+        self.class_live = [0; 10];
+        for byte in self.allocated_first_byte.drain(..) {
+            unsafe {
+                System.dealloc(byte.as_ptr(), Layout::from_size_align_unchecked(512, 16));
+            }
+        }
+        for list in &mut self.lists {
+            list.clear();
+        }
+    }
+
+    fn reset_peak(&mut self) {
+        self.peak_allocated_size = self.current_allocated_size;
+    }
+}
+
+impl Drop for VecSegregatedStorage {
+    fn drop(&mut self) {
+        for byte in &self.allocated_first_byte {
+            unsafe {
+                System.dealloc(byte.as_ptr(), Layout::from_size_align_unchecked(512, 16));
+            }
+        }
+    }
+}
+
+unsafe impl Allocator for Locked<VecSegregatedStorage> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut alloc: MutexGuard<'_, VecSegregatedStorage> = self.lock();
+
+        if layout.size() > 512 || layout.align() > alloc.guaranteed_alignment(&layout) {
+            alloc.alloc_failure += 1;
+            return Err(AllocError);
+        }
+        let index: usize = CLASS_TABLE[layout.size()] as usize;
+        let rounded_size: usize = 1 << index;
+
+        if alloc.lists[index].is_empty() {
+            let ptr: NonNull<[u8]> = System
+                .allocate(Layout::from_size_align(512, 16).unwrap())
+                .inspect_err(|_| alloc.alloc_failure += 1)?;
+            alloc.allocated_first_byte.push(ptr.as_non_null_ptr());
+            unsafe {
+                let chunks = (*ptr.as_ptr()).chunks_exact_mut(rounded_size);
+                // Reverse so blocks are handed out from the front of the region, like the LinkedList
+                for chunk in chunks.rev() {
+                    alloc.lists[index].push(NonNull::new_unchecked(chunk as *mut [u8]));
+                }
+            }
+            alloc.total_size += 512;
+            alloc.extension_count += 1;
+        }
+
+        alloc.current_allocated_size += rounded_size;
+        alloc.class_live[index] += 1;
+        alloc.alloc_success += 1;
+        alloc.peak_allocated_size =
+            usize::max(alloc.current_allocated_size, alloc.peak_allocated_size);
+
+        Ok(alloc.lists[index].pop().unwrap())
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let mut alloc: MutexGuard<'_, VecSegregatedStorage> = self.lock();
+
+        if layout.size() > 512 {
+            return;
+        }
+        let index: usize = CLASS_TABLE[layout.size()] as usize;
+        let rounded_size: usize = 1 << index;
+
+        alloc.lists[index].push(NonNull::slice_from_raw_parts(ptr, rounded_size));
+        alloc.current_allocated_size -= rounded_size;
+        alloc.class_live[index] -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_deallocate_success() {
+        let allocator: Locked<VecSegregatedStorage> = Locked::new(VecSegregatedStorage::new());
+        let layout: Layout = Layout::from_size_align(128, 8).unwrap();
+
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert_eq!(ptr.len(), 128);
+        assert_eq!(allocator.lock().lists[7].len(), 3);
+
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+        let alloc: MutexGuard<'_, VecSegregatedStorage> = allocator.lock();
+        assert_eq!(alloc.lists[7].len(), 4);
+        assert_eq!(alloc.calculate_allocation_ratio(), (128, 512, 0.25));
+    }
+
+    #[test]
+    fn test_over_aligned() {
+        let allocator: Locked<VecSegregatedStorage> = Locked::new(VecSegregatedStorage::new());
+        assert!(allocator
+            .allocate(Layout::from_size_align(1, 64).unwrap())
+            .is_err());
+        assert!(allocator
+            .allocate(Layout::from_size_align(128, 32).unwrap())
+            .is_err());
+        assert_eq!(allocator.lock().alloc_failure(), 2);
+        assert_eq!(allocator.lock().extension_count(), 0);
+
+        let layout: Layout = Layout::from_size_align(128, 16).unwrap();
+        let ptr: NonNull<[u8]> = allocator.allocate(layout).unwrap();
+        assert!(ptr.addr().get().is_multiple_of(16));
+        unsafe {
+            allocator.deallocate(ptr.as_non_null_ptr(), layout);
+        }
+    }
+}